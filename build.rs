@@ -1,12 +1,24 @@
 use std::io::Result;
+use std::{env, path::PathBuf};
 fn main() -> Result<()> {
-    prost_build::Config::new()
+    let mut config = prost_build::Config::new();
+    config
         .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
         .type_attribute(".", "#[serde(rename_all = \"camelCase\")]")
+        .type_attribute("ListTransactionsInput", "#[serde(default)]")
         .field_attribute("merchant_data", "#[serde(default)]")
         .field_attribute("item_id", "#[serde(default)]")
+        .field_attribute("GetStatisticsInput.currency", "#[serde(default)]")
+        .field_attribute("ReserveInput.ttl_seconds", "#[serde(default)]")
+        .field_attribute("quote_id", "#[serde(default)]")
+        .field_attribute("CommitReservationInput.idempotency_key", "#[serde(default)]")
+        .field_attribute("CommitReservationInput.keep_reservation", "#[serde(default)]")
         .compile_well_known_types()
-        .extern_path(".google.protobuf.Timestamp", "::prost_wkt_types::Timestamp")
-        .compile_protos(&["src/proto/api.proto"], &["src/proto"])?;
+        .extern_path(".google.protobuf.Timestamp", "::prost_wkt_types::Timestamp");
+    // descriptors are used to generate the openapi document
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("api_descriptor.bin");
+    tonic_build::configure()
+        .file_descriptor_set_path(descriptor_path)
+        .compile_with_config(config, &["src/proto/api.proto"], &["src/proto"])?;
     Ok(())
 }
@@ -0,0 +1,17 @@
+create table clients
+(
+    id           varchar(36)                         not null,
+    name         varchar(255)                        not null,
+    -- hex encoded sha-256 of the api key, the key itself is never stored
+    api_key_hash varchar(64)                         not null,
+    -- shared secret for hmac signed requests, null if client only uses the api key
+    hmac_secret  varchar(128),
+    scopes       varchar(32)[]                       not null,
+    is_active    boolean   default true              not null,
+    created_at   timestamp default CURRENT_TIMESTAMP not null,
+    constraint clients_pk
+        primary key (id)
+);
+
+create unique index clients_api_key_hash_index
+    on clients (api_key_hash);
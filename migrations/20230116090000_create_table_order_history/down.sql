@@ -0,0 +1,6 @@
+drop table order_history;
+
+drop index balance_reserve_expires_at_index;
+
+alter table balance_reserve
+    drop column expires_at;
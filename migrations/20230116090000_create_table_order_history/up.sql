@@ -0,0 +1,27 @@
+-- reservation is released by the sweeper after this time, null means it never expires
+alter table balance_reserve
+    add column expires_at timestamp;
+
+create index balance_reserve_expires_at_index
+    on balance_reserve (expires_at)
+    where expires_at is not null;
+
+-- final state of reservations: committed, cancelled or expired
+create table order_history
+(
+    id                  bigint                              not null,
+    order_id            varchar(36)                         not null,
+    user_id             varchar(36)                         not null,
+    item_id             varchar(36)                         not null,
+    state               varchar(16)                         not null,
+    currency            varchar(3)                          not null,
+    released_value      numeric(10, 2)                      not null,
+    captured_value      numeric(10, 2)                      not null,
+    reserved_at         timestamp                           not null,
+    finished_at         timestamp default CURRENT_TIMESTAMP not null,
+    constraint order_history_pk
+        primary key (id)
+);
+
+create index order_history_order_id_index
+    on order_history (order_id);
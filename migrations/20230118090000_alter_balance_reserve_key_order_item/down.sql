@@ -0,0 +1,14 @@
+drop index order_history_order_id_index;
+create index order_history_order_id_index
+    on order_history (order_id);
+
+drop index transaction_order_id_index;
+create index transaction_order_id_index
+    on "transaction" ((order_data ->> 'order_id'))
+    where (order_data ->> 'order_id') is not null;
+
+alter table balance_reserve
+    drop constraint balance_reserve_pk;
+alter table balance_reserve
+    add constraint balance_reserve_pk
+        primary key (order_id);
@@ -0,0 +1,15 @@
+-- every item (line) of an order is reserved, captured and cancelled separately
+alter table balance_reserve
+    drop constraint balance_reserve_pk;
+alter table balance_reserve
+    add constraint balance_reserve_pk
+        primary key (order_id, item_id);
+
+drop index transaction_order_id_index;
+create index transaction_order_id_index
+    on "transaction" ((order_data ->> 'order_id'), (order_data ->> 'item_id'))
+    where (order_data ->> 'order_id') is not null;
+
+drop index order_history_order_id_index;
+create index order_history_order_id_index
+    on order_history (order_id, item_id);
@@ -0,0 +1,4 @@
+alter table transaction
+    drop column rate_snapshot_id;
+
+drop table currency_rate;
@@ -0,0 +1,18 @@
+-- every loaded set of exchange rates, rates are relative to the base currency of the snapshot
+create table currency_rate
+(
+    snapshot_id   bigint     not null,
+    base_currency varchar(3) not null,
+    currency      varchar(3) not null,
+    rate          numeric    not null,
+    created_at    timestamp  not null,
+    constraint currency_rate_pk
+        primary key (snapshot_id, currency)
+);
+
+create index currency_rate_created_at_index
+    on currency_rate (created_at);
+
+-- snapshot of the rates used to convert transaction value, null when nothing was converted
+alter table transaction
+    add column rate_snapshot_id bigint;
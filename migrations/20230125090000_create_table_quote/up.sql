@@ -0,0 +1,15 @@
+-- locked exchange rate for converting up to value of from_currency into to_currency until expires_at
+create table quote
+(
+    id               bigint         not null,
+    from_currency    varchar(3)     not null,
+    to_currency      varchar(3)     not null,
+    value            numeric(10, 2) not null,
+    converted_value  numeric(10, 2) not null,
+    rate             numeric        not null,
+    rate_snapshot_id bigint         not null,
+    created_at       timestamp      not null,
+    expires_at       timestamp      not null,
+    constraint quote_pk
+        primary key (id)
+);
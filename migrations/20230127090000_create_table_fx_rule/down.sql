@@ -0,0 +1,6 @@
+drop table spread_income;
+
+alter table quote
+    drop column spread;
+
+drop table fx_rule;
@@ -0,0 +1,34 @@
+-- conversion rules per currency pair, "*" matches any currency, exact pairs win over wildcards
+create table fx_rule
+(
+    from_currency  varchar(3)     not null,
+    to_currency    varchar(3)     not null,
+    spread         numeric(6, 4)  not null,
+    reserve_margin numeric(6, 4)  not null,
+    max_overdraft  numeric(10, 2) not null,
+    constraint fx_rule_pk
+        primary key (from_currency, to_currency)
+);
+
+-- reservations used to hold 6% more than converted value
+insert into fx_rule (from_currency, to_currency, spread, reserve_margin, max_overdraft)
+values ('*', '*', 0, 0.06, 0);
+
+-- spread of the quote is locked together with its rate
+alter table quote
+    add column spread numeric(6, 4) default 0 not null;
+
+-- income from conversion spreads, in the currency of the converted wallet
+create table spread_income
+(
+    id             bigint                              not null,
+    transaction_id bigint                              not null,
+    currency       varchar(3)                          not null,
+    value          numeric(10, 2)                      not null,
+    created_at     timestamp default CURRENT_TIMESTAMP not null,
+    constraint spread_income_pk
+        primary key (id)
+);
+
+create index spread_income_transaction_id_index
+    on spread_income (transaction_id);
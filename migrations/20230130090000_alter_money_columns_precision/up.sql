@@ -0,0 +1,31 @@
+-- amounts are stored with up to 8 digits after dot, each currency is rounded to its ISO 4217 minor units
+alter table balance
+    alter column current_value type numeric(28, 8);
+
+alter table transaction
+    alter column transaction_value type numeric(28, 8),
+    alter column sender_value type numeric(28, 8),
+    alter column sender_balance_before type numeric(28, 8),
+    alter column sender_balance_after type numeric(28, 8),
+    alter column recipient_value type numeric(28, 8),
+    alter column recipient_balance_before type numeric(28, 8),
+    alter column recipient_balance_after type numeric(28, 8);
+
+alter table balance_reserve
+    alter column value type numeric(28, 8),
+    alter column user_currency_value type numeric(28, 8),
+    alter column captured_value type numeric(28, 8);
+
+alter table order_history
+    alter column released_value type numeric(28, 8),
+    alter column captured_value type numeric(28, 8);
+
+alter table quote
+    alter column value type numeric(28, 8),
+    alter column converted_value type numeric(28, 8);
+
+alter table spread_income
+    alter column value type numeric(28, 8);
+
+alter table fx_rule
+    alter column max_overdraft type numeric(28, 8);
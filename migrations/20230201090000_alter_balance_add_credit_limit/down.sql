@@ -0,0 +1,5 @@
+drop index balance_negative_since_idx;
+
+alter table balance
+    drop column negative_since,
+    drop column credit_limit;
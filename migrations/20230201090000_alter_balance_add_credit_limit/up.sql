@@ -0,0 +1,11 @@
+-- credit line lets the wallet go below zero, negative_since is set while the balance is below zero
+alter table balance
+    add column credit_limit numeric(28, 8) not null default 0,
+    add column negative_since timestamp;
+
+-- wallets that are already negative are reported from the time of the migration
+update balance
+set negative_since = now()
+where current_value < 0;
+
+create index balance_negative_since_idx on balance (negative_since) where negative_since is not null;
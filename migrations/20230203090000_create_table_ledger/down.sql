@@ -0,0 +1,7 @@
+drop table posting;
+
+drop function check_journal_entry_balanced();
+
+drop table journal_entry;
+
+drop table ledger_account;
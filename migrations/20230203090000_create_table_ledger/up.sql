@@ -0,0 +1,110 @@
+-- accounts of the double-entry ledger: wallet and hold of every user wallet, company accounts have empty user_id
+create table ledger_account
+(
+    kind       varchar(16)                         not null,
+    user_id    varchar(36)                         not null,
+    currency   varchar(3)                          not null,
+    created_at timestamp default CURRENT_TIMESTAMP not null,
+    constraint ledger_account_pk
+        primary key (kind, user_id, currency)
+);
+
+-- postings of a single operation, referenced transaction or order item
+create table journal_entry
+(
+    id             bigint                              not null,
+    operation      varchar(16)                         not null,
+    transaction_id bigint,
+    order_id       varchar(36),
+    item_id        varchar(36),
+    created_at     timestamp default CURRENT_TIMESTAMP not null,
+    constraint journal_entry_pk
+        primary key (id)
+);
+
+create index journal_entry_transaction_id_index
+    on journal_entry (transaction_id);
+
+-- positive value increases the account, values of an entry sum up to zero in every currency
+create table posting
+(
+    id           bigint         not null,
+    entry_id     bigint         not null,
+    account_kind varchar(16)    not null,
+    user_id      varchar(36)    not null,
+    currency     varchar(3)     not null,
+    value        numeric(28, 8) not null,
+    constraint posting_pk
+        primary key (id),
+    constraint posting_entry_fk
+        foreign key (entry_id) references journal_entry (id),
+    constraint posting_account_fk
+        foreign key (account_kind, user_id, currency) references ledger_account (kind, user_id, currency)
+);
+
+create index posting_entry_id_index
+    on posting (entry_id);
+
+create index posting_account_index
+    on posting (user_id, currency, account_kind);
+
+-- checked on commit, when all postings of the entry are inserted
+create function check_journal_entry_balanced() returns trigger as
+$$
+begin
+    if exists(select 1
+              from posting
+              where entry_id = new.entry_id
+              group by currency
+              having sum(value) <> 0) then
+        raise exception 'journal entry % is not balanced', new.entry_id;
+    end if;
+    return null;
+end;
+$$ language plpgsql;
+
+create constraint trigger posting_balanced
+    after insert
+    on posting
+    deferrable initially deferred
+    for each row
+execute function check_journal_entry_balanced();
+
+-- balances that existed before the ledger are opened against merchant clearing in a single entry,
+-- ids are far below the ones of the id generator
+insert into ledger_account (kind, user_id, currency)
+select kinds.kind, balance.user_id, balance.currency
+from balance,
+     (values ('wallet'), ('hold')) as kinds (kind);
+
+insert into ledger_account (kind, user_id, currency)
+select distinct 'clearing', '', currency
+from balance;
+
+insert into journal_entry (id, operation)
+select 1, 'opening'
+where exists(select 1 from balance);
+
+with wallet as (select balance.user_id,
+                       balance.currency,
+                       balance.current_value,
+                       coalesce(sum(balance_reserve.user_currency_value), 0) as held
+                from balance
+                         left join balance_reserve
+                                   on balance_reserve.user_id = balance.user_id
+                                       and balance_reserve.wallet_currency = balance.currency
+                group by balance.user_id, balance.currency, balance.current_value),
+     opening (kind, user_id, currency, value) as (select 'wallet', user_id, currency, current_value - held
+                                                  from wallet
+                                                  union all
+                                                  select 'hold', user_id, currency, held
+                                                  from wallet
+                                                  union all
+                                                  select 'clearing', '', currency, -sum(current_value)
+                                                  from wallet
+                                                  group by currency)
+insert
+into posting (id, entry_id, account_kind, user_id, currency, value)
+select row_number() over (), 1, kind, user_id, currency, value
+from opening
+where value <> 0;
@@ -0,0 +1,3 @@
+drop table reconciliation_finding;
+
+drop table reconciliation_report;
@@ -0,0 +1,28 @@
+-- runs of the reconciliation that replays wallet history, findings are the discrepancies it found
+create table reconciliation_report
+(
+    id              bigint                              not null,
+    checked_wallets bigint                              not null,
+    created_at      timestamp default CURRENT_TIMESTAMP not null,
+    constraint reconciliation_report_pk
+        primary key (id)
+);
+
+create table reconciliation_finding
+(
+    id             bigint         not null,
+    report_id      bigint         not null,
+    user_id        varchar(36)    not null,
+    currency       varchar(3)     not null,
+    kind           varchar(16)    not null,
+    transaction_id bigint,
+    expected       numeric(28, 8) not null,
+    actual         numeric(28, 8) not null,
+    constraint reconciliation_finding_pk
+        primary key (id),
+    constraint reconciliation_finding_report_fk
+        foreign key (report_id) references reconciliation_report (id)
+);
+
+create index reconciliation_finding_report_id_index
+    on reconciliation_finding (report_id);
@@ -0,0 +1,4 @@
+drop table balance_status_history;
+
+alter table balance
+    drop column status;
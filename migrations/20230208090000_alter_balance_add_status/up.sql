@@ -0,0 +1,23 @@
+-- active, frozen_debits (only credits are allowed), frozen_all (balance can't change) or closed
+alter table balance
+    add column status varchar(16) not null default 'active';
+
+-- every status change of a wallet with its reason, old_status is null when the wallet is opened
+create table balance_status_history
+(
+    id                    bigint                              not null,
+    user_id               varchar(36)                         not null,
+    currency              varchar(3)                          not null,
+    old_status            varchar(16),
+    new_status            varchar(16)                         not null,
+    reason                text                                not null,
+    payout_transaction_id bigint,
+    created_at            timestamp default CURRENT_TIMESTAMP not null,
+    constraint balance_status_history_pk
+        primary key (id),
+    constraint balance_status_history_balance_fk
+        foreign key (user_id, currency) references balance (user_id, currency)
+);
+
+create index balance_status_history_user_id_index
+    on balance_status_history (user_id, currency);
@@ -0,0 +1,3 @@
+-- rate the sender value was converted at into the recipient value when a wallet changes its currency
+alter table transaction
+    add column rate numeric;
@@ -0,0 +1,4 @@
+alter table transaction
+    drop column description,
+    drop column category,
+    drop column source;
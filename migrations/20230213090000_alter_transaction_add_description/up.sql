@@ -0,0 +1,20 @@
+-- human-readable texts of a transaction, supplied by the caller or generated by the service
+alter table transaction
+    add column description text,
+    add column category    varchar(32),
+    add column source      varchar(64);
+
+-- wallet closing payouts and currency changes are adjustments made by admins
+update transaction
+set category = case
+                   when sender_id is null then 'top_up'
+                   when recipient_id is null then 'purchase'
+                   when exists(select 1
+                               from journal_entry
+                               where journal_entry.transaction_id = transaction.id
+                                 and journal_entry.operation in ('payout', 'convert')) then 'adjustment'
+                   else 'transfer'
+    end;
+
+alter table transaction
+    alter column category set not null;
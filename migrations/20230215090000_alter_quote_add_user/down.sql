@@ -0,0 +1,4 @@
+alter table quote
+    drop column user_id,
+    drop column order_id,
+    drop column item_id;
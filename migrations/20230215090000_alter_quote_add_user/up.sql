@@ -0,0 +1,6 @@
+-- quote is made for one user and is taken by the first order item that uses it,
+-- quotes made before have no user and can't be used
+alter table quote
+    add column user_id  varchar,
+    add column order_id varchar,
+    add column item_id  varchar;
@@ -0,0 +1,7 @@
+drop index balance_reserve_order_id_index;
+
+alter table balance_reserve
+    drop constraint balance_reserve_pk;
+alter table balance_reserve
+    add constraint balance_reserve_pk
+        primary key (order_id, item_id);
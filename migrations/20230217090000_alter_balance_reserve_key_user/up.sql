@@ -0,0 +1,9 @@
+-- order ids are issued by services per user, different users may reserve the same order item
+alter table balance_reserve
+    drop constraint balance_reserve_pk;
+alter table balance_reserve
+    add constraint balance_reserve_pk
+        primary key (user_id, order_id, item_id);
+
+create index balance_reserve_order_id_index
+    on balance_reserve (order_id, item_id);
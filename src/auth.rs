@@ -0,0 +1,468 @@
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{web, HttpMessage};
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+
+use crate::database::{models, queries};
+use crate::errors::ServiceError;
+
+const CLIENT_ID_HEADER: &str = "x-client-id";
+const TIMESTAMP_HEADER: &str = "x-timestamp";
+const SIGNATURE_HEADER: &str = "x-signature";
+// signed requests with a timestamp further than this from the server clock are rejected. signatures are not
+// remembered, so a captured request is accepted again within this window: operations changing balances rely on
+// their idempotency keys and order ids to not be applied twice
+const SIGNATURE_MAX_SKEW_SECONDS: i64 = 300;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scope {
+    // top-up and transfers
+    Billing,
+    // reserve, commit and cancel
+    Service,
+    // reports
+    Accounting,
+    // credit limits
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Billing => "billing",
+            Scope::Service => "service",
+            Scope::Accounting => "accounting",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+pub fn has_scope(client: &models::Client, scope: Scope) -> bool {
+    client.scopes.iter().any(|s| s == scope.as_str())
+}
+
+// api key is sent as "Authorization: Bearer <key>", signed requests carry
+// X-Client-Id, X-Timestamp (unix seconds) and X-Signature headers
+#[derive(PartialEq, Debug)]
+pub enum Credentials {
+    ApiKey(String),
+    Signature {
+        client_id: String,
+        timestamp: i64,
+        signature: String,
+    },
+}
+
+impl Credentials {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Credentials> {
+        let value = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+        if let Some(signature) = value(SIGNATURE_HEADER) {
+            return Some(Credentials::Signature {
+                client_id: value(CLIENT_ID_HEADER)?.to_string(),
+                timestamp: value(TIMESTAMP_HEADER)?.parse().ok()?,
+                signature: signature.to_string(),
+            });
+        }
+        bearer_token(value(header::AUTHORIZATION.as_str())?).map(Credentials::ApiKey)
+    }
+}
+
+pub fn bearer_token(authorization: &str) -> Option<String> {
+    authorization
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+}
+
+// only the hash of an api key is stored in the clients table
+pub fn hash_api_key(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+// parts of the request covered by the signature
+pub struct SignedRequest {
+    pub method: String,
+    pub path: String,
+    pub body: web::Bytes,
+}
+
+// signature is hmac-sha256 of "METHOD\nPATH?QUERY\nTIMESTAMP\nBODY" keyed with client secret
+fn signature_mac(secret: &str, request: &SignedRequest, timestamp: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(format!("{}\n{}\n{}\n", request.method, request.path, timestamp).as_bytes());
+    mac.update(&request.body);
+    mac
+}
+
+fn is_signature_valid(client: &models::Client, request: &SignedRequest, timestamp: i64, signature: &str) -> bool {
+    match (&client.hmac_secret, hex::decode(signature)) {
+        (Some(secret), Ok(signature)) => signature_mac(secret, request, timestamp)
+            .verify_slice(&signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+pub fn authenticate_api_key(
+    conn: &mut PgConnection,
+    api_key: &str,
+) -> Result<Option<models::Client>, diesel::result::Error> {
+    Ok(queries::load_client_by_key_hash(conn, hash_api_key(api_key).as_str())?.filter(|client| client.is_active))
+}
+
+// returns active client the credentials belong to, None if they are wrong
+pub fn authenticate(
+    conn: &mut PgConnection,
+    credentials: &Credentials,
+    request: &SignedRequest,
+    now: i64,
+) -> Result<Option<models::Client>, diesel::result::Error> {
+    let client = match credentials {
+        Credentials::ApiKey(api_key) => return authenticate_api_key(conn, api_key.as_str()),
+        Credentials::Signature {
+            client_id,
+            timestamp,
+            signature,
+        } => {
+            if (now - timestamp).abs() > SIGNATURE_MAX_SKEW_SECONDS {
+                return Ok(None);
+            }
+            queries::load_client(conn, client_id.as_str())?
+                .filter(|client| is_signature_valid(client, request, *timestamp, signature.as_str()))
+        }
+    };
+    Ok(client.filter(|client| client.is_active))
+}
+
+// middleware for a single route: rejects requests without valid credentials
+// or from clients lacking the scope, the client is put into request extensions
+pub struct AuthMiddleware<S> {
+    service: Rc<S>,
+    scope: Option<Scope>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let scope = self.scope;
+
+        Box::pin(async move {
+            let client = authorized_client(&mut req).await?;
+            match client {
+                Some(client) if scope.iter().all(|scope| has_scope(&client, *scope)) => {
+                    req.extensions_mut().insert(client);
+                    service.call(req).await.map(ServiceResponse::map_into_left_body)
+                }
+                client => {
+                    warn!(
+                        client_id = client.map(|c| c.id),
+                        path = req.path(),
+                        "request rejected as unauthorized"
+                    );
+                    Ok(req.error_response(ServiceError::Unauthorized).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+async fn authorized_client(req: &mut ServiceRequest) -> Result<Option<models::Client>, actix_web::Error> {
+    let credentials = match Credentials::from_headers(req.headers()) {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+
+    // signature covers the body, so it is read here and put back for the handler
+    let body = if let Credentials::Signature { .. } = credentials {
+        let body = req.extract::<web::Bytes>().await?;
+        let (_, mut payload) = actix_http::h1::Payload::create(true);
+        payload.unread_data(body.clone());
+        req.set_payload(payload.into());
+        body
+    } else {
+        web::Bytes::new()
+    };
+    let request = SignedRequest {
+        method: req.method().to_string(),
+        path: req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or_else(|| req.path())
+            .to_string(),
+        body,
+    };
+
+    let db = req
+        .app_data::<web::Data<Pool<ConnectionManager<PgConnection>>>>()
+        .cloned()
+        .ok_or_else(|| ErrorInternalServerError("database pool is not configured"))?;
+    let now = chrono::Utc::now().timestamp();
+    web::block(move || {
+        let mut conn = db.get()?;
+        authenticate(&mut conn, &credentials, &request, now).map_err(anyhow::Error::from)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|res| res)
+    .map_err(|e| {
+        error!("{e}");
+        ErrorInternalServerError("internal error")
+    })
+}
+
+// each route is wrapped with one of these, e.g. #[post("/top-up", wrap = "auth::RequireBilling")]
+macro_rules! scope_middleware {
+    ($($name:ident => $scope:expr),* $(,)?) => {
+        $(
+            pub struct $name;
+
+            impl<S, B> Transform<S, ServiceRequest> for $name
+            where
+                S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+                B: 'static,
+            {
+                type Response = ServiceResponse<EitherBody<B>>;
+                type Error = actix_web::Error;
+                type Transform = AuthMiddleware<S>;
+                type InitError = ();
+                type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+                fn new_transform(&self, service: S) -> Self::Future {
+                    ready(Ok(AuthMiddleware {
+                        service: Rc::new(service),
+                        scope: $scope,
+                    }))
+                }
+            }
+        )*
+    };
+}
+
+scope_middleware! {
+    RequireClient => None,
+    RequireBilling => Some(Scope::Billing),
+    RequireService => Some(Scope::Service),
+    RequireAccounting => Some(Scope::Accounting),
+    RequireAdmin => Some(Scope::Admin),
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{database, responses};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{post, App, HttpResponse};
+    use diesel::result::Error;
+    use diesel::{Connection, ExpressionMethods, RunQueryDsl};
+
+    // hex encoded signature as computed by clients
+    fn sign(secret: &str, request: &SignedRequest, timestamp: i64) -> String {
+        hex::encode(signature_mac(secret, request, timestamp).finalize().into_bytes())
+    }
+
+    // creates or refreshes a client with the given scopes, returns its api key which is also its hmac secret
+    pub fn create_test_client(conn: &mut PgConnection, client_id: &str, client_scopes: &[Scope]) -> String {
+        use crate::schema::clients::dsl::*;
+        let api_key = format!("key-{client_id}");
+        let values = (
+            name.eq(client_id),
+            api_key_hash.eq(hash_api_key(api_key.as_str())),
+            hmac_secret.eq(Some(api_key.as_str())),
+            scopes.eq(client_scopes.iter().map(|s| s.as_str().to_string()).collect::<Vec<_>>()),
+            is_active.eq(true),
+        );
+        diesel::insert_into(clients)
+            .values((id.eq(client_id), values.clone()))
+            .on_conflict(id)
+            .do_update()
+            .set(values)
+            .execute(conn)
+            .unwrap();
+        api_key
+    }
+
+    #[test]
+    fn test_credentials_from_headers() {
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer secret-key"))
+            .to_srv_request();
+        assert_eq!(
+            Credentials::from_headers(req.headers()),
+            Some(Credentials::ApiKey("secret-key".to_string()))
+        );
+
+        let req = TestRequest::default()
+            .insert_header((CLIENT_ID_HEADER, "billing"))
+            .insert_header((TIMESTAMP_HEADER, "1672531200"))
+            .insert_header((SIGNATURE_HEADER, "abcdef"))
+            .to_srv_request();
+        assert_eq!(
+            Credentials::from_headers(req.headers()),
+            Some(Credentials::Signature {
+                client_id: "billing".to_string(),
+                timestamp: 1672531200,
+                signature: "abcdef".to_string(),
+            })
+        );
+
+        // incomplete or unsupported credentials
+        let req = TestRequest::default()
+            .insert_header((SIGNATURE_HEADER, "abcdef"))
+            .to_srv_request();
+        assert_eq!(Credentials::from_headers(req.headers()), None);
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Basic dXNlcjpwYXNz"))
+            .to_srv_request();
+        assert_eq!(Credentials::from_headers(req.headers()), None);
+    }
+
+    #[actix_web::test]
+    async fn test_authenticate() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+        let now = 1672531200;
+        let request = SignedRequest {
+            method: "POST".to_string(),
+            path: "/top-up".to_string(),
+            body: web::Bytes::from_static(b"{\"userId\":\"u1\"}"),
+        };
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            let api_key = create_test_client(conn, "test_authenticate", &[Scope::Billing]);
+
+            let client = authenticate_api_key(conn, api_key.as_str())?.unwrap();
+            assert_eq!(client.id, "test_authenticate");
+            assert!(has_scope(&client, Scope::Billing));
+            assert!(!has_scope(&client, Scope::Service));
+            assert!(authenticate_api_key(conn, "wrong key")?.is_none());
+
+            let signed = |timestamp: i64, signature: String| Credentials::Signature {
+                client_id: "test_authenticate".to_string(),
+                timestamp,
+                signature,
+            };
+            let signature = sign(api_key.as_str(), &request, now);
+            assert!(authenticate(conn, &signed(now, signature.clone()), &request, now)?.is_some());
+            // request is too old
+            assert!(authenticate(conn, &signed(now, signature.clone()), &request, now + 301)?.is_none());
+            // signature doesn't match the timestamp
+            assert!(authenticate(conn, &signed(now - 1, signature.clone()), &request, now)?.is_none());
+            // body was tampered with
+            let tampered = SignedRequest {
+                body: web::Bytes::from_static(b"{\"userId\":\"u2\"}"),
+                ..request
+            };
+            assert!(authenticate(conn, &signed(now, signature), &tampered, now)?.is_none());
+
+            // disabled clients are rejected
+            {
+                use crate::schema::clients::dsl::*;
+                diesel::update(clients)
+                    .filter(id.eq("test_authenticate"))
+                    .set(is_active.eq(false))
+                    .execute(conn)?;
+            }
+            assert!(authenticate_api_key(conn, api_key.as_str())?.is_none());
+
+            Ok(())
+        });
+    }
+
+    #[post("/billing", wrap = "RequireBilling")]
+    async fn billing_handler(body: web::Bytes) -> HttpResponse {
+        HttpResponse::Ok().body(body)
+    }
+
+    #[actix_web::test]
+    async fn test_middleware() {
+        dotenvy::dotenv().ok();
+
+        let db = database::connect::create_test_db_connection_pool();
+        let billing_key = create_test_client(&mut db.get().unwrap(), "test_middleware_billing", &[Scope::Billing]);
+        let service_key = create_test_client(&mut db.get().unwrap(), "test_middleware_service", &[Scope::Service]);
+        let app = init_service(App::new().app_data(web::Data::new(db)).service(billing_handler)).await;
+
+        let unauthorized = serde_json::to_string(&responses::error_output(&ServiceError::Unauthorized)).unwrap();
+
+        // no credentials
+        let req = TestRequest::post().uri("/billing").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(read_body(res).await, unauthorized);
+
+        // client without the scope
+        let req = TestRequest::post()
+            .uri("/billing")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {service_key}")))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(read_body(res).await, unauthorized);
+
+        // api key
+        let req = TestRequest::post()
+            .uri("/billing")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {billing_key}")))
+            .set_payload("payload")
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(read_body(res).await, "payload");
+
+        // signed request, handler still receives the body
+        let timestamp = chrono::Utc::now().timestamp();
+        let signature = sign(
+            billing_key.as_str(),
+            &SignedRequest {
+                method: "POST".to_string(),
+                path: "/billing?x=1".to_string(),
+                body: web::Bytes::from_static(b"payload"),
+            },
+            timestamp,
+        );
+        let req = TestRequest::post()
+            .uri("/billing?x=1")
+            .insert_header((CLIENT_ID_HEADER, "test_middleware_billing"))
+            .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+            .insert_header((SIGNATURE_HEADER, signature.clone()))
+            .set_payload("payload")
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(read_body(res).await, "payload");
+
+        // signature of a different body
+        let req = TestRequest::post()
+            .uri("/billing?x=1")
+            .insert_header((CLIENT_ID_HEADER, "test_middleware_billing"))
+            .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+            .insert_header((SIGNATURE_HEADER, signature))
+            .set_payload("other payload")
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+}
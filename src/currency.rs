@@ -1,249 +1,263 @@
-use bigdecimal::{BigDecimal, FromPrimitive};
+use bigdecimal::{BigDecimal, Signed, Zero};
+use chrono::NaiveDateTime;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::sync::{Arc, RwLock};
 
-#[derive(Debug, Clone)]
-pub struct CurrencyConverter {
-    base_currency: String,
-    rates: Arc<Mutex<HashMap<String, BigDecimal>>>,
+use crate::database::idgen;
+use crate::money::Money;
+use crate::rates;
+
+// exchange rates relative to the base currency, every loaded set is a snapshot with its own id
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rates {
+    pub snapshot_id: i64,
+    pub fetched_at: NaiveDateTime,
+    pub base_currency: String,
+    pub rates: HashMap<String, BigDecimal>,
 }
 
-impl CurrencyConverter {
-    fn new(base_currency: String, rates: HashMap<String, BigDecimal>) -> Self {
+impl Rates {
+    pub fn new(base_currency: String, rates: HashMap<String, BigDecimal>) -> Self {
         Self {
+            snapshot_id: idgen::next(),
+            fetched_at: chrono::Utc::now().naive_utc(),
             base_currency,
-            rates: Arc::new(Mutex::new(rates)),
+            rates,
         }
     }
 
-    pub fn is_currency_valid(&self, currency: &str) -> bool {
-        currency == self.base_currency || self.rates.lock().unwrap().contains_key(currency)
+    // value in into_currency rounded to its minor units
+    pub fn convert(&self, value: &Money, into_currency: &str) -> Result<Money, UnknownCurrency> {
+        Ok(Money::new(
+            self.exchange(value.currency(), value.amount().clone(), into_currency)?,
+            into_currency,
+        )
+        .round())
+    }
+
+    // price of one unit of from_currency in into_currency, not rounded
+    pub fn rate(&self, from_currency: &str, into_currency: &str) -> Result<BigDecimal, UnknownCurrency> {
+        self.exchange(from_currency, BigDecimal::from(1), into_currency)
     }
 
-    pub fn convert(
+    pub fn has_currency(&self, currency: &str) -> bool {
+        currency == self.base_currency || self.rates.contains_key(currency)
+    }
+
+    // base currency and every currency with a rate
+    pub fn currencies(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.base_currency.as_str()).chain(self.rates.keys().map(String::as_str))
+    }
+
+    fn exchange(
         &self,
         from_currency: &str,
         value: BigDecimal,
         into_currency: &str,
-    ) -> BigDecimal {
-        let rates = self.rates.lock().unwrap();
-        if from_currency == into_currency {
+    ) -> Result<BigDecimal, UnknownCurrency> {
+        let rate = |currency: &str| {
+            self.rates
+                .get(currency)
+                .ok_or_else(|| UnknownCurrency(currency.to_string()))
+        };
+        Ok(if from_currency == into_currency {
             value
         } else if from_currency == self.base_currency {
-            value * rates.get(into_currency).unwrap()
+            value * rate(into_currency)?
         } else if into_currency == self.base_currency {
-            value / rates.get(from_currency).unwrap()
+            value / rate(from_currency)?
         } else {
-            value * rates.get(into_currency).unwrap() / rates.get(from_currency).unwrap()
+            value * rate(into_currency)? / rate(from_currency)?
+        })
+    }
+}
+
+// currency the rates snapshot has no rate of
+#[derive(Debug, PartialEq)]
+pub struct UnknownCurrency(pub String);
+
+impl fmt::Display for UnknownCurrency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no rate of {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCurrency {}
+
+// conversions run inside database transactions, a missing rate aborts the transaction
+impl From<UnknownCurrency> for diesel::result::Error {
+    fn from(e: UnknownCurrency) -> Self {
+        diesel::result::Error::QueryBuilderError(Box::new(e))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CurrencyConverter {
+    // refresher replaces the whole snapshot, readers hold the lock only to clone the pointer
+    rates: Arc<RwLock<Arc<Rates>>>,
+}
+
+impl CurrencyConverter {
+    pub fn new(rates: Rates) -> Self {
+        Self {
+            rates: Arc::new(RwLock::new(Arc::new(rates))),
         }
     }
+
+    // rates of one snapshot, operations converting several values should use the same one
+    pub fn snapshot(&self) -> Arc<Rates> {
+        self.rates.read().unwrap().clone()
+    }
+
+    pub fn set_rates(&self, rates: Rates) {
+        *self.rates.write().unwrap() = Arc::new(rates);
+    }
+
+    pub fn base_currency(&self) -> String {
+        self.snapshot().base_currency.clone()
+    }
+
+    pub fn is_currency_valid(&self, currency: &str) -> bool {
+        self.snapshot().has_currency(currency)
+    }
+
+    pub fn convert(&self, value: &Money, into_currency: &str) -> Result<Money, UnknownCurrency> {
+        self.snapshot().convert(value, into_currency)
+    }
 }
 
-pub async fn create_currency_converter() -> CurrencyConverter {
-    // TODO: actually fetch the rates and update them periodically
-    // let json = reqwest::get(
-    //     "https://api.apilayer.com/exchangerates_data/latest?symbols=symbols&base=base",
-    // )
-    // .header("apikey", "api_key")
-    // .await
-    // .unwrap()
-    // .json::<serde_json::Value>()
-    // .await
-    // .unwrap();
-
-    let json: serde_json::Value = serde_json::from_str(STUB_CURRENCY_RATES_JSON).unwrap();
-    let base_currency = json["base"].as_str().unwrap().to_string();
-    let mut rates: HashMap<String, BigDecimal> = HashMap::new();
-    json.as_object()
-        .unwrap()
-        .get("rates")
-        .unwrap()
-        .as_object()
-        .unwrap()
+// money columns hold up to 8 digits after dot, enough for the smallest unit of any supported currency
+pub const MAX_MINOR_UNITS: i64 = 8;
+
+// ISO 4217 minor units of currencies that don't use 2 digits after dot,
+// units without minor ones in ISO 4217 (metals, XDR) and crypto currencies are kept at storage precision
+const MINOR_UNITS: &[(&str, i64)] = &[
+    ("BHD", 3),
+    ("BIF", 0),
+    ("BTC", 8),
+    ("BYR", 0),
+    ("CLF", 4),
+    ("CLP", 0),
+    ("DJF", 0),
+    ("GNF", 0),
+    ("IQD", 3),
+    ("ISK", 0),
+    ("JOD", 3),
+    ("JPY", 0),
+    ("KMF", 0),
+    ("KRW", 0),
+    ("KWD", 3),
+    ("LYD", 3),
+    ("OMR", 3),
+    ("PYG", 0),
+    ("RWF", 0),
+    ("TND", 3),
+    ("UGX", 0),
+    ("VND", 0),
+    ("VUV", 0),
+    ("XAF", 0),
+    ("XAG", MAX_MINOR_UNITS),
+    ("XAU", MAX_MINOR_UNITS),
+    ("XDR", MAX_MINOR_UNITS),
+    ("XOF", 0),
+    ("XPF", 0),
+];
+
+// digits after dot of the currency amounts
+pub fn minor_units(currency: &str) -> i64 {
+    MINOR_UNITS
         .iter()
-        .for_each(|(k, v)| {
-            rates.insert(
-                k.to_string(),
-                BigDecimal::from_f64(v.as_f64().unwrap()).unwrap(),
-            );
-        });
-    CurrencyConverter::new(base_currency, rates)
+        .find(|(code, _)| *code == currency)
+        .map_or(2, |(_, units)| *units)
 }
 
-const STUB_CURRENCY_RATES_JSON: &str = r#"{
-  "base": "EUR",
-  "date": "2022-11-20",
-  "rates": {
-    "AED": 3.799913,
-    "AFN": 91.040516,
-    "ALL": 117.162584,
-    "AMD": 408.830211,
-    "ANG": 1.862988,
-    "AOA": 528.802751,
-    "ARS": 168.470072,
-    "AUD": 1.549912,
-    "AWG": 1.862191,
-    "AZN": 1.756403,
-    "BAM": 1.950345,
-    "BBD": 2.08717,
-    "BDT": 106.527193,
-    "BGN": 1.960269,
-    "BHD": 0.389326,
-    "BIF": 2123.932057,
-    "BMD": 1.03455,
-    "BND": 1.41915,
-    "BOB": 7.143049,
-    "BRL": 5.568784,
-    "BSD": 1.033723,
-    "BTC": 6.2510943e-05,
-    "BTN": 84.429292,
-    "BWP": 13.390494,
-    "BYN": 2.610758,
-    "BYR": 20277.188664,
-    "BZD": 2.08368,
-    "CAD": 1.387281,
-    "CDF": 2114.620859,
-    "CHF": 0.987777,
-    "CLF": 0.035311,
-    "CLP": 974.339479,
-    "CNY": 7.3659,
-    "COP": 5161.196282,
-    "CRC": 631.117254,
-    "CUC": 1.03455,
-    "CUP": 27.415587,
-    "CVE": 110.541277,
-    "CZK": 24.399839,
-    "DJF": 183.860512,
-    "DKK": 7.453415,
-    "DOP": 56.385041,
-    "DZD": 143.858337,
-    "EGP": 25.412711,
-    "ERN": 15.518257,
-    "ETB": 54.800194,
-    "EUR": 1,
-    "FJD": 2.307824,
-    "FKP": 0.869885,
-    "GBP": 0.870211,
-    "GEL": 2.814406,
-    "GGP": 0.869885,
-    "GHS": 15.000588,
-    "GIP": 0.869885,
-    "GMD": 63.626077,
-    "GNF": 9078.179937,
-    "GTQ": 8.071317,
-    "GYD": 216.27176,
-    "HKD": 8.092202,
-    "HNL": 25.708943,
-    "HRK": 7.526667,
-    "HTG": 142.655555,
-    "HUF": 407.343721,
-    "IDR": 16181.817284,
-    "ILS": 3.586375,
-    "IMP": 0.869885,
-    "INR": 84.336707,
-    "IQD": 1510.443645,
-    "IRR": 43864.938367,
-    "ISK": 149.202686,
-    "JEP": 0.869885,
-    "JMD": 158.931096,
-    "JOD": 0.733449,
-    "JPY": 145.193963,
-    "KES": 126.370682,
-    "KGS": 87.360855,
-    "KHR": 4283.038765,
-    "KMF": 492.960672,
-    "KPW": 931.095413,
-    "KRW": 1386.328965,
-    "KWD": 0.318431,
-    "KYD": 0.861407,
-    "KZT": 477.53698,
-    "LAK": 19087.455773,
-    "LBP": 850.922872,
-    "LKR": 379.902419,
-    "LRD": 159.320487,
-    "LSL": 17.97059,
-    "LTL": 3.054759,
-    "LVL": 0.625789,
-    "LYD": 5.064168,
-    "MAD": 11.08469,
-    "MDL": 19.821741,
-    "MGA": 4466.154127,
-    "MKD": 61.442201,
-    "MMK": 2170.870312,
-    "MNT": 3531.935,
-    "MOP": 8.328658,
-    "MRO": 369.33433,
-    "MUR": 45.147845,
-    "MVR": 15.947597,
-    "MWK": 1059.379798,
-    "MXN": 20.112179,
-    "MYR": 4.711139,
-    "MZN": 66.035672,
-    "NAD": 17.969926,
-    "NGN": 457.953764,
-    "NIO": 37.243989,
-    "NOK": 10.545328,
-    "NPR": 135.088648,
-    "NZD": 1.681513,
-    "OMR": 0.397771,
-    "PAB": 1.033713,
-    "PEN": 3.940344,
-    "PGK": 3.641493,
-    "PHP": 59.183546,
-    "PKR": 230.13581,
-    "PLN": 4.708911,
-    "PYG": 7412.496478,
-    "QAR": 3.766285,
-    "RON": 4.951383,
-    "RSD": 117.32505,
-    "RUB": 62.952522,
-    "RWF": 1091.450716,
-    "SAR": 3.888389,
-    "SBD": 8.514963,
-    "SCR": 14.975175,
-    "SDG": 589.17846,
-    "SEK": 11.003891,
-    "SGD": 1.423647,
-    "SHP": 1.424989,
-    "SLE": 18.715042,
-    "SLL": 18647.772106,
-    "SOS": 588.14321,
-    "SRD": 31.699142,
-    "STD": 21413.105401,
-    "SVC": 9.044563,
-    "SYP": 2599.337015,
-    "SZL": 17.970319,
-    "THB": 37.046883,
-    "TJS": 10.551326,
-    "TMT": 3.631272,
-    "TND": 3.273836,
-    "TOP": 2.454315,
-    "TRY": 19.264674,
-    "TTD": 7.016602,
-    "TWD": 32.201729,
-    "TZS": 2412.571578,
-    "UAH": 38.177783,
-    "UGX": 3860.941532,
-    "USD": 1.03455,
-    "UYU": 41.131521,
-    "UZS": 11607.656055,
-    "VEF": 1011298.968166,
-    "VND": 25664.610091,
-    "VUV": 122.823144,
-    "WST": 2.881748,
-    "XAF": 654.133131,
-    "XAG": 0.049449,
-    "XAU": 0.000591,
-    "XCD": 2.795925,
-    "XDR": 0.788801,
-    "XOF": 663.672391,
-    "XPF": 119.645986,
-    "YER": 258.922152,
-    "ZAR": 17.856723,
-    "ZMK": 9312.187622,
-    "ZMW": 17.227433,
-    "ZWL": 333.12482
-  },
-  "success": true,
-  "timestamp": 1668963843
-}"#;
+// positive amount with no more digits after dot than the currency has
+pub fn is_value_valid(value: &BigDecimal, currency: &str) -> bool {
+    value.is_positive() && value.normalized().as_bigint_and_exponent().1 <= minor_units(currency)
+}
+
+// rounds value to minor units of the currency, half to even (banker's rounding),
+// BigDecimal::round alone overflows on long conversion quotients and rounds half away from zero
+pub fn round_value(value: &BigDecimal, currency: &str) -> BigDecimal {
+    let digits = minor_units(currency);
+    let truncated = value.with_scale(digits);
+    let unit = BigDecimal::new(1.into(), digits);
+    let twice_remainder = (value - &truncated).abs() * BigDecimal::from(2);
+    let (truncated_digits, _) = truncated.as_bigint_and_exponent();
+    let is_odd = !(BigDecimal::new(truncated_digits, 0) % BigDecimal::from(2)).is_zero();
+    if twice_remainder < unit || (twice_remainder == unit && !is_odd) {
+        truncated
+    } else if value.is_negative() {
+        truncated - unit
+    } else {
+        truncated + unit
+    }
+}
+
+// amount as string with exactly the minor units of the currency after dot
+pub fn format_value(value: &BigDecimal, currency: &str) -> String {
+    round_value(value, currency).to_string()
+}
+
+// loads initial rates from the provider configured by RATES_PROVIDER
+pub async fn create_currency_converter() -> CurrencyConverter {
+    let rates = rates::provider_from_env()
+        .fetch()
+        .await
+        .expect("Failed to load currency rates");
+    CurrencyConverter::new(rates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_round_value() {
+        let round =
+            |value: &str, currency: &str| round_value(&BigDecimal::from_str(value).unwrap(), currency).to_string();
+        assert_eq!(round("1.005", "USD"), "1.00");
+        assert_eq!(round("1.015", "USD"), "1.02");
+        assert_eq!(round("1.0051", "USD"), "1.01");
+        assert_eq!(round("-1.015", "USD"), "-1.02");
+        assert_eq!(round("-1.005", "USD"), "-1.00");
+        assert_eq!(round("2.5", "JPY"), "2");
+        assert_eq!(round("3.5", "JPY"), "4");
+        assert_eq!(round("1.2345", "BHD"), "1.234");
+        assert_eq!(round("0.123456789", "BTC"), "0.12345679");
+        assert_eq!(round("7", "USD"), "7.00");
+        // long conversion quotient
+        let quotient = BigDecimal::from(100) / BigDecimal::from(3);
+        assert_eq!(round_value(&quotient, "EUR").to_string(), "33.33");
+    }
+
+    #[test]
+    fn test_is_value_valid() {
+        let valid = |value: &str, currency: &str| is_value_valid(&BigDecimal::from_str(value).unwrap(), currency);
+        assert!(valid("10.25", "USD"));
+        assert!(valid("10.00", "JPY"));
+        assert!(!valid("10.5", "JPY"));
+        assert!(valid("10.125", "BHD"));
+        assert!(!valid("10.125", "USD"));
+        assert!(valid("0.00000001", "BTC"));
+        assert!(!valid("0", "USD"));
+        assert!(!valid("-1", "USD"));
+    }
+
+    #[test]
+    fn test_unknown_currency() {
+        let rates = Rates::new(
+            "EUR".to_string(),
+            HashMap::from([("USD".to_string(), BigDecimal::from_str("1.08").unwrap())]),
+        );
+        let eur = Money::new(BigDecimal::from(10), "EUR");
+        assert_eq!(
+            rates.convert(&eur, "USD"),
+            Ok(Money::new(BigDecimal::from_str("10.80").unwrap(), "USD"))
+        );
+        assert_eq!(rates.convert(&eur, "RUB"), Err(UnknownCurrency("RUB".to_string())));
+        assert_eq!(rates.rate("RUB", "USD"), Err(UnknownCurrency("RUB".to_string())));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::database::queries::{self, TransactionsFilter, TransactionsOrder, TransactionsPage, TransactionsPosition};
+use crate::proto;
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use prost::Message;
+use std::str::FromStr;
+
+// cursor is a protobuf message encoded with url-safe base64, clients must treat it as opaque string
+pub fn encode(filter: &TransactionsFilter, last: &TransactionsPosition) -> String {
+    let cursor = proto::TransactionsCursor {
+        user_id: filter.user_id.clone(),
+        order: order_into_proto(filter.order) as i32,
+        min_ts: filter.min_ts.map(Into::into),
+        max_ts: filter.max_ts.map(Into::into),
+        category: filter.category.clone().unwrap_or_default(),
+        last_id: last.id,
+        last_created_at: Some(last.created_at.into()),
+        last_user_currency_value: last.user_currency_value.to_string(),
+    };
+    base64::encode_config(cursor.encode_to_vec(), base64::URL_SAFE_NO_PAD)
+}
+
+// returns cursor pointing after the last transaction of the page, empty string for the last page
+pub fn next_page(filter: &TransactionsFilter, page: &TransactionsPage) -> String {
+    match page.transactions.last() {
+        Some(tx) if page.has_more => encode(filter, &queries::transaction_position(tx, filter.user_id.as_str())),
+        _ => String::new(),
+    }
+}
+
+// restores filter of the previous page request, returns None if cursor is malformed
+pub fn decode(cursor: &str) -> Option<TransactionsFilter> {
+    let bytes = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+    let cursor = proto::TransactionsCursor::decode(bytes.as_slice()).ok()?;
+    if cursor.user_id.is_empty() {
+        return None;
+    }
+    Some(TransactionsFilter {
+        user_id: cursor.user_id,
+        order: order_from_proto(cursor.order)?,
+        min_ts: match cursor.min_ts {
+            Some(ts) => Some(naive_utc(ts)?),
+            None => None,
+        },
+        max_ts: match cursor.max_ts {
+            Some(ts) => Some(naive_utc(ts)?),
+            None => None,
+        },
+        category: Some(cursor.category).filter(|category| !category.is_empty()),
+        after: Some(TransactionsPosition {
+            id: cursor.last_id,
+            created_at: naive_utc(cursor.last_created_at?)?,
+            user_currency_value: BigDecimal::from_str(cursor.last_user_currency_value.as_str()).ok()?,
+        }),
+    })
+}
+
+pub fn order_from_proto(order: i32) -> Option<TransactionsOrder> {
+    match proto::TransactionsOrder::from_i32(order)? {
+        proto::TransactionsOrder::DateDesc => Some(TransactionsOrder::DateDesc),
+        proto::TransactionsOrder::DateAsc => Some(TransactionsOrder::DateAsc),
+        proto::TransactionsOrder::AmountDesc => Some(TransactionsOrder::AmountDesc),
+        proto::TransactionsOrder::AmountAsc => Some(TransactionsOrder::AmountAsc),
+    }
+}
+
+fn order_into_proto(order: TransactionsOrder) -> proto::TransactionsOrder {
+    match order {
+        TransactionsOrder::DateDesc => proto::TransactionsOrder::DateDesc,
+        TransactionsOrder::DateAsc => proto::TransactionsOrder::DateAsc,
+        TransactionsOrder::AmountDesc => proto::TransactionsOrder::AmountDesc,
+        TransactionsOrder::AmountAsc => proto::TransactionsOrder::AmountAsc,
+    }
+}
+
+// unlike From<Timestamp> for DateTime, doesn't panic on out-of-range values
+pub fn naive_utc(ts: prost_wkt_types::Timestamp) -> Option<NaiveDateTime> {
+    if ts.nanos < 0 {
+        return None;
+    }
+    NaiveDateTime::from_timestamp_opt(ts.seconds, ts.nanos as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let now = chrono::Utc::now().naive_utc();
+        let filter = TransactionsFilter {
+            user_id: "test_user".to_string(),
+            order: TransactionsOrder::AmountAsc,
+            min_ts: Some(now),
+            max_ts: None,
+            category: Some("purchase".to_string()),
+            after: None,
+        };
+        let last = TransactionsPosition {
+            id: 42,
+            created_at: now,
+            user_currency_value: BigDecimal::from_str("10.50").unwrap(),
+        };
+
+        let cursor = encode(&filter, &last);
+        let decoded = decode(cursor.as_str()).unwrap();
+        assert_eq!(decoded.user_id, filter.user_id);
+        assert_eq!(decoded.order, filter.order);
+        assert_eq!(decoded.min_ts, filter.min_ts);
+        assert_eq!(decoded.max_ts, None);
+        assert_eq!(decoded.category, filter.category);
+        assert_eq!(decoded.after, Some(last));
+
+        assert!(decode("").is_none());
+        assert!(decode("not a cursor").is_none());
+    }
+}
@@ -15,7 +15,32 @@ pub fn create_db_connection_pool() -> Pool<ConnectionManager<PgConnection>> {
         .expect("Failed to create db connection pool.")
 }
 
+// single connection pool that never commits, for tests that reach the database through handlers
+#[cfg(test)]
+pub fn create_test_db_connection_pool() -> Pool<ConnectionManager<PgConnection>> {
+    use diesel::r2d2::{CustomizeConnection, Error};
+    use diesel::Connection;
+
+    #[derive(Debug)]
+    struct TestTransaction;
+
+    impl CustomizeConnection<PgConnection, Error> for TestTransaction {
+        fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), Error> {
+            conn.begin_test_transaction().map_err(Error::QueryError)
+        }
+    }
+
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let manager = ConnectionManager::<PgConnection>::new(database_url);
+    Pool::builder()
+        .max_size(1)
+        .connection_customizer(Box::new(TestTransaction))
+        .build(manager)
+        .expect("Failed to create db connection pool.")
+}
+
 // run diesel migrations
+#[allow(clippy::unused_unit)]
 pub fn run_migrations(pool: &Pool<ConnectionManager<PgConnection>>) -> () {
     pool.get()
         .unwrap()
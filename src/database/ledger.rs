@@ -0,0 +1,349 @@
+use crate::database::{idgen, models};
+use crate::fx::Conversion;
+use crate::money::Money;
+use bigdecimal::{BigDecimal, Zero};
+use diesel::result::Error;
+use diesel::sql_types::{Numeric, Varchar};
+use diesel::{ExpressionMethods, PgConnection, QueryDsl, QueryableByName, RunQueryDsl};
+use std::collections::{BTreeMap, BTreeSet};
+
+// funds of the user that are not held, balance.current_value of the wallet is the sum of its wallet and hold accounts
+pub const WALLET: &str = "wallet";
+// funds of the user held by reservations
+pub const HOLD: &str = "hold";
+// revenue from committed orders, in the currency of the order
+pub const REVENUE: &str = "revenue";
+// spread taken on conversion, in the currency of the converted wallet
+pub const FX_SPREAD: &str = "fx_spread";
+// currency position of the company, takes one currency and gives out another on conversion
+pub const FX_POSITION: &str = "fx_position";
+// money received through merchants on top-up
+pub const CLEARING: &str = "clearing";
+
+// operations that record journal entries
+pub const TOP_UP: &str = "top_up";
+pub const RESERVE: &str = "reserve";
+pub const COMMIT: &str = "commit";
+pub const CANCEL: &str = "cancel";
+pub const EXPIRE: &str = "expire";
+pub const TRANSFER: &str = "transfer";
+// remaining funds of a closed wallet
+pub const PAYOUT: &str = "payout";
+// wallet moved into another currency
+pub const CONVERT: &str = "convert";
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Account {
+    pub kind: &'static str,
+    // empty for company accounts
+    pub user_id: String,
+    pub currency: String,
+}
+
+impl Account {
+    pub fn wallet(user_id: &str, currency: &str) -> Self {
+        Self {
+            kind: WALLET,
+            user_id: user_id.to_string(),
+            currency: currency.to_string(),
+        }
+    }
+
+    pub fn hold(user_id: &str, currency: &str) -> Self {
+        Self {
+            kind: HOLD,
+            user_id: user_id.to_string(),
+            currency: currency.to_string(),
+        }
+    }
+
+    pub fn company(kind: &'static str, currency: &str) -> Self {
+        Self {
+            kind,
+            user_id: String::new(),
+            currency: currency.to_string(),
+        }
+    }
+}
+
+// postings of a single operation, nothing is written until the entry is recorded
+#[derive(Debug)]
+pub struct JournalEntry {
+    operation: &'static str,
+    transaction_id: Option<i64>,
+    order_id: Option<String>,
+    item_id: Option<String>,
+    postings: Vec<(Account, Money)>,
+}
+
+impl JournalEntry {
+    pub fn new(operation: &'static str) -> Self {
+        Self {
+            operation,
+            transaction_id: None,
+            order_id: None,
+            item_id: None,
+            postings: Vec::new(),
+        }
+    }
+
+    pub fn for_transaction(mut self, transaction_id: i64) -> Self {
+        self.transaction_id = Some(transaction_id);
+        self
+    }
+
+    pub fn for_order(mut self, order_id: &str, item_id: &str) -> Self {
+        self.order_id = Some(order_id.to_string());
+        self.item_id = Some(item_id.to_string());
+        self
+    }
+
+    // positive value increases the account, negative decreases it, zero values are skipped
+    pub fn post(&mut self, account: Account, value: Money) -> &mut Self {
+        assert_eq!(
+            account.currency,
+            value.currency(),
+            "{} account is posted a value in another currency",
+            account.kind
+        );
+        if !value.amount().is_zero() {
+            self.postings.push((account, value));
+        }
+        self
+    }
+
+    // moves value from one account to another
+    pub fn transfer(&mut self, from: Account, to: Account, value: &Money) -> &mut Self {
+        self.post(from, -value.clone()).post(to, value.clone())
+    }
+
+    // value is given to the fx position of its currency and converted value is taken from the position
+    // of the other one, nothing is posted when there is no conversion
+    pub fn exchange(&mut self, value: &Money, converted: &Money) -> &mut Self {
+        if value == converted {
+            return self;
+        }
+        self.post(Account::company(FX_POSITION, value.currency()), value.clone())
+            .post(Account::company(FX_POSITION, converted.currency()), -converted.clone())
+    }
+
+    // wallet pays the debited value, the spread is taken as income and the rest is exchanged into charged value,
+    // which is left for the caller to post
+    pub fn debit(&mut self, wallet: Account, debit: &Conversion, charged: &Money) -> &mut Self {
+        let converted = &debit.value - &debit.spread_income;
+        self.post(wallet, -debit.value.clone())
+            .post(
+                Account::company(FX_SPREAD, debit.spread_income.currency()),
+                debit.spread_income.clone(),
+            )
+            .exchange(&converted, charged)
+    }
+
+    // paid value, posted by the caller, is exchanged into wallet currency, the wallet gets it less the spread
+    pub fn credit(&mut self, paid: &Money, credit: &Conversion, wallet: Account) -> &mut Self {
+        let converted = &credit.value + &credit.spread_income;
+        self.exchange(paid, &converted).post(wallet, credit.value.clone()).post(
+            Account::company(FX_SPREAD, credit.spread_income.currency()),
+            credit.spread_income.clone(),
+        )
+    }
+
+    // values of every currency sum up to zero
+    pub fn is_balanced(&self) -> bool {
+        let mut sums: BTreeMap<&str, Money> = BTreeMap::new();
+        for (_, value) in &self.postings {
+            *sums
+                .entry(value.currency())
+                .or_insert_with(|| Money::zero(value.currency())) += value;
+        }
+        sums.values().all(|sum| sum.amount().is_zero())
+    }
+
+    // writes the entry and its postings, accounts are opened on their first posting.
+    // the database checks the balance again on commit
+    pub fn record(self, conn: &mut PgConnection) -> Result<i64, Error> {
+        assert!(
+            self.is_balanced(),
+            "{} journal entry is not balanced: {:?}",
+            self.operation,
+            self.postings
+        );
+        let new_entry_id = idgen::next();
+        {
+            use crate::schema::ledger_account::dsl::*;
+            let accounts: BTreeSet<&Account> = self.postings.iter().map(|(account, _)| account).collect();
+            let new_accounts: Vec<_> = accounts
+                .into_iter()
+                .map(|account| {
+                    (
+                        kind.eq(account.kind),
+                        user_id.eq(account.user_id.as_str()),
+                        currency.eq(account.currency.as_str()),
+                    )
+                })
+                .collect();
+            diesel::insert_into(ledger_account)
+                .values(&new_accounts)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+        }
+        {
+            use crate::schema::journal_entry::dsl::*;
+            diesel::insert_into(journal_entry)
+                .values(&models::NewJournalEntry {
+                    id: new_entry_id,
+                    operation: self.operation.to_string(),
+                    transaction_id: self.transaction_id,
+                    order_id: self.order_id,
+                    item_id: self.item_id,
+                })
+                .execute(conn)?;
+        }
+        {
+            // single statement, so the balance check sees all postings of the entry
+            use crate::schema::posting::dsl::*;
+            let new_postings: Vec<models::NewPosting> = self
+                .postings
+                .into_iter()
+                .map(|(account, posting_value)| models::NewPosting {
+                    id: idgen::next(),
+                    entry_id: new_entry_id,
+                    account_kind: account.kind.to_string(),
+                    user_id: account.user_id,
+                    currency: account.currency,
+                    value: posting_value.into_amount(),
+                })
+                .collect();
+            diesel::insert_into(posting).values(&new_postings).execute(conn)?;
+        }
+        Ok(new_entry_id)
+    }
+}
+
+// sum of all postings to the account
+#[cfg(test)]
+pub fn account_balance(conn: &mut PgConnection, account: &Account) -> Result<Money, Error> {
+    use crate::schema::posting::dsl::*;
+    let sum = posting
+        .filter(user_id.eq(&account.user_id))
+        .filter(currency.eq(&account.currency))
+        .filter(account_kind.eq(account.kind))
+        .select(diesel::dsl::sum(value))
+        .first::<Option<BigDecimal>>(conn)?;
+    Ok(Money::new(sum.unwrap_or_default(), account.currency.clone()))
+}
+
+// values of hold accounts of all users, keyed by (user_id, currency)
+pub fn hold_balances(conn: &mut PgConnection) -> Result<BTreeMap<(String, String), BigDecimal>, Error> {
+    use crate::schema::posting::dsl::*;
+    posting
+        .filter(account_kind.eq(HOLD))
+        .group_by((user_id, currency))
+        .select((user_id, currency, diesel::dsl::sum(value)))
+        .load::<(String, String, Option<BigDecimal>)>(conn)
+        .map(|sums| {
+            sums.into_iter()
+                .map(|(sum_user_id, sum_currency, sum)| ((sum_user_id, sum_currency), sum.unwrap_or_default()))
+                .collect()
+        })
+}
+
+// balance.current_value of the user wallet as derived from the ledger
+pub fn wallet_value(conn: &mut PgConnection, req_user_id: &str, req_currency: &str) -> Result<Money, Error> {
+    use crate::schema::posting::dsl::*;
+    let sum = posting
+        .filter(user_id.eq(req_user_id))
+        .filter(currency.eq(req_currency))
+        .filter(account_kind.eq_any([WALLET, HOLD]))
+        .select(diesel::dsl::sum(value))
+        .first::<Option<BigDecimal>>(conn)?;
+    Ok(Money::new(sum.unwrap_or_default(), req_currency))
+}
+
+// wallet whose balance differs from the one derived from the ledger
+#[derive(QueryableByName, PartialEq, Debug)]
+pub struct WalletDrift {
+    #[diesel(sql_type = Varchar)]
+    pub user_id: String,
+    #[diesel(sql_type = Varchar)]
+    pub currency: String,
+    #[diesel(sql_type = Numeric)]
+    pub current_value: BigDecimal,
+    #[diesel(sql_type = Numeric)]
+    pub ledger_value: BigDecimal,
+}
+
+// wallets of all users that don't match the ledger
+pub fn wallet_drift(conn: &mut PgConnection) -> Result<Vec<WalletDrift>, Error> {
+    diesel::sql_query(
+        "select balance.user_id, balance.currency, balance.current_value, \
+                coalesce(sum(posting.value), 0) as ledger_value \
+         from balance \
+         left join posting on posting.user_id = balance.user_id \
+                          and posting.currency = balance.currency \
+                          and posting.account_kind in ('wallet', 'hold') \
+         group by 1, 2, 3 \
+         having balance.current_value <> coalesce(sum(posting.value), 0) \
+         order by 1, 2",
+    )
+    .load::<WalletDrift>(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_journal_entry() {
+        let money = |value: &str, currency: &str| Money::new(BigDecimal::from_str(value).unwrap(), currency);
+
+        // 20 EUR order is paid from a USD wallet with 1% spread
+        let mut entry = JournalEntry::new(COMMIT);
+        entry
+            .debit(
+                Account::wallet("user", "USD"),
+                &Conversion {
+                    value: money("21.82", "USD"),
+                    spread_income: money("0.22", "USD"),
+                },
+                &money("20", "EUR"),
+            )
+            .post(Account::company(REVENUE, "EUR"), money("20", "EUR"));
+        assert!(entry.is_balanced());
+        assert_eq!(
+            entry.postings,
+            vec![
+                (Account::wallet("user", "USD"), money("-21.82", "USD")),
+                (Account::company(FX_SPREAD, "USD"), money("0.22", "USD")),
+                (Account::company(FX_POSITION, "USD"), money("21.60", "USD")),
+                (Account::company(FX_POSITION, "EUR"), money("-20", "EUR")),
+                (Account::company(REVENUE, "EUR"), money("20", "EUR")),
+            ]
+        );
+
+        // same currency needs no exchange, zero spread is not posted
+        let mut entry = JournalEntry::new(TOP_UP);
+        entry
+            .post(Account::company(CLEARING, "USD"), money("-10", "USD"))
+            .credit(
+                &money("10", "USD"),
+                &Conversion {
+                    value: money("10", "USD"),
+                    spread_income: money("0", "USD"),
+                },
+                Account::wallet("user", "USD"),
+            );
+        assert!(entry.is_balanced());
+        assert_eq!(entry.postings.len(), 2);
+
+        entry.transfer(
+            Account::wallet("user", "USD"),
+            Account::hold("user", "USD"),
+            &money("5", "USD"),
+        );
+        assert!(entry.is_balanced());
+        entry.post(Account::company(REVENUE, "USD"), money("1", "USD"));
+        assert!(!entry.is_balanced());
+    }
+}
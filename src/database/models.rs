@@ -2,11 +2,78 @@ use bigdecimal::BigDecimal;
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
 
+use crate::money::Money;
+
+#[derive(Queryable, Clone, Debug)]
+pub struct Client {
+    pub id: String,
+    #[allow(dead_code)]
+    pub name: String,
+    #[allow(dead_code)]
+    pub api_key_hash: String,
+    pub hmac_secret: Option<String>,
+    pub scopes: Vec<String>,
+    pub is_active: bool,
+    #[allow(dead_code)]
+    pub created_at: NaiveDateTime,
+}
+
 #[derive(Queryable)]
 pub struct Balance {
     pub user_id: String,
     pub currency: String,
     pub current_value: BigDecimal,
+    pub created_at: NaiveDateTime,
+    pub credit_limit: BigDecimal,
+    pub negative_since: Option<NaiveDateTime>,
+    pub status: String,
+}
+
+// values of balance.status
+pub const ACCOUNT_ACTIVE: &str = "active";
+// only credits are allowed
+pub const ACCOUNT_FROZEN_DEBITS: &str = "frozen_debits";
+// balance can't change, held reservations can still be cancelled
+pub const ACCOUNT_FROZEN_ALL: &str = "frozen_all";
+// final, the wallet is empty and has no reservations
+pub const ACCOUNT_CLOSED: &str = "closed";
+
+impl Balance {
+    pub fn value(&self) -> Money {
+        Money::new(self.current_value.clone(), self.currency.clone())
+    }
+
+    pub fn credit_limit(&self) -> Money {
+        Money::new(self.credit_limit.clone(), self.currency.clone())
+    }
+
+    // balance together with the credit line, the most that can be spent from the wallet
+    pub fn funds(&self) -> Money {
+        &self.value() + &self.credit_limit()
+    }
+
+    // reserve, commit and transfer can take funds from the wallet
+    pub fn allows_debits(&self) -> bool {
+        self.status == ACCOUNT_ACTIVE
+    }
+
+    // top-up and transfer can put funds into the wallet
+    pub fn allows_credits(&self) -> bool {
+        self.status == ACCOUNT_ACTIVE || self.status == ACCOUNT_FROZEN_DEBITS
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::balance_status_history)]
+pub struct NewBalanceStatusChange {
+    pub id: i64,
+    pub user_id: String,
+    pub currency: String,
+    pub old_status: Option<String>,
+    pub new_status: String,
+    pub reason: String,
+    pub payout_transaction_id: Option<i64>,
+    pub created_at: NaiveDateTime,
 }
 
 #[derive(Queryable)]
@@ -18,6 +85,25 @@ pub struct BalanceReserve {
     pub value: BigDecimal,
     pub user_currency_value: BigDecimal,
     pub created_at: NaiveDateTime,
+    pub captured_value: BigDecimal,
+    pub expires_at: Option<NaiveDateTime>,
+    pub wallet_currency: String,
+}
+
+impl BalanceReserve {
+    // what is left of the reserved order value
+    pub fn reserved(&self) -> Money {
+        Money::new(self.value.clone(), self.currency.clone())
+    }
+
+    // held in the wallet for the reservation
+    pub fn held(&self) -> Money {
+        Money::new(self.user_currency_value.clone(), self.wallet_currency.clone())
+    }
+
+    pub fn captured(&self) -> Money {
+        Money::new(self.captured_value.clone(), self.currency.clone())
+    }
 }
 
 #[derive(Queryable)]
@@ -30,15 +116,41 @@ pub struct Transaction {
     pub sender_value: Option<BigDecimal>,
     pub sender_balance_before: Option<BigDecimal>,
     pub sender_balance_after: Option<BigDecimal>,
+    #[allow(dead_code)]
     pub recipient_id: Option<String>,
     pub recipient_currency: Option<String>,
     pub recipient_value: Option<BigDecimal>,
     pub recipient_balance_before: Option<BigDecimal>,
     pub recipient_balance_after: Option<BigDecimal>,
+    #[allow(dead_code)]
     pub merchant_data: Option<serde_json::Value>,
     pub order_data: Option<serde_json::Value>,
     pub created_at: NaiveDateTime,
+    #[allow(dead_code)]
     pub idempotency_key: Option<String>,
+    pub rate_snapshot_id: Option<i64>,
+    pub rate: Option<BigDecimal>,
+    // empty for transactions made before descriptions were kept
+    pub description: Option<String>,
+    pub category: String,
+    pub source: Option<String>,
+}
+
+impl Transaction {
+    pub fn value(&self) -> Money {
+        Money::new(self.transaction_value.clone(), self.transaction_currency.clone())
+    }
+
+    pub fn sender_value(&self) -> Option<Money> {
+        Some(Money::new(self.sender_value.clone()?, self.sender_currency.clone()?))
+    }
+
+    pub fn recipient_value(&self) -> Option<Money> {
+        Some(Money::new(
+            self.recipient_value.clone()?,
+            self.recipient_currency.clone()?,
+        ))
+    }
 }
 
 #[derive(Insertable)]
@@ -55,6 +167,9 @@ pub struct NewTopupTransaction {
     pub merchant_data: Option<serde_json::Value>,
     pub created_at: NaiveDateTime,
     pub idempotency_key: Option<String>,
+    pub rate_snapshot_id: Option<i64>,
+    #[diesel(embed)]
+    pub details: NewTransactionDetails,
 }
 
 #[derive(Insertable)]
@@ -69,8 +184,74 @@ pub struct NewCommitTransaction {
     pub sender_balance_before: Option<BigDecimal>,
     pub sender_balance_after: Option<BigDecimal>,
     pub order_data: Option<serde_json::Value>,
+    pub idempotency_key: Option<String>,
+    pub rate_snapshot_id: Option<i64>,
+    #[diesel(embed)]
+    pub details: NewTransactionDetails,
 }
 
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::transaction)]
+pub struct NewTransferTransaction {
+    pub id: i64,
+    pub transaction_currency: String,
+    pub transaction_value: BigDecimal,
+    pub sender_id: Option<String>,
+    pub sender_currency: Option<String>,
+    pub sender_value: Option<BigDecimal>,
+    pub sender_balance_before: Option<BigDecimal>,
+    pub sender_balance_after: Option<BigDecimal>,
+    pub recipient_id: Option<String>,
+    pub recipient_currency: Option<String>,
+    pub recipient_value: Option<BigDecimal>,
+    pub recipient_balance_before: Option<BigDecimal>,
+    pub recipient_balance_after: Option<BigDecimal>,
+    pub created_at: NaiveDateTime,
+    pub idempotency_key: Option<String>,
+    pub rate_snapshot_id: Option<i64>,
+    #[diesel(embed)]
+    pub details: NewTransactionDetails,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::transaction)]
+pub struct NewConversionTransaction {
+    pub id: i64,
+    pub transaction_currency: String,
+    pub transaction_value: BigDecimal,
+    pub sender_id: Option<String>,
+    pub sender_currency: Option<String>,
+    pub sender_value: Option<BigDecimal>,
+    pub sender_balance_before: Option<BigDecimal>,
+    pub sender_balance_after: Option<BigDecimal>,
+    pub recipient_id: Option<String>,
+    pub recipient_currency: Option<String>,
+    pub recipient_value: Option<BigDecimal>,
+    pub recipient_balance_before: Option<BigDecimal>,
+    pub recipient_balance_after: Option<BigDecimal>,
+    pub created_at: NaiveDateTime,
+    pub rate_snapshot_id: Option<i64>,
+    pub rate: Option<BigDecimal>,
+    #[diesel(embed)]
+    pub details: NewTransactionDetails,
+}
+
+// texts of a transaction shown in the user history
+#[derive(Insertable, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::transaction)]
+pub struct NewTransactionDetails {
+    pub description: Option<String>,
+    pub category: String,
+    pub source: Option<String>,
+}
+
+// transaction.category values the service uses by default, callers may use their own ones
+pub const CATEGORY_TOP_UP: &str = "top_up";
+pub const CATEGORY_PURCHASE: &str = "purchase";
+pub const CATEGORY_TRANSFER: &str = "transfer";
+// wallet closing payouts and currency changes
+pub const CATEGORY_ADJUSTMENT: &str = "adjustment";
+
 #[derive(Insertable)]
 #[diesel(table_name = crate::schema::balance_reserve)]
 pub struct NewBalanceReserve {
@@ -81,4 +262,174 @@ pub struct NewBalanceReserve {
     pub value: BigDecimal,
     pub user_currency_value: BigDecimal,
     pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub wallet_currency: String,
+}
+
+// values of order_history.state
+pub const ORDER_COMMITTED: &str = "committed";
+pub const ORDER_CANCELLED: &str = "cancelled";
+pub const ORDER_EXPIRED: &str = "expired";
+
+#[derive(Queryable)]
+pub struct OrderHistory {
+    #[allow(dead_code)]
+    pub id: i64,
+    #[allow(dead_code)]
+    pub order_id: String,
+    pub user_id: String,
+    pub item_id: String,
+    pub state: String,
+    pub currency: String,
+    #[allow(dead_code)]
+    pub released_value: BigDecimal,
+    pub captured_value: BigDecimal,
+    #[allow(dead_code)]
+    pub reserved_at: NaiveDateTime,
+    pub finished_at: NaiveDateTime,
+}
+
+impl OrderHistory {
+    pub fn captured(&self) -> Money {
+        Money::new(self.captured_value.clone(), self.currency.clone())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::order_history)]
+pub struct NewOrderHistory {
+    pub id: i64,
+    pub order_id: String,
+    pub user_id: String,
+    pub item_id: String,
+    pub state: String,
+    pub currency: String,
+    pub released_value: BigDecimal,
+    pub captured_value: BigDecimal,
+    pub reserved_at: NaiveDateTime,
+    pub finished_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+#[allow(dead_code)]
+pub struct CurrencyRate {
+    pub snapshot_id: i64,
+    pub base_currency: String,
+    pub currency: String,
+    pub rate: BigDecimal,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::currency_rate)]
+pub struct NewCurrencyRate {
+    pub snapshot_id: i64,
+    pub base_currency: String,
+    pub currency: String,
+    pub rate: BigDecimal,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct Quote {
+    pub id: i64,
+    pub from_currency: String,
+    pub to_currency: String,
+    pub value: BigDecimal,
+    pub converted_value: BigDecimal,
+    pub rate: BigDecimal,
+    pub rate_snapshot_id: i64,
+    #[allow(dead_code)]
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub spread: BigDecimal,
+    pub user_id: Option<String>,
+    // order item that took the quote, none while it is not used
+    pub order_id: Option<String>,
+    pub item_id: Option<String>,
+}
+
+impl Quote {
+    // the most that can be converted with the quote
+    pub fn value(&self) -> Money {
+        Money::new(self.value.clone(), self.from_currency.clone())
+    }
+
+    pub fn converted_value(&self) -> Money {
+        Money::new(self.converted_value.clone(), self.to_currency.clone())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::quote)]
+pub struct NewQuote {
+    pub id: i64,
+    pub from_currency: String,
+    pub to_currency: String,
+    pub value: BigDecimal,
+    pub converted_value: BigDecimal,
+    pub rate: BigDecimal,
+    pub rate_snapshot_id: i64,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub spread: BigDecimal,
+    pub user_id: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::spread_income)]
+pub struct NewSpreadIncome {
+    pub id: i64,
+    pub transaction_id: i64,
+    pub currency: String,
+    pub value: BigDecimal,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::journal_entry)]
+pub struct NewJournalEntry {
+    pub id: i64,
+    pub operation: String,
+    pub transaction_id: Option<i64>,
+    pub order_id: Option<String>,
+    pub item_id: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::posting)]
+pub struct NewPosting {
+    pub id: i64,
+    pub entry_id: i64,
+    pub account_kind: String,
+    pub user_id: String,
+    pub currency: String,
+    pub value: BigDecimal,
+}
+
+#[derive(Queryable, PartialEq, Debug)]
+pub struct ReconciliationReport {
+    pub id: i64,
+    pub checked_wallets: i64,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::reconciliation_report)]
+pub struct NewReconciliationReport {
+    pub id: i64,
+    pub checked_wallets: i64,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Insertable, Clone, PartialEq, Debug)]
+#[diesel(table_name = crate::schema::reconciliation_finding)]
+pub struct ReconciliationFinding {
+    pub id: i64,
+    pub report_id: i64,
+    pub user_id: String,
+    pub currency: String,
+    pub kind: String,
+    pub transaction_id: Option<i64>,
+    pub expected: BigDecimal,
+    pub actual: BigDecimal,
 }
@@ -1,13 +1,20 @@
-use crate::currency::CurrencyConverter;
+use crate::currency::{CurrencyConverter, Rates};
+use crate::database::ledger::{self, Account, JournalEntry};
+use crate::database::queries;
 use crate::database::{idgen, models};
-use bigdecimal::{BigDecimal, FromPrimitive, Signed};
+use crate::funding;
+use crate::fx::{Conversion, FxRule};
+use crate::money::Money;
+use crate::reconcile::Finding;
+use bigdecimal::{BigDecimal, Zero};
+use chrono::NaiveDateTime;
 use diesel::result::Error;
 use diesel::{
-    Connection, ExpressionMethods, OptionalExtension, PgAnyJsonExpressionMethods, PgConnection, QueryDsl, QueryResult,
-    RunQueryDsl,
+    Connection, ExpressionMethods, OptionalExtension, PgAnyJsonExpressionMethods, PgConnection, QueryDsl, RunQueryDsl,
 };
+use std::collections::HashMap;
 
-// creates new balance table record, on conflict does nothing
+// creates user wallet in the currency, on conflict does nothing
 fn init_user_balance(conn: &mut PgConnection, req_currency: &str, req_user_id: &str) -> Result<bool, Error> {
     use crate::schema::balance::dsl::*;
     diesel::insert_into(balance)
@@ -15,32 +22,425 @@ fn init_user_balance(conn: &mut PgConnection, req_currency: &str, req_user_id: &
             user_id.eq(req_user_id),
             currency.eq(req_currency),
             current_value.eq(BigDecimal::from(0)),
+            created_at.eq(chrono::Utc::now().naive_utc()),
         ))
-        .on_conflict(user_id)
+        .on_conflict((user_id, currency))
         .do_nothing()
         .execute(conn)
         .map(|res| res > 0)
 }
 
-// adds value to balance, returns new balance
+// records status change of the wallet, req_old_status is none when the wallet is opened
+fn log_status_change(
+    conn: &mut PgConnection,
+    req_user_id: &str,
+    req_currency: &str,
+    req_old_status: Option<&str>,
+    req_new_status: &str,
+    req_reason: &str,
+    req_payout_transaction_id: Option<i64>,
+) -> Result<usize, Error> {
+    use crate::schema::balance_status_history::dsl::*;
+    diesel::insert_into(balance_status_history)
+        .values(&models::NewBalanceStatusChange {
+            id: idgen::next(),
+            user_id: req_user_id.to_string(),
+            currency: req_currency.to_string(),
+            old_status: req_old_status.map(str::to_string),
+            new_status: req_new_status.to_string(),
+            reason: req_reason.to_string(),
+            payout_transaction_id: req_payout_transaction_id,
+            created_at: chrono::Utc::now().naive_utc(),
+        })
+        .execute(conn)
+}
+
+// locks all wallets of the users in (user_id, currency) order to avoid deadlocks,
+// returns them ordered by creation, so the primary wallet of a user comes first and closed wallets come last
+fn lock_wallets(conn: &mut PgConnection, req_user_ids: &[&str]) -> Result<Vec<models::Balance>, Error> {
+    let mut wallets = {
+        use crate::schema::balance::dsl::*;
+        balance
+            .filter(user_id.eq_any(req_user_ids))
+            .order((user_id, currency))
+            .for_update()
+            .load::<models::Balance>(conn)?
+    };
+    wallets.sort_by_key(|wallet| {
+        (
+            wallet.status == models::ACCOUNT_CLOSED,
+            wallet.created_at,
+            wallet.currency.clone(),
+        )
+    });
+    Ok(wallets)
+}
+
+// sums reserved values of the user per wallet currency
+fn wallet_reservations(conn: &mut PgConnection, req_user_id: &str) -> Result<HashMap<String, Money>, Error> {
+    use crate::schema::balance_reserve::dsl::*;
+    balance_reserve
+        .filter(user_id.eq(req_user_id))
+        .group_by(wallet_currency)
+        .select((wallet_currency, diesel::dsl::sum(user_currency_value)))
+        .load::<(String, Option<BigDecimal>)>(conn)
+        .map(|sums| {
+            sums.into_iter()
+                .map(|(sum_currency, sum)| (sum_currency.clone(), Money::new(sum.unwrap_or_default(), sum_currency)))
+                .collect()
+        })
+}
+
+// funds of the wallet including its credit line that are not held by reservations
+fn available(wallet: &models::Balance, reservations: &HashMap<String, Money>) -> Money {
+    match reservations.get(&wallet.currency) {
+        Some(reserved) => &wallet.funds() - reserved,
+        None => wallet.funds(),
+    }
+}
+
+// first wallet in funding strategy order that has enough funds
+fn choose_wallet<'a>(
+    wallets: &'a [models::Balance],
+    req_currency: &str,
+    has_enough: impl Fn(&models::Balance) -> bool,
+) -> Option<&'a models::Balance> {
+    let currencies: Vec<&str> = wallets.iter().map(|wallet| wallet.currency.as_str()).collect();
+    funding::wallet_order(*funding::FUNDING_STRATEGY, &currencies, req_currency)
+        .into_iter()
+        .map(|idx| &wallets[idx])
+        .find(|wallet| has_enough(wallet))
+}
+
+// sets value of the user wallet derived from the ledger, keeps the time the wallet went below zero
+// for overdraft reports
+fn update_wallet(conn: &mut PgConnection, wallet: &models::Balance) -> Result<usize, Error> {
+    let req_current_value = ledger::wallet_value(conn, &wallet.user_id, &wallet.currency)?;
+    use crate::schema::balance::dsl::*;
+    let req_negative_since = match req_current_value.is_negative() {
+        true => wallet.negative_since.or_else(|| Some(chrono::Utc::now().naive_utc())),
+        false => None,
+    };
+    diesel::update(balance.find((&wallet.user_id, &wallet.currency)))
+        .set((
+            current_value.eq(req_current_value.into_amount()),
+            negative_since.eq(req_negative_since),
+        ))
+        .execute(conn)
+}
+
+// writes all rates of the snapshot to rate history
+pub fn save_rates(conn: &mut PgConnection, rates: &Rates) -> Result<usize, Error> {
+    use crate::schema::currency_rate::dsl::*;
+    let new_rates: Vec<models::NewCurrencyRate> = rates
+        .rates
+        .iter()
+        .map(|(rate_currency, rate_value)| models::NewCurrencyRate {
+            snapshot_id: rates.snapshot_id,
+            base_currency: rates.base_currency.clone(),
+            currency: rate_currency.clone(),
+            rate: rate_value.clone(),
+            created_at: rates.fetched_at,
+        })
+        .collect();
+    diesel::insert_into(currency_rate)
+        .values(&new_rates)
+        .on_conflict_do_nothing()
+        .execute(conn)
+}
+
+// saves findings of a reconciliation run
+pub fn save_reconciliation_report(
+    conn: &mut PgConnection,
+    req_checked_wallets: i64,
+    findings: Vec<Finding>,
+) -> Result<(models::ReconciliationReport, Vec<models::ReconciliationFinding>), Error> {
+    conn.transaction(|conn| {
+        let report = {
+            use crate::schema::reconciliation_report::dsl::*;
+            diesel::insert_into(reconciliation_report)
+                .values(&models::NewReconciliationReport {
+                    id: idgen::next(),
+                    checked_wallets: req_checked_wallets,
+                    created_at: chrono::Utc::now().naive_utc(),
+                })
+                .get_result::<models::ReconciliationReport>(conn)?
+        };
+        let rows: Vec<models::ReconciliationFinding> = findings
+            .into_iter()
+            .map(|finding| models::ReconciliationFinding {
+                id: idgen::next(),
+                report_id: report.id,
+                user_id: finding.user_id,
+                currency: finding.currency,
+                kind: finding.kind.to_string(),
+                transaction_id: finding.transaction_id,
+                expected: finding.expected,
+                actual: finding.actual,
+            })
+            .collect();
+        {
+            use crate::schema::reconciliation_finding::dsl::*;
+            diesel::insert_into(reconciliation_finding)
+                .values(&rows)
+                .execute(conn)?;
+        }
+        Ok((report, rows))
+    })
+}
+
+// conversion rules from the request currency into each of the wallets
+fn wallet_fx_rules(
+    conn: &mut PgConnection,
+    req_currency: &str,
+    wallets: &[models::Balance],
+) -> Result<HashMap<String, FxRule>, Error> {
+    wallets
+        .iter()
+        .map(|wallet| {
+            Ok((
+                wallet.currency.clone(),
+                queries::load_fx_rule(conn, req_currency, &wallet.currency)?,
+            ))
+        })
+        .collect()
+}
+
+// request value converted into the currency of each of the wallets
+fn wallet_values(
+    rates: &Rates,
+    req_value: &Money,
+    wallets: &[models::Balance],
+) -> Result<HashMap<String, Money>, Error> {
+    wallets
+        .iter()
+        .map(|wallet| Ok((wallet.currency.clone(), rates.convert(req_value, &wallet.currency)?)))
+        .collect()
+}
+
+// spread locked by the quote replaces the configured one, its rate needs no reserve margin
+fn quote_fx_rule(rule: &FxRule, quote: &models::Quote) -> FxRule {
+    FxRule {
+        spread: quote.spread.clone(),
+        reserve_margin: BigDecimal::from(0),
+        max_overdraft: rule.max_overdraft.clone(),
+    }
+}
+
+// books spread taken on conversion of the transaction, zero spread is not booked
+fn book_spread_income(conn: &mut PgConnection, req_transaction_id: i64, req_value: Money) -> Result<usize, Error> {
+    use crate::schema::spread_income::dsl::*;
+    if req_value <= Money::zero(req_value.currency()) {
+        return Ok(0);
+    }
+    diesel::insert_into(spread_income)
+        .values(&models::NewSpreadIncome {
+            id: idgen::next(),
+            transaction_id: req_transaction_id,
+            currency: req_value.currency().to_string(),
+            value: req_value.into_amount(),
+        })
+        .execute(conn)
+}
+
+// id of the rates snapshot to reference from a transaction, only when some value was converted
+fn converted_with(snapshot_id: i64, req_currency: &str, wallet_currencies: &[&str]) -> Option<i64> {
+    wallet_currencies
+        .iter()
+        .any(|wallet_currency| *wallet_currency != req_currency)
+        .then_some(snapshot_id)
+}
+
+// texts of a transaction supplied by the caller, missing ones are generated by the operation
+#[derive(Default, Clone, Copy, Debug)]
+pub struct TransactionDetails<'a> {
+    pub description: Option<&'a str>,
+    pub category: Option<&'a str>,
+    pub source: Option<&'a str>,
+}
+
+impl<'a> TransactionDetails<'a> {
+    // empty fields are missing
+    pub fn new(description: &'a str, category: &'a str, source: &'a str) -> Self {
+        let non_empty = |field: &'a str| Some(field).filter(|field| !field.is_empty());
+        Self {
+            description: non_empty(description),
+            category: non_empty(category),
+            source: non_empty(source),
+        }
+    }
+
+    // name of the first field that doesn't fit its column, category is a lowercase identifier
+    pub fn invalid_field(&self) -> Option<&'static str> {
+        if self
+            .description
+            .is_some_and(|description| description.chars().count() > 1024)
+        {
+            return Some("description");
+        }
+        if self.category.is_some_and(|category| {
+            category.len() > 32
+                || !category
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        }) {
+            return Some("category");
+        }
+        if self.source.is_some_and(|source| source.chars().count() > 64) {
+            return Some("source");
+        }
+        None
+    }
+
+    // fields given by the caller win over the generated ones
+    fn or_default(self, description: String, category: &str, source: Option<String>) -> models::NewTransactionDetails {
+        models::NewTransactionDetails {
+            description: Some(self.description.map_or(description, str::to_string)),
+            category: self.category.unwrap_or(category).to_string(),
+            source: self.source.map(str::to_string).or(source),
+        }
+    }
+}
+
+// merchant named in the free-form merchant data of a top-up, cut to fit transaction.source
+fn merchant_name(merchant_data: Option<&str>) -> Option<String> {
+    let data = serde_json::from_str::<serde_json::Value>(merchant_data?).ok()?;
+    ["merchant", "merchant_id"]
+        .iter()
+        .find_map(|key| data.get(key)?.as_str())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.chars().take(64).collect())
+}
+
+// locks the current rate for converting up to req_value into req_to_currency for one order item of the user
+pub fn create_quote(
+    conn: &mut PgConnection,
+    curr: &CurrencyConverter,
+    req_user_id: &str,
+    req_value: Money,
+    req_to_currency: &str,
+    req_expires_at: NaiveDateTime,
+) -> Result<models::Quote, Error> {
+    let req_from_currency = req_value.currency();
+    let rule = queries::load_fx_rule(conn, req_from_currency, req_to_currency)?;
+    use crate::schema::quote::dsl::*;
+    let rates = curr.snapshot();
+    let quote_rate = rates
+        .rate(req_from_currency, req_to_currency)?
+        .with_scale(11)
+        .round(10)
+        .normalized();
+    let new_quote = models::NewQuote {
+        id: idgen::next(),
+        from_currency: req_from_currency.to_string(),
+        to_currency: req_to_currency.to_string(),
+        // charged from the wallet, spread included, the same way as the quoted value on reserve and commit
+        converted_value: rule
+            .debit(&Money::new(req_value.amount() * &quote_rate, req_to_currency).round())
+            .value
+            .into_amount(),
+        value: req_value.into_amount(),
+        rate: quote_rate,
+        rate_snapshot_id: rates.snapshot_id,
+        created_at: chrono::Utc::now().naive_utc(),
+        expires_at: req_expires_at,
+        spread: rule.spread,
+        user_id: req_user_id.to_string(),
+    };
+    diesel::insert_into(quote).values(&new_quote).get_result(conn)
+}
+
+// quote that converts req_value for the order item of the user, None when it is unknown, expired, quoted for
+// another user, currency or a smaller value, or taken by another order item
+fn valid_quote(
+    conn: &mut PgConnection,
+    req_quote_id: i64,
+    req_user_id: &str,
+    req_value: &Money,
+    req_order_id: &str,
+    req_item_id: &str,
+) -> Result<Option<models::Quote>, Error> {
+    use crate::schema::quote::dsl::*;
+    let now = chrono::Utc::now().naive_utc();
+    // locked until the transaction ends, so only one order item takes the quote
+    let found = quote
+        .find(req_quote_id)
+        .for_update()
+        .first::<models::Quote>(conn)
+        .optional()?;
+    Ok(found.filter(|found| {
+        found.user_id.as_deref() == Some(req_user_id)
+            && found.from_currency == req_value.currency()
+            && found.expires_at >= now
+            && *req_value <= found.value()
+            && (found.order_id.is_none()
+                || (found.order_id.as_deref() == Some(req_order_id) && found.item_id.as_deref() == Some(req_item_id)))
+    }))
+}
+
+// binds the quote to the order item that uses it first, later captures of the item keep using it
+fn take_quote(
+    conn: &mut PgConnection,
+    used_quote: &models::Quote,
+    req_order_id: &str,
+    req_item_id: &str,
+) -> Result<(), Error> {
+    if used_quote.order_id.is_some() {
+        return Ok(());
+    }
+    use crate::schema::quote::dsl::*;
+    diesel::update(quote.find(used_quote.id))
+        .set((order_id.eq(req_order_id), item_id.eq(req_item_id)))
+        .execute(conn)?;
+    Ok(())
+}
+
+// value converted at the locked rate of the quote and rounded to minor units of its currency, before spread
+fn quoted_value(quote: &models::Quote, req_value: &Money) -> Money {
+    Money::new(req_value.amount() * &quote.rate, quote.to_currency.clone()).round()
+}
+
+// adds value to the user wallet in the same currency, creates the wallet on first top-up
+#[derive(PartialEq, Debug)]
+pub enum TopUpResult {
+    Ok(i64),
+    // wallet is frozen for all operations or closed, or a new wallet is asked for a user without open wallets
+    AccountBlocked,
+}
+
 pub fn top_up(
     conn: &mut PgConnection,
     curr: &CurrencyConverter,
     req_idempotency_key: &str,
     req_user_id: &str,
-    req_currency: &str,
-    req_value: BigDecimal,
+    req_value: Money,
     req_merchant_data: Option<&str>,
-) -> Result<i64, Error> {
+    req_details: TransactionDetails,
+) -> Result<TopUpResult, Error> {
+    let req_currency = req_value.currency();
+    // wallet in a new currency is opened on the first top-up only while the user has a wallet that takes credits,
+    // a closed or frozen user can't be reopened by money in another currency
+    let wallets = {
+        use crate::schema::balance::dsl::*;
+        balance.filter(user_id.eq(req_user_id)).load::<models::Balance>(conn)?
+    };
+    if !wallets.iter().any(|wallet| wallet.currency == req_currency)
+        && !wallets.is_empty()
+        && !wallets.iter().any(models::Balance::allows_credits)
+    {
+        return Ok(TopUpResult::AccountBlocked);
+    }
     init_user_balance(conn, req_currency, req_user_id)?;
 
     // wrap in transaction
     conn.transaction::<_, Error, _>(|conn| {
-        // load user balance record and lock for update
+        // same rates for every conversion of the operation
+        let rates = curr.snapshot();
+        // load user wallet record and lock for update
         let user_balance = {
             use crate::schema::balance::dsl::*;
             balance
-                .filter(user_id.eq(req_user_id))
+                .find((req_user_id, req_currency))
                 .for_update()
                 .first::<models::Balance>(conn)
         };
@@ -57,15 +457,24 @@ pub fn top_up(
                 .optional()
         };
         match user_transaction {
-            Ok(Some(user_transaction)) => return Ok(user_transaction.id),
+            Ok(Some(user_transaction)) => return Ok(TopUpResult::Ok(user_transaction.id)),
             Err(e) => return Err(e),
             Ok(None) => {}
         };
+        if !user_balance.allows_credits() {
+            return Ok(TopUpResult::AccountBlocked);
+        }
 
-        // convert value to user currency
-        let topup_in_user_currency = curr.convert(req_currency, req_value.clone(), user_balance.currency.as_str());
-        let balance_after_topup = user_balance.current_value.clone() + topup_in_user_currency.clone();
+        // wallet is in the same currency, so the rule takes no spread unless top-ups get converted
+        let rule = queries::load_fx_rule(conn, req_currency, &user_balance.currency)?;
+        let topup = rule.credit(&rates.convert(&req_value, &user_balance.currency)?);
+        let balance_after_topup = &user_balance.value() + &topup.value;
 
+        let merchant = merchant_name(req_merchant_data);
+        let tx_description = match &merchant {
+            Some(merchant) => format!("Top-up of {req_value} {req_currency} via {merchant}"),
+            None => format!("Top-up of {req_value} {req_currency}"),
+        };
         let tx_id = idgen::next();
         {
             // create transaction record
@@ -74,30 +483,148 @@ pub fn top_up(
             let new_transaction = models::NewTopupTransaction {
                 id: tx_id,
                 transaction_currency: req_currency.to_string(),
-                transaction_value: req_value,
+                transaction_value: req_value.amount().clone(),
                 recipient_id: Some(req_user_id.to_string()),
                 recipient_currency: Some(user_balance.currency.to_string()),
-                recipient_value: Some(topup_in_user_currency),
+                recipient_value: Some(topup.value.amount().clone()),
                 recipient_balance_before: Some(user_balance.current_value.clone()),
-                recipient_balance_after: Some(balance_after_topup.clone()),
+                recipient_balance_after: Some(balance_after_topup.amount().clone()),
                 merchant_data: req_merchant_data.map(|s| serde_json::Value::String(s.to_string())),
                 created_at: chrono::Utc::now().naive_utc(),
                 idempotency_key: Some(req_idempotency_key.to_string()),
+                rate_snapshot_id: converted_with(rates.snapshot_id, req_currency, &[&user_balance.currency]),
+                details: req_details.or_default(tx_description, models::CATEGORY_TOP_UP, merchant),
             };
             diesel::insert_into(transaction)
                 .values(&new_transaction)
                 .execute(conn)?;
         }
-        {
-            // update balance
-            use crate::schema::balance::dsl::*;
-            diesel::update(balance.filter(user_id.eq(req_user_id)))
-                .set(current_value.eq(balance_after_topup))
-                .execute(conn)?;
-        }
+        let mut entry = JournalEntry::new(ledger::TOP_UP).for_transaction(tx_id);
+        entry
+            .post(Account::company(ledger::CLEARING, req_currency), -req_value.clone())
+            .credit(&req_value, &topup, Account::wallet(req_user_id, &user_balance.currency));
+        entry.record(conn)?;
+        book_spread_income(conn, tx_id, topup.spread_income)?;
+        // update balance
+        update_wallet(conn, &user_balance)?;
 
         // return new transaction id
-        return Ok(tx_id);
+        Ok(TopUpResult::Ok(tx_id))
+    })
+}
+
+// checks if the order item of the user has a final state (committed, cancelled or expired reservation),
+// without req_item_id any item of the order is checked
+fn order_finished(
+    conn: &mut PgConnection,
+    req_user_id: &str,
+    req_order_id: &str,
+    req_item_id: Option<&str>,
+) -> Result<bool, Error> {
+    use crate::schema::order_history::dsl::*;
+    let query = order_history
+        .filter(user_id.eq(req_user_id))
+        .filter(order_id.eq(req_order_id))
+        .into_boxed();
+    let query = match req_item_id {
+        Some(req_item_id) => query.filter(item_id.eq(req_item_id)),
+        None => query,
+    };
+    query.select(id).first::<i64>(conn).optional().map(|res| res.is_some())
+}
+
+// latest commit transaction of the order item paid by the user, without req_item_id of any item of the order
+fn last_order_transaction(
+    conn: &mut PgConnection,
+    req_user_id: &str,
+    req_order_id: &str,
+    req_item_id: Option<&str>,
+) -> Result<Option<models::Transaction>, Error> {
+    use crate::schema::transaction::dsl::*;
+    let query = transaction
+        .filter(order_data.retrieve_as_text("order_id").eq(req_order_id))
+        .filter(sender_id.eq(req_user_id))
+        .into_boxed();
+    let query = match req_item_id {
+        Some(req_item_id) => query.filter(order_data.retrieve_as_text("item_id").eq(req_item_id)),
+        None => query,
+    };
+    query.order(id.desc()).first::<models::Transaction>(conn).optional()
+}
+
+// saves final state of the reservation that has just been deleted
+fn record_order_state(
+    conn: &mut PgConnection,
+    reservation: &models::BalanceReserve,
+    req_state: &str,
+    req_released_value: Money,
+    req_captured_value: Money,
+) -> Result<usize, Error> {
+    use crate::schema::order_history::dsl::*;
+    let new_state = models::NewOrderHistory {
+        id: idgen::next(),
+        order_id: reservation.order_id.clone(),
+        user_id: reservation.user_id.clone(),
+        item_id: reservation.item_id.clone(),
+        state: req_state.to_string(),
+        currency: reservation.currency.clone(),
+        released_value: req_released_value.into_amount(),
+        captured_value: req_captured_value.into_amount(),
+        reserved_at: reservation.created_at,
+        finished_at: chrono::Utc::now().naive_utc(),
+    };
+    diesel::insert_into(order_history).values(&new_state).execute(conn)
+}
+
+// returns what the reservation holds to the wallet
+fn release_hold(
+    conn: &mut PgConnection,
+    operation: &'static str,
+    reservation: &models::BalanceReserve,
+) -> Result<i64, Error> {
+    let mut entry = JournalEntry::new(operation).for_order(&reservation.order_id, &reservation.item_id);
+    entry.transfer(
+        Account::hold(&reservation.user_id, &reservation.wallet_currency),
+        Account::wallet(&reservation.user_id, &reservation.wallet_currency),
+        &reservation.held(),
+    );
+    entry.record(conn)
+}
+
+// releases reservations that expired before req_now, at most req_limit at once.
+// reservations locked by running commits are skipped and left for the next run
+pub fn expire_reservations(conn: &mut PgConnection, req_now: NaiveDateTime, req_limit: i64) -> Result<usize, Error> {
+    conn.transaction(|conn| {
+        let expired: Vec<models::BalanceReserve> = {
+            use crate::schema::balance_reserve::dsl::*;
+            balance_reserve
+                .filter(expires_at.le(req_now))
+                .order(expires_at.asc())
+                .limit(req_limit)
+                .for_update()
+                .skip_locked()
+                .load(conn)?
+        };
+        for reservation in &expired {
+            {
+                use crate::schema::balance_reserve::dsl::*;
+                diesel::delete(balance_reserve.find((
+                    &reservation.user_id,
+                    &reservation.order_id,
+                    &reservation.item_id,
+                )))
+                .execute(conn)?;
+            }
+            record_order_state(
+                conn,
+                reservation,
+                models::ORDER_EXPIRED,
+                reservation.reserved(),
+                reservation.captured(),
+            )?;
+            release_hold(conn, ledger::EXPIRE, reservation)?;
+        }
+        Ok(expired.len())
     })
 }
 
@@ -107,48 +634,38 @@ pub enum ReserveResult {
     UserNotFound,
     InsufficientFunds,
     InvalidTransactionState,
+    // quote is unknown, expired or doesn't match the request
+    InvalidQuote,
+    // wallet is frozen or closed
+    AccountBlocked,
 }
 
+// reserves value of the order item in one of the user wallets, with req_quote_id the value is converted
+// at the locked rate of the quote into the wallet of its currency
+#[allow(clippy::too_many_arguments)]
 pub fn reserve(
     conn: &mut PgConnection,
     curr: &CurrencyConverter,
     req_user_id: &str,
-    req_currency: &str,
-    req_value: BigDecimal,
+    req_value: Money,
     req_order_id: &str,
     req_item_id: Option<&str>,
+    req_expires_at: Option<NaiveDateTime>,
+    req_quote_id: Option<i64>,
 ) -> Result<ReserveResult, Error> {
+    let req_currency = req_value.currency();
     // wrap in transaction
     conn.transaction::<_, Error, _>(|conn| {
-        // load user balance record and lock for update
-        let user_balance = {
-            use crate::schema::balance::dsl::*;
-            balance
-                .filter(user_id.eq(req_user_id))
-                .for_update()
-                .first::<models::Balance>(conn)
-                .optional()
-        };
-        let user_balance: models::Balance = match user_balance {
-            Ok(Some(user_balance)) => user_balance,
-            Ok(None) => return Ok(ReserveResult::UserNotFound),
-            Err(e) => return Err(e),
-        };
+        // same rates for every conversion of the operation
+        let rates = curr.snapshot();
+        // load user wallets and lock for update
+        let wallets = lock_wallets(conn, &[req_user_id])?;
+        if wallets.is_empty() {
+            return Ok(ReserveResult::UserNotFound);
+        }
 
-        // sum existing user's reservations
-        let user_reservations = {
-            use crate::schema::balance_reserve::dsl::*;
-            balance_reserve
-                .filter(user_id.eq(req_user_id))
-                .select(diesel::dsl::sum(user_currency_value))
-                .first::<Option<BigDecimal>>(conn)
-                .optional()
-        };
-        let user_reservations = match user_reservations {
-            Ok(Some(user_reservations)) => user_reservations.unwrap_or(BigDecimal::from(0)),
-            Ok(None) => BigDecimal::from(0),
-            Err(e) => return Err(e),
-        };
+        // sum existing user's reservations per wallet
+        let user_reservations = wallet_reservations(conn, req_user_id)?;
 
         // idempotency check (reservation)
         let existing_reservation = {
@@ -156,6 +673,7 @@ pub fn reserve(
             balance_reserve
                 .filter(user_id.eq(req_user_id))
                 .filter(order_id.eq(req_order_id))
+                .filter(item_id.eq(req_item_id.unwrap_or_default()))
                 .first::<models::BalanceReserve>(conn)
                 .optional()
         };
@@ -165,33 +683,80 @@ pub fn reserve(
             Ok(None) => {}
         };
         // idempotency check (transaction)
-        let existing_transaction = {
-            use crate::schema::transaction::dsl::*;
-            transaction
-                .filter(order_data.retrieve_as_text("order_id").eq(req_order_id))
-                .first::<models::Transaction>(conn)
-                .optional()
-        };
+        let existing_transaction = last_order_transaction(conn, req_user_id, req_order_id, req_item_id);
         match existing_transaction {
             Ok(Some(_)) => return Ok(ReserveResult::InvalidTransactionState), // already committed
             Err(e) => return Err(e),
             Ok(None) => {}
         };
+        // cancelled or expired order can't be reserved again
+        if order_finished(conn, req_user_id, req_order_id, req_item_id)? {
+            return Ok(ReserveResult::InvalidTransactionState);
+        }
+        // frozen and closed wallets can't be debited
+        if !wallets.iter().any(models::Balance::allows_debits) {
+            return Ok(ReserveResult::AccountBlocked);
+        }
+
+        let quote = match req_quote_id {
+            Some(req_quote_id) => match valid_quote(
+                conn,
+                req_quote_id,
+                req_user_id,
+                &req_value,
+                req_order_id,
+                req_item_id.unwrap_or_default(),
+            )? {
+                Some(quote) => Some(quote),
+                None => return Ok(ReserveResult::InvalidQuote),
+            },
+            None => None,
+        };
 
-        // convert value to user currency
-        let reserve_multiplier = if user_balance.currency == req_currency {
-            BigDecimal::from(1)
-        } else {
-            BigDecimal::from_f64(1.06).unwrap()
+        // convert value to wallet currency with spread and reserve margin of the pair
+        let fx_rules = wallet_fx_rules(conn, req_currency, &wallets)?;
+        let converted = wallet_values(&rates, &req_value, &wallets)?;
+        let reserve_in_wallet_currency = |wallet: &models::Balance| {
+            let rule = &fx_rules[&wallet.currency];
+            // locked rate needs no reserve for rate changes
+            if let Some(quote) = &quote {
+                return quote_fx_rule(rule, quote).reserve(&quoted_value(quote, &req_value));
+            }
+            rule.reserve(&converted[&wallet.currency])
         };
-        let reserve_in_user_currency =
-            curr.convert(req_currency, req_value.clone(), user_balance.currency.as_str()) * reserve_multiplier;
 
-        // check if user have enough funds
-        if user_balance.current_value.clone() - user_reservations.clone() < reserve_in_user_currency.clone() {
-            return Ok(ReserveResult::InsufficientFunds);
+        // pick a wallet with enough funds, quoted value can be reserved only in the wallet of the quote currency
+        let has_enough = |wallet: &models::Balance| {
+            fx_rules[&wallet.currency]
+                .allows_balance(&(available(wallet, &user_reservations) - reserve_in_wallet_currency(wallet)))
+        };
+        let wallet = match &quote {
+            Some(quote) => match wallets.iter().find(|wallet| wallet.currency == quote.to_currency) {
+                Some(wallet) if !wallet.allows_debits() => return Ok(ReserveResult::AccountBlocked),
+                Some(wallet) => Some(wallet).filter(|wallet| has_enough(wallet)),
+                None => return Ok(ReserveResult::InvalidQuote),
+            },
+            None => choose_wallet(&wallets, req_currency, |wallet| {
+                wallet.allows_debits() && has_enough(wallet)
+            }),
+        };
+        let wallet = match wallet {
+            Some(wallet) => wallet,
+            None => return Ok(ReserveResult::InsufficientFunds),
+        };
+        let reserve_in_user_currency = reserve_in_wallet_currency(wallet);
+        if let Some(quote) = &quote {
+            take_quote(conn, quote, req_order_id, req_item_id.unwrap_or_default())?;
         }
 
+        let mut entry = JournalEntry::new(ledger::RESERVE).for_order(req_order_id, req_item_id.unwrap_or_default());
+        entry.transfer(
+            Account::wallet(req_user_id, &wallet.currency),
+            Account::hold(req_user_id, &wallet.currency),
+            &reserve_in_user_currency,
+        );
+        entry.record(conn)?;
+
         // create reservation record
         {
             use crate::schema::balance_reserve::dsl::*;
@@ -201,8 +766,10 @@ pub fn reserve(
                 user_id: req_user_id.to_string(),
                 created_at: chrono::Utc::now().naive_utc(),
                 currency: req_currency.to_string(),
-                value: req_value,
-                user_currency_value: reserve_in_user_currency,
+                value: req_value.amount().clone(),
+                user_currency_value: reserve_in_user_currency.into_amount(),
+                expires_at: req_expires_at,
+                wallet_currency: wallet.currency.clone(),
             };
             diesel::insert_into(balance_reserve)
                 .values(&new_reserve)
@@ -213,137 +780,910 @@ pub fn reserve(
     })
 }
 
+#[derive(PartialEq, Debug)]
+#[allow(dead_code)]
 pub enum CommitResult {
     Ok(i64),
     UserNotFound,
     InsufficientFunds,
+    // capture is larger than what is left of the reservation or the order is already finished
+    InvalidTransactionState,
+    // order has several reserved items and the request doesn't tell which one to capture
+    ItemRequired,
+    // quote is unknown, expired or doesn't match the request or the wallet of the reservation
+    InvalidQuote,
+    // wallet is frozen or closed
+    AccountBlocked,
 }
 
+// captures reserved funds of the order item, possibly in several steps: with req_keep_reservation
+// the rest of the reservation stays held for further captures, otherwise it is released.
+// every capture is a separate transaction, repeated captures are detected by idempotency key
+// or, when there is no key, by any transaction of the order item when nothing is reserved anymore.
+// without req_item_id the only reserved item of the order is captured
+#[allow(clippy::too_many_arguments)]
 pub fn commit(
     conn: &mut PgConnection,
     curr: &CurrencyConverter,
     req_user_id: &str,
-    req_currency: &str,
-    req_value: BigDecimal,
+    req_value: Money,
     req_order_id: &str,
     req_item_id: Option<&str>,
+    req_idempotency_key: Option<&str>,
+    req_keep_reservation: bool,
+    req_quote_id: Option<i64>,
+    req_details: TransactionDetails,
 ) -> Result<CommitResult, Error> {
+    let req_currency = req_value.currency();
     conn.transaction(|conn| {
-        // load user balance and lock for update
-        let user_balance: Option<models::Balance> = {
-            use crate::schema::balance::dsl::*;
-            balance
-                .filter(user_id.eq(req_user_id))
-                .for_update()
-                .first::<models::Balance>(conn)
-                .optional()?
-        };
-        let user_balance = match user_balance {
-            Some(user_balance) => user_balance,
-            None => return Ok(CommitResult::UserNotFound),
+        // same rates for every conversion of the operation
+        let rates = curr.snapshot();
+        // load user wallets and lock for update
+        let wallets = lock_wallets(conn, &[req_user_id])?;
+        if wallets.is_empty() {
+            return Ok(CommitResult::UserNotFound);
+        }
+
+        // idempotency check (capture)
+        if let Some(req_idempotency_key) = req_idempotency_key {
+            let existing_transaction: Option<models::Transaction> = {
+                use crate::schema::transaction::dsl::*;
+                transaction
+                    .filter(idempotency_key.eq(req_idempotency_key))
+                    .first::<models::Transaction>(conn)
+                    .optional()?
+            };
+            if let Some(tx) = existing_transaction {
+                return Ok(CommitResult::Ok(tx.id)); // already captured
+            }
+        }
+
+        let mut reservations: Vec<models::BalanceReserve> = {
+            use crate::schema::balance_reserve::dsl::*;
+            // only reservations of the user are captured
+            let query = balance_reserve
+                .filter(order_id.eq(req_order_id))
+                .filter(user_id.eq(req_user_id));
+            match req_item_id {
+                Some(req_item_id) => query.filter(item_id.eq(req_item_id)).for_update().load(conn)?,
+                None => query.for_update().load(conn)?,
+            }
         };
+        if reservations.len() > 1 {
+            return Ok(CommitResult::ItemRequired);
+        }
+        let reservation = reservations.pop();
 
-        // idempotency check (transaction)
-        let existing_transaction: QueryResult<Option<models::Transaction>> = {
-            use crate::schema::transaction::dsl::*;
-            transaction
-                .filter(order_data.retrieve_as_text("order_id").eq(req_order_id))
-                .first::<models::Transaction>(conn)
-                .optional()
+        // nothing is reserved for the order item, it is either finished or charged without reservation
+        if reservation.is_none() {
+            let last_transaction = last_order_transaction(conn, req_user_id, req_order_id, req_item_id)?;
+            match (last_transaction, req_idempotency_key) {
+                (Some(tx), None) => return Ok(CommitResult::Ok(tx.id)), // already committed
+                (Some(_), Some(_)) => return Ok(CommitResult::InvalidTransactionState),
+                (None, _) if order_finished(conn, req_user_id, req_order_id, req_item_id)? => {
+                    return Ok(CommitResult::InvalidTransactionState)
+                }
+                (None, _) => {}
+            }
+        }
+
+        // frozen and closed wallets can't be debited
+        if !wallets.iter().any(models::Balance::allows_debits) {
+            return Ok(CommitResult::AccountBlocked);
+        }
+
+        // locked rate of the quote replaces the live one, the quote must convert into the wallet of the reservation
+        // and belong to the reserved item
+        let quote_item_id = reservation
+            .as_ref()
+            .map_or(req_item_id.unwrap_or_default(), |reservation| {
+                reservation.item_id.as_str()
+            });
+        let quote = match req_quote_id {
+            Some(req_quote_id) => {
+                match valid_quote(conn, req_quote_id, req_user_id, &req_value, req_order_id, quote_item_id)? {
+                    Some(quote)
+                        if wallets.iter().any(|wallet| wallet.currency == quote.to_currency)
+                            && reservation
+                                .iter()
+                                .all(|reservation| reservation.wallet_currency == quote.to_currency) =>
+                    {
+                        Some(quote)
+                    }
+                    _ => return Ok(CommitResult::InvalidQuote),
+                }
+            }
+            None => None,
         };
-        match existing_transaction {
-            Ok(Some(tx)) => return Ok(CommitResult::Ok(tx.id)), // already committed
-            Err(e) => return Err(e),
-            Ok(None) => {}
+
+        // capture must fit into what is left of the reservation
+        let capture_in_reservation_currency = match &reservation {
+            Some(reservation) => {
+                let capture_in_reservation_currency = if reservation.currency == req_currency {
+                    req_value.clone()
+                } else {
+                    rates.convert(&req_value, &reservation.currency)?
+                };
+                if capture_in_reservation_currency > reservation.reserved() {
+                    return Ok(CommitResult::InvalidTransactionState);
+                }
+                capture_in_reservation_currency
+            }
+            None => Money::zero(req_currency),
         };
 
-        // delete pre-existing reservation
-        let previously_reserved = {
-            use crate::schema::balance_reserve::dsl::*;
-            diesel::delete(balance_reserve.filter(order_id.eq(req_order_id))).execute(conn)?
-        } > 0;
+        // value is converted at the locked rate of the quote or the live one, spread of the pair is charged on top
+        let fx_rules = wallet_fx_rules(conn, req_currency, &wallets)?;
+        let converted = wallet_values(&rates, &req_value, &wallets)?;
+        let debit = |wallet: &models::Balance| {
+            let rule = &fx_rules[&wallet.currency];
+            match &quote {
+                Some(quote) => quote_fx_rule(rule, quote).debit(&quoted_value(quote, &req_value)),
+                None => rule.debit(&converted[&wallet.currency]),
+            }
+        };
+        // the wallet may go below zero only within its credit line and the overdraft of the pair, funds held for
+        // other orders can't be charged, the hold of the captured reservation can
+        let mut user_reservations = wallet_reservations(conn, req_user_id)?;
+        if let Some(reservation) = &reservation {
+            if let Some(reserved) = user_reservations.get_mut(&reservation.wallet_currency) {
+                *reserved = &*reserved - &reservation.held();
+            }
+        }
+        let has_enough = |wallet: &models::Balance| {
+            fx_rules[&wallet.currency].allows_balance(&(available(wallet, &user_reservations) - debit(wallet).value))
+        };
 
-        let commit_in_user_balance_currency =
-            curr.convert(req_currency, req_value.clone(), user_balance.currency.as_str());
+        // reserved funds are taken from the wallet of the reservation, quoted value from the wallet of the quote
+        // currency, otherwise a wallet is picked
+        let user_balance = match (&reservation, &quote) {
+            (Some(reservation), _) => wallets
+                .iter()
+                .find(|wallet| wallet.currency == reservation.wallet_currency),
+            (None, Some(quote)) => wallets.iter().find(|wallet| wallet.currency == quote.to_currency),
+            (None, None) => choose_wallet(&wallets, req_currency, |wallet| {
+                wallet.allows_debits() && has_enough(wallet)
+            }),
+        };
+        if user_balance.is_some_and(|wallet| !wallet.allows_debits()) {
+            return Ok(CommitResult::AccountBlocked);
+        }
+        let user_balance = match user_balance.filter(|wallet| has_enough(wallet)) {
+            Some(user_balance) => user_balance,
+            None => return Ok(CommitResult::InsufficientFunds),
+        };
 
-        let balance_new_value = user_balance.current_value.clone() - commit_in_user_balance_currency.clone();
+        if let Some(quote) = &quote {
+            take_quote(conn, quote, req_order_id, quote_item_id)?;
+        }
+        let commit = debit(user_balance);
+        let balance_new_value = &user_balance.value() - &commit.value;
+        let mut entry = JournalEntry::new(ledger::COMMIT);
 
-        if balance_new_value.is_negative() && (!previously_reserved || req_currency == user_balance.currency) {
-            return Ok(CommitResult::InsufficientFunds);
+        // capture part of the reservation or release it completely
+        if let Some(reservation) = &reservation {
+            use crate::schema::balance_reserve::dsl::*;
+            let reservation_line = balance_reserve.find((req_user_id, req_order_id, &reservation.item_id));
+            if req_keep_reservation && capture_in_reservation_currency < reservation.reserved() {
+                // hold is reduced proportionally to the captured part
+                let released =
+                    (&reservation.held() * &(capture_in_reservation_currency.amount() / &reservation.value)).round();
+                diesel::update(reservation_line)
+                    .set((
+                        value.eq((&reservation.reserved() - &capture_in_reservation_currency).into_amount()),
+                        user_currency_value.eq((&reservation.held() - &released).into_amount()),
+                        captured_value.eq((&reservation.captured() + &capture_in_reservation_currency).into_amount()),
+                    ))
+                    .execute(conn)?;
+                entry.transfer(
+                    Account::hold(req_user_id, &user_balance.currency),
+                    Account::wallet(req_user_id, &user_balance.currency),
+                    &released,
+                );
+            } else {
+                diesel::delete(reservation_line).execute(conn)?;
+                entry.transfer(
+                    Account::hold(req_user_id, &user_balance.currency),
+                    Account::wallet(req_user_id, &user_balance.currency),
+                    &reservation.held(),
+                );
+                record_order_state(
+                    conn,
+                    reservation,
+                    models::ORDER_COMMITTED,
+                    &reservation.reserved() - &capture_in_reservation_currency,
+                    &reservation.captured() + &capture_in_reservation_currency,
+                )?;
+            }
         }
 
         let tx_id = idgen::next();
-        let req_order_data = serde_json::json!({"order_id": req_order_id,"item_id": req_item_id,});
+        // item of the captured reservation is credited in the revenue report
+        let tx_item_id = reservation
+            .as_ref()
+            .map(|reservation| reservation.item_id.as_str())
+            .filter(|reserved_item_id| !reserved_item_id.is_empty())
+            .or(req_item_id);
+        let req_order_data = serde_json::json!({"order_id": req_order_id,"item_id": tx_item_id,});
+        let tx_description = match tx_item_id {
+            Some(tx_item_id) => {
+                format!("Payment of {req_value} {req_currency} for order {req_order_id}, item {tx_item_id}")
+            }
+            None => format!("Payment of {req_value} {req_currency} for order {req_order_id}"),
+        };
         {
             // insert commit transaction record
             use crate::schema::transaction::dsl::*;
             let new_tx = models::NewCommitTransaction {
                 id: tx_id,
                 transaction_currency: req_currency.to_string(),
-                transaction_value: req_value,
+                transaction_value: req_value.amount().clone(),
                 sender_id: Some(req_user_id.to_string()),
                 sender_currency: Some(user_balance.currency.clone()),
-                sender_value: Some(commit_in_user_balance_currency),
-                sender_balance_before: Some(user_balance.current_value),
-                sender_balance_after: Some(balance_new_value.clone()),
+                sender_value: Some(commit.value.amount().clone()),
+                sender_balance_before: Some(user_balance.current_value.clone()),
+                sender_balance_after: Some(balance_new_value.amount().clone()),
                 order_data: Some(req_order_data),
+                idempotency_key: req_idempotency_key.map(str::to_string),
+                rate_snapshot_id: converted_with(
+                    quote.as_ref().map_or(rates.snapshot_id, |quote| quote.rate_snapshot_id),
+                    req_currency,
+                    &[&user_balance.currency],
+                ),
+                details: req_details.or_default(tx_description, models::CATEGORY_PURCHASE, None),
             };
             diesel::insert_into(transaction).values(&new_tx).execute(conn)?;
         }
+        // order value is the revenue of the company
+        entry
+            .debit(
+                Account::wallet(req_user_id, &user_balance.currency),
+                &commit,
+                &req_value,
+            )
+            .post(Account::company(ledger::REVENUE, req_currency), req_value.clone());
+        entry.for_transaction(tx_id).record(conn)?;
+        book_spread_income(conn, tx_id, commit.spread_income)?;
         // save new balance value
-        {
-            use crate::schema::balance::dsl::*;
-            diesel::update(balance.filter(user_id.eq(req_user_id)))
-                .set(current_value.eq(balance_new_value))
-                .execute(conn)?;
-        }
+        update_wallet(conn, user_balance)?;
 
         Ok(CommitResult::Ok(tx_id))
     })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::database::queries;
-    use crate::database::queries::{UserBalance, UserBalanceValues};
-    use crate::{currency, database};
-    use bigdecimal::BigDecimal;
-    use diesel::result::Error;
-    use diesel::Connection;
-    use std::str::FromStr;
+#[derive(PartialEq, Debug)]
+pub enum CancelResult {
+    Ok,
+    UserNotFound,
+    InvalidTransactionState,
+}
 
-    #[actix_web::test]
-    async fn test_top_up() {
-        dotenvy::dotenv().ok();
+// releases reserved funds of the order item or of all items of the order, does nothing if there is no reservation
+pub fn cancel(
+    conn: &mut PgConnection,
+    req_user_id: &str,
+    req_order_id: &str,
+    req_item_id: Option<&str>,
+) -> Result<CancelResult, Error> {
+    conn.transaction(|conn| {
+        // load user wallets and lock for update
+        if lock_wallets(conn, &[req_user_id])?.is_empty() {
+            return Ok(CancelResult::UserNotFound);
+        }
 
-        let conn = database::connect::create_db_connection_pool();
+        // delete reservation (if any), after partial captures this releases the remainder
+        let released: Vec<models::BalanceReserve> = {
+            use crate::schema::balance_reserve::dsl::*;
+            let query = diesel::delete(balance_reserve)
+                .filter(user_id.eq(req_user_id))
+                .filter(order_id.eq(req_order_id))
+                .into_boxed();
+            let query = match req_item_id {
+                Some(req_item_id) => query.filter(item_id.eq(req_item_id)),
+                None => query,
+            };
+            query.get_results(conn)?
+        };
+        for reservation in &released {
+            record_order_state(
+                conn,
+                reservation,
+                models::ORDER_CANCELLED,
+                reservation.reserved(),
+                reservation.captured(),
+            )?;
+            release_hold(conn, ledger::CANCEL, reservation)?;
+        }
 
-        let curr = currency::create_currency_converter().await;
+        // committed order can't be cancelled
+        if released.is_empty() {
+            let existing_transaction = last_order_transaction(conn, req_user_id, req_order_id, req_item_id)?;
+            if existing_transaction.is_some() {
+                return Ok(CancelResult::InvalidTransactionState);
+            }
+        }
 
-        let user_id = "test_user";
-        let currency = "USD";
-        let value = BigDecimal::from_str("100").unwrap();
-        let idempotency_key = "test";
+        Ok(CancelResult::Ok)
+    })
+}
 
-        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
-            let tx_id = top_up(conn, &curr, idempotency_key, user_id, currency, value.clone(), None)?;
-            assert!(tx_id > 0);
+#[derive(PartialEq, Debug)]
+pub enum CreditLimitResult {
+    Ok,
+    UserNotFound,
+}
 
-            let balance = queries::load_balance(conn, user_id)?;
-            assert_eq!(
-                balance,
-                UserBalance::Ok(UserBalanceValues {
-                    currency: currency.to_string(),
-                    balance: value.clone(),
-                    reserved: Default::default()
-                })
+// sets credit line of the user wallet in the currency of the limit, the wallet is created if the user
+// has none in this currency, zero limit closes the credit line
+pub fn set_credit_limit(
+    conn: &mut PgConnection,
+    req_user_id: &str,
+    req_credit_limit: Money,
+) -> Result<CreditLimitResult, Error> {
+    let req_currency = req_credit_limit.currency().to_string();
+    conn.transaction(|conn| {
+        // load user wallets and lock for update
+        if lock_wallets(conn, &[req_user_id])?.is_empty() {
+            return Ok(CreditLimitResult::UserNotFound);
+        }
+        init_user_balance(conn, &req_currency, req_user_id)?;
+
+        use crate::schema::balance::dsl::*;
+        diesel::update(balance.find((req_user_id, &req_currency)))
+            .set(credit_limit.eq(req_credit_limit.into_amount()))
+            .execute(conn)?;
+
+        Ok(CreditLimitResult::Ok)
+    })
+}
+
+#[derive(PartialEq, Debug)]
+pub enum TransferResult {
+    Ok(i64),
+    UserNotFound,
+    InsufficientFunds,
+    // wallet of the sender is frozen or closed, or the one of the recipient can't be credited
+    AccountBlocked,
+}
+
+// writes transaction of the value moved between wallets of two users, its journal entry and spread income,
+// updates both wallets
+#[allow(clippy::too_many_arguments)]
+fn record_transfer(
+    conn: &mut PgConnection,
+    operation: &'static str,
+    req_idempotency_key: Option<&str>,
+    rates: &Rates,
+    req_value: &Money,
+    sender_balance: &models::Balance,
+    sender_debit: Conversion,
+    recipient_balance: &models::Balance,
+    recipient_credit: Conversion,
+    details: models::NewTransactionDetails,
+) -> Result<i64, Error> {
+    let sender_balance_after_transfer = &sender_balance.value() - &sender_debit.value;
+    let recipient_balance_after_transfer = &recipient_balance.value() + &recipient_credit.value;
+
+    let tx_id = idgen::next();
+    {
+        // create transaction record
+        use crate::schema::transaction::dsl::*;
+        let new_tx = models::NewTransferTransaction {
+            id: tx_id,
+            transaction_currency: req_value.currency().to_string(),
+            transaction_value: req_value.amount().clone(),
+            sender_id: Some(sender_balance.user_id.clone()),
+            sender_currency: Some(sender_balance.currency.clone()),
+            sender_value: Some(sender_debit.value.amount().clone()),
+            sender_balance_before: Some(sender_balance.current_value.clone()),
+            sender_balance_after: Some(sender_balance_after_transfer.amount().clone()),
+            recipient_id: Some(recipient_balance.user_id.clone()),
+            recipient_currency: Some(recipient_balance.currency.clone()),
+            recipient_value: Some(recipient_credit.value.amount().clone()),
+            recipient_balance_before: Some(recipient_balance.current_value.clone()),
+            recipient_balance_after: Some(recipient_balance_after_transfer.amount().clone()),
+            created_at: chrono::Utc::now().naive_utc(),
+            idempotency_key: req_idempotency_key.map(str::to_string),
+            rate_snapshot_id: converted_with(
+                rates.snapshot_id,
+                req_value.currency(),
+                &[&sender_balance.currency, &recipient_balance.currency],
+            ),
+            details,
+        };
+        diesel::insert_into(transaction).values(&new_tx).execute(conn)?;
+    }
+    let mut entry = JournalEntry::new(operation).for_transaction(tx_id);
+    entry
+        .debit(
+            Account::wallet(&sender_balance.user_id, &sender_balance.currency),
+            &sender_debit,
+            req_value,
+        )
+        .credit(
+            req_value,
+            &recipient_credit,
+            Account::wallet(&recipient_balance.user_id, &recipient_balance.currency),
+        );
+    entry.record(conn)?;
+    book_spread_income(conn, tx_id, sender_debit.spread_income)?;
+    book_spread_income(conn, tx_id, recipient_credit.spread_income)?;
+    // update both balances
+    update_wallet(conn, sender_balance)?;
+    update_wallet(conn, recipient_balance)?;
+
+    Ok(tx_id)
+}
+
+// moves value from sender to recipient balance, both balances must exist
+pub fn transfer(
+    conn: &mut PgConnection,
+    curr: &CurrencyConverter,
+    req_idempotency_key: &str,
+    req_sender_id: &str,
+    req_recipient_id: &str,
+    req_value: Money,
+    req_details: TransactionDetails,
+) -> Result<TransferResult, Error> {
+    let req_currency = req_value.currency();
+    conn.transaction(|conn| {
+        // same rates for every conversion of the operation
+        let rates = curr.snapshot();
+        // load wallets of both users and lock them for update
+        let (sender_wallets, recipient_wallets): (Vec<_>, Vec<_>) =
+            lock_wallets(conn, &[req_sender_id, req_recipient_id])?
+                .into_iter()
+                .partition(|wallet| wallet.user_id == req_sender_id);
+        if sender_wallets.is_empty() || recipient_wallets.is_empty() {
+            return Ok(TransferResult::UserNotFound);
+        }
+
+        // idempotency check
+        let existing_transaction: Option<models::Transaction> = {
+            use crate::schema::transaction::dsl::*;
+            transaction
+                .filter(idempotency_key.eq(req_idempotency_key))
+                .first::<models::Transaction>(conn)
+                .optional()?
+        };
+        if let Some(existing_transaction) = existing_transaction {
+            return Ok(TransferResult::Ok(existing_transaction.id));
+        }
+
+        // frozen and closed wallets can't be debited
+        if !sender_wallets.iter().any(models::Balance::allows_debits) {
+            return Ok(TransferResult::AccountBlocked);
+        }
+
+        // sum existing sender's reservations per wallet
+        let sender_reservations = wallet_reservations(conn, req_sender_id)?;
+
+        // sender pays the spread of the pair on top, recipient gets converted value less the spread
+        let sender_fx_rules = wallet_fx_rules(conn, req_currency, &sender_wallets)?;
+        let sender_values = wallet_values(&rates, &req_value, &sender_wallets)?;
+        let debit =
+            |wallet: &models::Balance| sender_fx_rules[&wallet.currency].debit(&sender_values[&wallet.currency]);
+
+        // reserved funds can't be transferred
+        let sender_balance = choose_wallet(&sender_wallets, req_currency, |wallet| {
+            wallet.allows_debits()
+                && sender_fx_rules[&wallet.currency]
+                    .allows_balance(&(available(wallet, &sender_reservations) - debit(wallet).value))
+        });
+        let sender_balance = match sender_balance {
+            Some(sender_balance) => sender_balance,
+            None => return Ok(TransferResult::InsufficientFunds),
+        };
+        // recipient gets the money into the wallet of the same currency or into the primary one
+        let recipient_balance = recipient_wallets
+            .iter()
+            .find(|wallet| wallet.currency == req_currency)
+            .unwrap_or(&recipient_wallets[0]);
+        if !recipient_balance.allows_credits() {
+            return Ok(TransferResult::AccountBlocked);
+        }
+
+        // convert value to both balance currencies
+        let sender_debit = debit(sender_balance);
+        let recipient_credit = queries::load_fx_rule(conn, req_currency, &recipient_balance.currency)?
+            .credit(&rates.convert(&req_value, &recipient_balance.currency)?);
+
+        let tx_id = record_transfer(
+            conn,
+            ledger::TRANSFER,
+            Some(req_idempotency_key),
+            &rates,
+            &req_value,
+            sender_balance,
+            sender_debit,
+            recipient_balance,
+            recipient_credit,
+            req_details.or_default(
+                format!("Transfer of {req_value} {req_currency} from {req_sender_id} to {req_recipient_id}"),
+                models::CATEGORY_TRANSFER,
+                None,
+            ),
+        )?;
+        Ok(TransferResult::Ok(tx_id))
+    })
+}
+
+#[derive(PartialEq, Debug)]
+pub enum OpenAccountResult {
+    Ok,
+    // user already has a wallet in the currency
+    AlreadyExists,
+}
+
+// opens user wallet in the currency, the first wallet of the user is the primary one
+pub fn open_account(
+    conn: &mut PgConnection,
+    req_user_id: &str,
+    req_currency: &str,
+) -> Result<OpenAccountResult, Error> {
+    conn.transaction(|conn| {
+        if !init_user_balance(conn, req_currency, req_user_id)? {
+            return Ok(OpenAccountResult::AlreadyExists);
+        }
+        log_status_change(
+            conn,
+            req_user_id,
+            req_currency,
+            None,
+            models::ACCOUNT_ACTIVE,
+            "opened",
+            None,
+        )?;
+        Ok(OpenAccountResult::Ok)
+    })
+}
+
+#[derive(PartialEq, Debug)]
+pub enum AccountStatusResult {
+    Ok,
+    UserNotFound,
+    // closed wallet can't be changed, wallet to close has reservations, a negative balance
+    // or funds and no payout user
+    InvalidTransactionState,
+    // payout wallet can't be credited
+    AccountBlocked,
+}
+
+// changes status of the user wallet and logs it with the reason. only an empty wallet without reservations
+// can be closed, with req_payout_user_id its remaining funds are transferred to the wallet of the payout user
+// in the same currency or to the primary one
+#[allow(clippy::too_many_arguments)]
+pub fn set_account_status(
+    conn: &mut PgConnection,
+    curr: &CurrencyConverter,
+    req_user_id: &str,
+    req_currency: &str,
+    req_status: &str,
+    req_reason: &str,
+    req_payout_user_id: Option<&str>,
+    req_details: TransactionDetails,
+) -> Result<AccountStatusResult, Error> {
+    conn.transaction(|conn| {
+        // same rates for every conversion of the operation
+        let rates = curr.snapshot();
+        // load wallets of the user and the payout user and lock them for update
+        let user_ids: Vec<&str> = std::iter::once(req_user_id).chain(req_payout_user_id).collect();
+        let wallets = lock_wallets(conn, &user_ids)?;
+        let is_closed_wallet =
+            |wallet: &&models::Balance| wallet.user_id == req_user_id && wallet.currency == req_currency;
+        let wallet = match wallets.iter().find(is_closed_wallet) {
+            Some(wallet) => wallet,
+            None => return Ok(AccountStatusResult::UserNotFound),
+        };
+        if wallet.status == models::ACCOUNT_CLOSED {
+            return Ok(AccountStatusResult::InvalidTransactionState);
+        }
+        if wallet.status == req_status {
+            return Ok(AccountStatusResult::Ok);
+        }
+
+        let mut payout_transaction_id = None;
+        if req_status == models::ACCOUNT_CLOSED {
+            if wallet_reservations(conn, req_user_id)?.contains_key(req_currency) {
+                return Ok(AccountStatusResult::InvalidTransactionState);
+            }
+            let remainder = wallet.value();
+            if remainder.is_negative() || (!remainder.amount().is_zero() && req_payout_user_id.is_none()) {
+                return Ok(AccountStatusResult::InvalidTransactionState);
+            }
+            if !remainder.amount().is_zero() {
+                // the payout user may be the owner of the wallet, then one of the other wallets gets the funds
+                let payout_wallets: Vec<&models::Balance> = wallets
+                    .iter()
+                    .filter(|wallet| Some(wallet.user_id.as_str()) == req_payout_user_id)
+                    .filter(|wallet| !is_closed_wallet(wallet))
+                    .collect();
+                let payout_wallet = match payout_wallets
+                    .iter()
+                    .find(|wallet| wallet.currency == req_currency)
+                    .or(payout_wallets.first())
+                {
+                    Some(payout_wallet) => *payout_wallet,
+                    None => return Ok(AccountStatusResult::UserNotFound),
+                };
+                if !payout_wallet.allows_credits() {
+                    return Ok(AccountStatusResult::AccountBlocked);
+                }
+                // the whole remainder leaves the wallet, the payout wallet gets it less the spread of the pair
+                let payout_credit = queries::load_fx_rule(conn, req_currency, &payout_wallet.currency)?
+                    .credit(&rates.convert(&remainder, &payout_wallet.currency)?);
+                let payout_debit = Conversion {
+                    value: remainder.clone(),
+                    spread_income: Money::zero(req_currency),
+                };
+                payout_transaction_id = Some(record_transfer(
+                    conn,
+                    ledger::PAYOUT,
+                    None,
+                    &rates,
+                    &remainder,
+                    wallet,
+                    payout_debit,
+                    payout_wallet,
+                    payout_credit,
+                    req_details.or_default(
+                        format!("Payout of closed {req_currency} wallet: {req_reason}"),
+                        models::CATEGORY_ADJUSTMENT,
+                        None,
+                    ),
+                )?);
+            }
+        }
+
+        {
+            use crate::schema::balance::dsl::*;
+            diesel::update(balance.find((req_user_id, req_currency)))
+                .set(status.eq(req_status))
+                .execute(conn)?;
+        }
+        log_status_change(
+            conn,
+            req_user_id,
+            req_currency,
+            Some(&wallet.status),
+            req_status,
+            req_reason,
+            payout_transaction_id,
+        )?;
+        Ok(AccountStatusResult::Ok)
+    })
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ChangeCurrencyResult {
+    Ok(i64),
+    UserNotFound,
+    // user already has a wallet in the new currency that is not closed
+    InvalidTransactionState,
+    // wallet is frozen or closed
+    AccountBlocked,
+}
+
+// moves the user wallet into another currency at the live rate without spread: the balance, the credit line
+// and held values of open reservations are converted, the old wallet is closed and the new one takes its place.
+// closed wallet in the new currency is reopened, so the user can change back to the old currency.
+// returns the conversion transaction from the old wallet to the new one
+pub fn change_currency(
+    conn: &mut PgConnection,
+    curr: &CurrencyConverter,
+    req_user_id: &str,
+    req_currency: &str,
+    req_to_currency: &str,
+    req_reason: &str,
+    req_details: TransactionDetails,
+) -> Result<ChangeCurrencyResult, Error> {
+    conn.transaction(|conn| {
+        // same rates for every conversion of the operation
+        let rates = curr.snapshot();
+        // load user wallets and lock for update
+        let wallets = lock_wallets(conn, &[req_user_id])?;
+        let wallet = match wallets.iter().find(|wallet| wallet.currency == req_currency) {
+            Some(wallet) => wallet,
+            None => return Ok(ChangeCurrencyResult::UserNotFound),
+        };
+        if !wallet.allows_debits() {
+            return Ok(ChangeCurrencyResult::AccountBlocked);
+        }
+        // closed wallet is empty and has no reservations, it is reopened in place of the old one
+        let closed_wallet = match wallets.iter().find(|wallet| wallet.currency == req_to_currency) {
+            Some(wallet) if wallet.status != models::ACCOUNT_CLOSED => {
+                return Ok(ChangeCurrencyResult::InvalidTransactionState)
+            }
+            found => found,
+        };
+
+        // new wallet keeps the creation time, so it stays the primary one
+        let value = wallet.value();
+        let converted = rates.convert(&value, req_to_currency)?;
+        let new_wallet = {
+            use crate::schema::balance::dsl::*;
+            diesel::update(balance.find((req_user_id, req_currency)))
+                .set(status.eq(models::ACCOUNT_CLOSED))
+                .execute(conn)?;
+            let new_credit_limit = rates.convert(&wallet.credit_limit(), req_to_currency)?.into_amount();
+            match closed_wallet {
+                Some(_) => diesel::update(balance.find((req_user_id, req_to_currency)))
+                    .set((
+                        created_at.eq(wallet.created_at),
+                        credit_limit.eq(new_credit_limit),
+                        negative_since.eq(wallet.negative_since),
+                        status.eq(&wallet.status),
+                    ))
+                    .get_result::<models::Balance>(conn)?,
+                None => diesel::insert_into(balance)
+                    .values((
+                        user_id.eq(req_user_id),
+                        currency.eq(req_to_currency),
+                        current_value.eq(BigDecimal::from(0)),
+                        created_at.eq(wallet.created_at),
+                        credit_limit.eq(new_credit_limit),
+                        negative_since.eq(wallet.negative_since),
+                        status.eq(&wallet.status),
+                    ))
+                    .get_result::<models::Balance>(conn)?,
+            }
+        };
+
+        // reservations stay in their order currency, only the held value moves to the new wallet
+        let reservations: Vec<models::BalanceReserve> = {
+            use crate::schema::balance_reserve::dsl::*;
+            balance_reserve
+                .filter(user_id.eq(req_user_id))
+                .filter(wallet_currency.eq(req_currency))
+                .for_update()
+                .load(conn)?
+        };
+        let mut held = Money::zero(req_currency);
+        let mut converted_held = Money::zero(req_to_currency);
+        for reservation in &reservations {
+            let converted_reservation = rates.convert(&reservation.held(), req_to_currency)?;
+            held += &reservation.held();
+            converted_held += &converted_reservation;
+            use crate::schema::balance_reserve::dsl::*;
+            diesel::update(balance_reserve.find((req_user_id, &reservation.order_id, &reservation.item_id)))
+                .set((
+                    user_currency_value.eq(converted_reservation.into_amount()),
+                    wallet_currency.eq(req_to_currency),
+                ))
+                .execute(conn)?;
+        }
+
+        let tx_id = idgen::next();
+        {
+            // both sides of the conversion are in one transaction record
+            use crate::schema::transaction::dsl::*;
+            let new_tx = models::NewConversionTransaction {
+                id: tx_id,
+                transaction_currency: req_currency.to_string(),
+                transaction_value: value.amount().clone(),
+                sender_id: Some(req_user_id.to_string()),
+                sender_currency: Some(req_currency.to_string()),
+                sender_value: Some(value.amount().clone()),
+                sender_balance_before: Some(value.amount().clone()),
+                sender_balance_after: Some(BigDecimal::from(0)),
+                recipient_id: Some(req_user_id.to_string()),
+                recipient_currency: Some(req_to_currency.to_string()),
+                recipient_value: Some(converted.amount().clone()),
+                recipient_balance_before: Some(BigDecimal::from(0)),
+                recipient_balance_after: Some(converted.amount().clone()),
+                created_at: chrono::Utc::now().naive_utc(),
+                rate_snapshot_id: Some(rates.snapshot_id),
+                rate: Some(
+                    rates
+                        .rate(req_currency, req_to_currency)?
+                        .with_scale(11)
+                        .round(10)
+                        .normalized(),
+                ),
+                details: req_details.or_default(
+                    format!("Wallet currency change from {req_currency} to {req_to_currency}: {req_reason}"),
+                    models::CATEGORY_ADJUSTMENT,
+                    None,
+                ),
+            };
+            diesel::insert_into(transaction).values(&new_tx).execute(conn)?;
+        }
+        // hold is released into the old wallet, the whole balance is exchanged and held again in the new one
+        let mut entry = JournalEntry::new(ledger::CONVERT).for_transaction(tx_id);
+        entry
+            .transfer(
+                Account::hold(req_user_id, req_currency),
+                Account::wallet(req_user_id, req_currency),
+                &held,
+            )
+            .post(Account::wallet(req_user_id, req_currency), -value.clone())
+            .exchange(&value, &converted)
+            .post(Account::wallet(req_user_id, req_to_currency), converted.clone())
+            .transfer(
+                Account::wallet(req_user_id, req_to_currency),
+                Account::hold(req_user_id, req_to_currency),
+                &converted_held,
+            );
+        entry.record(conn)?;
+        update_wallet(conn, wallet)?;
+        update_wallet(conn, &new_wallet)?;
+
+        log_status_change(
+            conn,
+            req_user_id,
+            req_currency,
+            Some(&wallet.status),
+            models::ACCOUNT_CLOSED,
+            req_reason,
+            Some(tx_id),
+        )?;
+        log_status_change(
+            conn,
+            req_user_id,
+            req_to_currency,
+            closed_wallet.map(|closed_wallet| closed_wallet.status.as_str()),
+            &new_wallet.status,
+            req_reason,
+            Some(tx_id),
+        )?;
+        Ok(ChangeCurrencyResult::Ok(tx_id))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::queries;
+    use crate::money::Money;
+    use crate::{currency, database};
+    use bigdecimal::BigDecimal;
+    use diesel::result::Error;
+    use diesel::Connection;
+    use std::str::FromStr;
+
+    #[actix_web::test]
+    async fn test_top_up() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_user";
+        let currency = "USD";
+        let value = BigDecimal::from_str("100").unwrap();
+        let idempotency_key = "test";
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            let tx_id = top_up(
+                conn,
+                &curr,
+                idempotency_key,
+                user_id,
+                Money::new(value.clone(), currency),
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(tx_id, TopUpResult::Ok(id) if id > 0));
+
+            let balance = queries::load_balance(conn, &curr, user_id)?;
+            assert_eq!(
+                balance,
+                queries::tests::single_wallet(currency, value.clone(), Default::default())
             );
 
-            let tx_id2 = top_up(conn, &curr, idempotency_key, user_id, currency, value.clone(), None)?;
+            let tx_id2 = top_up(
+                conn,
+                &curr,
+                idempotency_key,
+                user_id,
+                Money::new(value.clone(), currency),
+                None,
+                Default::default(),
+            )?;
             assert_eq!(tx_id, tx_id2);
 
-            let balance2 = queries::load_balance(conn, user_id)?;
+            let balance2 = queries::load_balance(conn, &curr, user_id)?;
             assert_eq!(balance2, balance);
 
             Ok(())
@@ -364,31 +1704,1969 @@ mod tests {
         let order_id = "test_order";
 
         conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
-            let tx_id = top_up(conn, &curr, "id1", user_id, currency, value.clone(), None)?;
-            assert!(tx_id > 0);
+            let tx_id = top_up(
+                conn,
+                &curr,
+                "id1",
+                user_id,
+                Money::new(value.clone(), currency),
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(tx_id, TopUpResult::Ok(id) if id > 0));
 
-            let balance = queries::load_balance(conn, user_id)?;
+            let balance = queries::load_balance(conn, &curr, user_id)?;
             assert_eq!(
                 balance,
-                UserBalance::Ok(UserBalanceValues {
-                    currency: currency.to_string(),
-                    balance: value.clone(),
-                    reserved: Default::default()
-                })
+                queries::tests::single_wallet(currency, value.clone(), Default::default())
             );
 
-            let res = reserve(conn, &curr, user_id, currency, value.clone(), order_id, None)?;
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                Money::new(value.clone(), currency),
+                order_id,
+                None,
+                None,
+                None,
+            )?;
             assert_eq!(res, ReserveResult::Ok);
 
-            let balance2 = queries::load_balance(conn, user_id)?;
+            let balance2 = queries::load_balance(conn, &curr, user_id)?;
             assert_eq!(
                 balance2,
-                UserBalance::Ok(UserBalanceValues {
-                    currency: currency.to_string(),
-                    balance: BigDecimal::from_str("0").unwrap(),
-                    reserved: value.clone()
-                })
+                queries::tests::single_wallet(currency, BigDecimal::from_str("0").unwrap(), value.clone())
+            );
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_cancel() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_cancel";
+        let currency = "USD";
+        let value = BigDecimal::from_str("100").unwrap();
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            top_up(
+                conn,
+                &curr,
+                "test_cancel",
+                user_id,
+                Money::new(value.clone(), currency),
+                None,
+                Default::default(),
+            )?;
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                Money::new(value.clone(), currency),
+                "test_cancel_1",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+
+            let res = cancel(conn, user_id, "test_cancel_1", None)?;
+            assert_eq!(res, CancelResult::Ok);
+
+            let balance = queries::load_balance(conn, &curr, user_id)?;
+            assert_eq!(
+                balance,
+                queries::tests::single_wallet(currency, value.clone(), BigDecimal::from(0))
+            );
+
+            // repeated cancel is a no-op
+            let res = cancel(conn, user_id, "test_cancel_1", None)?;
+            assert_eq!(res, CancelResult::Ok);
+            assert_eq!(queries::load_balance(conn, &curr, user_id)?, balance);
+
+            // committed order can't be cancelled
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                Money::new(value.clone(), currency),
+                "test_cancel_2",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            let res = commit(
+                conn,
+                &curr,
+                user_id,
+                Money::new(value.clone(), currency),
+                "test_cancel_2",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, CommitResult::Ok(_)));
+            let res = cancel(conn, user_id, "test_cancel_2", None)?;
+            assert_eq!(res, CancelResult::InvalidTransactionState);
+
+            assert_eq!(
+                cancel(conn, "test_cancel_unknown", "test_cancel_2", None)?,
+                CancelResult::UserNotFound
+            );
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_partial_capture() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_partial_capture";
+        let order_id = "test_partial_capture_1";
+        let currency = "USD";
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            top_up(
+                conn,
+                &curr,
+                user_id,
+                user_id,
+                Money::new(BigDecimal::from(100), currency),
+                None,
+                Default::default(),
+            )?;
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(50), currency),
+                order_id,
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+
+            // first capture keeps the rest reserved
+            let capture = |conn: &mut PgConnection, key: &str, value: i32, keep: bool| {
+                commit(
+                    conn,
+                    &curr,
+                    user_id,
+                    Money::new(BigDecimal::from(value), currency),
+                    order_id,
+                    None,
+                    Some(key),
+                    keep,
+                    None,
+                    Default::default(),
+                )
+            };
+            let first = capture(conn, "test_partial_capture_a", 20, true)?;
+            assert!(matches!(first, CommitResult::Ok(_)));
+            let balance = queries::tests::single_wallet(currency, BigDecimal::from(50), BigDecimal::from(30));
+            assert_eq!(queries::load_balance(conn, &curr, user_id)?, balance);
+
+            // repeated capture is detected by idempotency key
+            assert_eq!(capture(conn, "test_partial_capture_a", 20, true)?, first);
+            assert_eq!(queries::load_balance(conn, &curr, user_id)?, balance);
+
+            // capture can't exceed what is still reserved
+            assert_eq!(
+                capture(conn, "test_partial_capture_b", 31, true)?,
+                CommitResult::InvalidTransactionState
+            );
+
+            let second = capture(conn, "test_partial_capture_b", 10, true)?;
+            assert!(matches!(second, CommitResult::Ok(_)));
+            assert_ne!(second, first);
+
+            // release the remainder
+            assert_eq!(cancel(conn, user_id, order_id, None)?, CancelResult::Ok);
+            assert_eq!(
+                queries::load_balance(conn, &curr, user_id)?,
+                queries::tests::single_wallet(currency, BigDecimal::from(70), BigDecimal::from(0))
+            );
+
+            // finished order can't be captured anymore
+            assert_eq!(
+                capture(conn, "test_partial_capture_c", 10, false)?,
+                CommitResult::InvalidTransactionState
+            );
+            assert_eq!(
+                cancel(conn, user_id, order_id, None)?,
+                CancelResult::InvalidTransactionState
+            );
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_expire_reservations() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_expire";
+        let currency = "USD";
+        let now = chrono::Utc::now().naive_utc();
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            top_up(
+                conn,
+                &curr,
+                user_id,
+                user_id,
+                Money::new(BigDecimal::from(100), currency),
+                None,
+                Default::default(),
+            )?;
+            let past = Some(now - chrono::Duration::seconds(1));
+            let future = Some(now + chrono::Duration::hours(1));
+            for (order_id, expires_at) in [
+                ("test_expire_1", past),
+                ("test_expire_2", future),
+                ("test_expire_3", None),
+            ] {
+                let res = reserve(
+                    conn,
+                    &curr,
+                    user_id,
+                    Money::new(BigDecimal::from(10), currency),
+                    order_id,
+                    None,
+                    expires_at,
+                    None,
+                )?;
+                assert_eq!(res, ReserveResult::Ok);
+            }
+
+            // only the expired hold is released
+            assert!(expire_reservations(conn, now, 100)? >= 1);
+            assert_eq!(
+                queries::load_balance(conn, &curr, user_id)?,
+                queries::tests::single_wallet(currency, BigDecimal::from(80), BigDecimal::from(20))
+            );
+            let state = queries::load_order_state(conn, "test_expire_1")?;
+            assert_eq!(state.len(), 1);
+            assert_eq!(state[0].status, queries::OrderStatus::Expired);
+            assert_eq!(state[0].user_id, user_id);
+            assert_eq!(
+                queries::load_order_state(conn, "test_expire_2")?[0].status,
+                queries::OrderStatus::Reserved
+            );
+
+            // expired order is finished
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(10), currency),
+                "test_expire_1",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::InvalidTransactionState);
+            let res = commit(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(10), currency),
+                "test_expire_1",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert_eq!(res, CommitResult::InvalidTransactionState);
+
+            // other final states are recorded too
+            let res = commit(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(10), currency),
+                "test_expire_2",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, CommitResult::Ok(_)));
+            assert_eq!(cancel(conn, user_id, "test_expire_3", None)?, CancelResult::Ok);
+            assert_eq!(
+                queries::load_order_state(conn, "test_expire_2")?[0].status,
+                queries::OrderStatus::Committed
+            );
+            assert_eq!(
+                queries::load_order_state(conn, "test_expire_3")?[0].status,
+                queries::OrderStatus::Cancelled
             );
+            assert!(queries::load_order_state(conn, "test_expire_unknown")?.is_empty());
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_multi_item_order() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_multi_item";
+        let order_id = "test_multi_item_1";
+        let currency = "USD";
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            top_up(
+                conn,
+                &curr,
+                user_id,
+                user_id,
+                Money::new(BigDecimal::from(100), currency),
+                None,
+                Default::default(),
+            )?;
+            for (item_id, value) in [("test_item_a", 10), ("test_item_b", 20), ("test_item_c", 30)] {
+                let res = reserve(
+                    conn,
+                    &curr,
+                    user_id,
+                    Money::new(BigDecimal::from(value), currency),
+                    order_id,
+                    Some(item_id),
+                    None,
+                    None,
+                )?;
+                assert_eq!(res, ReserveResult::Ok);
+            }
+            let commit_item = |conn: &mut PgConnection, item_id: Option<&str>, value: i32| {
+                commit(
+                    conn,
+                    &curr,
+                    user_id,
+                    Money::new(BigDecimal::from(value), currency),
+                    order_id,
+                    item_id,
+                    None,
+                    false,
+                    None,
+                    Default::default(),
+                )
+            };
+
+            // item has to be chosen when several are reserved
+            assert_eq!(commit_item(conn, None, 10)?, CommitResult::ItemRequired);
+
+            // items are captured and cancelled separately
+            assert!(matches!(
+                commit_item(conn, Some("test_item_a"), 10)?,
+                CommitResult::Ok(_)
+            ));
+            assert_eq!(cancel(conn, user_id, order_id, Some("test_item_b"))?, CancelResult::Ok);
+            assert_eq!(
+                commit_item(conn, Some("test_item_b"), 20)?,
+                CommitResult::InvalidTransactionState
+            );
+            assert_eq!(
+                queries::load_balance(conn, &curr, user_id)?,
+                queries::tests::single_wallet(currency, BigDecimal::from(60), BigDecimal::from(30))
+            );
+
+            // the only reserved item can be captured without item_id
+            let res = commit_item(conn, None, 30)?;
+            let tx_id = match res {
+                CommitResult::Ok(tx_id) => tx_id,
+                res => panic!("unexpected commit result: {res:?}"),
+            };
+            let tx: models::Transaction = {
+                use crate::schema::transaction::dsl::*;
+                transaction.find(tx_id).first(conn)?
+            };
+            assert_eq!(tx.order_data.unwrap()["item_id"], "test_item_c");
+
+            let statuses: Vec<_> = queries::load_order_state(conn, order_id)?
+                .into_iter()
+                .map(|item| (item.item_id, item.status))
+                .collect();
+            assert_eq!(
+                statuses,
+                vec![
+                    ("test_item_a".to_string(), queries::OrderStatus::Committed),
+                    ("test_item_b".to_string(), queries::OrderStatus::Cancelled),
+                    ("test_item_c".to_string(), queries::OrderStatus::Committed),
+                ]
+            );
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_commit_other_user_order() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let owner = "test_other_user_order_owner";
+        let other = "test_other_user_order_other";
+        let order_id = "test_other_user_order";
+        let usd = |value: i32| Money::new(BigDecimal::from(value), "USD");
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            for user_id in [owner, other] {
+                let res = top_up(conn, &curr, user_id, user_id, usd(100), None, Default::default())?;
+                assert!(matches!(res, TopUpResult::Ok(_)));
+            }
+            let res = reserve(conn, &curr, owner, usd(30), order_id, None, None, None)?;
+            assert_eq!(res, ReserveResult::Ok);
+            let commit_order = |conn: &mut PgConnection, user_id: &str, value: i32| {
+                commit(
+                    conn,
+                    &curr,
+                    user_id,
+                    usd(value),
+                    order_id,
+                    None,
+                    None,
+                    false,
+                    None,
+                    Default::default(),
+                )
+            };
+
+            // reservation of the owner is not captured by another user, who is charged without reservation
+            assert!(matches!(commit_order(conn, other, 10)?, CommitResult::Ok(_)));
+            assert_eq!(
+                queries::load_balance(conn, &curr, owner)?,
+                queries::tests::single_wallet("USD", BigDecimal::from(70), BigDecimal::from(30))
+            );
+            assert_eq!(
+                queries::load_balance(conn, &curr, other)?,
+                queries::tests::single_wallet("USD", BigDecimal::from(90), BigDecimal::from(0))
+            );
+            assert_eq!(ledger::account_balance(conn, &Account::hold(owner, "USD"))?, usd(30));
+            assert_eq!(ledger::account_balance(conn, &Account::hold(other, "USD"))?, usd(0));
+
+            // owner still captures the reservation
+            assert!(matches!(commit_order(conn, owner, 30)?, CommitResult::Ok(_)));
+            assert_eq!(
+                queries::load_balance(conn, &curr, owner)?,
+                queries::tests::single_wallet("USD", BigDecimal::from(70), BigDecimal::from(0))
+            );
+            assert_eq!(ledger::account_balance(conn, &Account::hold(owner, "USD"))?, usd(0));
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_same_order_other_users() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let owner = "test_same_order_owner";
+        let other = "test_same_order_other";
+        let order_id = "test_same_order";
+        let usd = |value: i32| Money::new(BigDecimal::from(value), "USD");
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            // order and item ids are only unique per user
+            for user_id in [owner, other] {
+                let res = top_up(conn, &curr, user_id, user_id, usd(100), None, Default::default())?;
+                assert!(matches!(res, TopUpResult::Ok(_)));
+                let res = reserve(conn, &curr, user_id, usd(30), order_id, Some("1"), None, None)?;
+                assert_eq!(res, ReserveResult::Ok);
+            }
+
+            let res = commit(
+                conn,
+                &curr,
+                owner,
+                usd(30),
+                order_id,
+                Some("1"),
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, CommitResult::Ok(_)));
+            assert_eq!(
+                queries::load_balance(conn, &curr, other)?,
+                queries::tests::single_wallet("USD", BigDecimal::from(70), BigDecimal::from(30))
+            );
+
+            // the other user still cancels own reservation, the committed one can't be cancelled
+            assert_eq!(cancel(conn, other, order_id, Some("1"))?, CancelResult::Ok);
+            assert_eq!(
+                queries::load_balance(conn, &curr, other)?,
+                queries::tests::single_wallet("USD", BigDecimal::from(100), BigDecimal::from(0))
+            );
+            assert_eq!(
+                cancel(conn, owner, order_id, Some("1"))?,
+                CancelResult::InvalidTransactionState
+            );
+            assert_eq!(
+                queries::load_balance(conn, &curr, owner)?,
+                queries::tests::single_wallet("USD", BigDecimal::from(70), BigDecimal::from(0))
+            );
+
+            // finished orders of either user can't be reserved again
+            for user_id in [owner, other] {
+                let res = reserve(conn, &curr, user_id, usd(10), order_id, Some("1"), None, None)?;
+                assert_eq!(res, ReserveResult::InvalidTransactionState);
+            }
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_transfer() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let sender_id = "test_transfer_sender";
+        let recipient_id = "test_transfer_recipient";
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            top_up(
+                conn,
+                &curr,
+                "test_transfer_1",
+                sender_id,
+                Money::new(BigDecimal::from(100), "EUR"),
+                None,
+                Default::default(),
+            )?;
+            top_up(
+                conn,
+                &curr,
+                "test_transfer_2",
+                recipient_id,
+                Money::new(BigDecimal::from(10), "USD"),
+                None,
+                Default::default(),
+            )?;
+            let res = reserve(
+                conn,
+                &curr,
+                sender_id,
+                Money::new(BigDecimal::from(30), "EUR"),
+                "test_transfer",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+
+            // reserved funds are not available
+            let res = transfer(
+                conn,
+                &curr,
+                "test_transfer_3",
+                sender_id,
+                recipient_id,
+                Money::new(BigDecimal::from(80), "EUR"),
+                Default::default(),
+            )?;
+            assert_eq!(res, TransferResult::InsufficientFunds);
+
+            let res = transfer(
+                conn,
+                &curr,
+                "test_transfer_4",
+                sender_id,
+                recipient_id,
+                Money::new(BigDecimal::from(50), "EUR"),
+                Default::default(),
+            )?;
+            let tx_id = match res {
+                TransferResult::Ok(tx_id) => tx_id,
+                res => panic!("unexpected transfer result: {res:?}"),
+            };
+
+            // conversion into recipient currency references the rates it used
+            let tx = {
+                use crate::schema::transaction::dsl::*;
+                transaction.find(tx_id).first::<models::Transaction>(conn)?
+            };
+            assert_eq!(tx.rate_snapshot_id, Some(curr.snapshot().snapshot_id));
+
+            let expected_sender_balance =
+                queries::tests::single_wallet("EUR", BigDecimal::from(20), BigDecimal::from(30));
+            let expected_recipient_balance = queries::tests::single_wallet(
+                "USD",
+                currency::round_value(
+                    &(BigDecimal::from(10)
+                        + curr
+                            .convert(&Money::new(BigDecimal::from(50), "EUR"), "USD")
+                            .unwrap()
+                            .into_amount()),
+                    "USD",
+                ),
+                BigDecimal::from(0),
+            );
+            assert_eq!(queries::load_balance(conn, &curr, sender_id)?, expected_sender_balance);
+            assert_eq!(
+                queries::load_balance(conn, &curr, recipient_id)?,
+                expected_recipient_balance
+            );
+
+            // repeated request doesn't move funds twice
+            let res = transfer(
+                conn,
+                &curr,
+                "test_transfer_4",
+                sender_id,
+                recipient_id,
+                Money::new(BigDecimal::from(50), "EUR"),
+                Default::default(),
+            )?;
+            assert_eq!(res, TransferResult::Ok(tx_id));
+            assert_eq!(queries::load_balance(conn, &curr, sender_id)?, expected_sender_balance);
+            assert_eq!(
+                queries::load_balance(conn, &curr, recipient_id)?,
+                expected_recipient_balance
+            );
+
+            let res = transfer(
+                conn,
+                &curr,
+                "test_transfer_5",
+                sender_id,
+                "test_transfer_unknown",
+                Money::new(BigDecimal::from(1), "EUR"),
+                Default::default(),
+            )?;
+            assert_eq!(res, TransferResult::UserNotFound);
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_wallets() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let wallet_owner = "test_wallets";
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            top_up(
+                conn,
+                &curr,
+                "test_wallets_1",
+                wallet_owner,
+                Money::new(BigDecimal::from(100), "USD"),
+                None,
+                Default::default(),
+            )?;
+            top_up(
+                conn,
+                &curr,
+                "test_wallets_2",
+                wallet_owner,
+                Money::new(BigDecimal::from(50), "EUR"),
+                None,
+                Default::default(),
+            )?;
+
+            // reservation in wallet currency is paid from that wallet without conversion
+            let res = reserve(
+                conn,
+                &curr,
+                wallet_owner,
+                Money::new(BigDecimal::from(30), "EUR"),
+                "test_wallets_1",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            // reservation in other currency is paid from the primary wallet
+            let res = reserve(
+                conn,
+                &curr,
+                wallet_owner,
+                Money::new(BigDecimal::from(100), "RUB"),
+                "test_wallets_2",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            // falls back to other wallets when the preferred one has not enough funds
+            let res = reserve(
+                conn,
+                &curr,
+                wallet_owner,
+                Money::new(BigDecimal::from(40), "EUR"),
+                "test_wallets_3",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+
+            let reservations = {
+                use crate::schema::balance_reserve::dsl::*;
+                balance_reserve
+                    .filter(user_id.eq(wallet_owner))
+                    .order(order_id)
+                    .load::<models::BalanceReserve>(conn)?
+            };
+            let wallet_currencies: Vec<&str> = reservations.iter().map(|rec| rec.wallet_currency.as_str()).collect();
+            assert_eq!(wallet_currencies, vec!["EUR", "USD", "USD"]);
+            assert_eq!(reservations[0].user_currency_value, BigDecimal::from(30));
+
+            let balance = match queries::load_balance(conn, &curr, wallet_owner)? {
+                queries::UserBalance::Ok(balance) => balance,
+                res => panic!("unexpected balance: {res:?}"),
+            };
+            assert_eq!(balance.currency, "USD");
+            let wallets: Vec<(&str, BigDecimal, BigDecimal)> = balance
+                .wallets
+                .iter()
+                .map(|wallet| {
+                    (
+                        wallet.currency.as_str(),
+                        wallet.balance.amount().clone(),
+                        wallet.reserved.amount().clone(),
+                    )
+                })
+                .collect();
+            assert_eq!(wallets[1], ("EUR", BigDecimal::from(20), BigDecimal::from(30)));
+            assert_eq!(wallets[0].0, "USD");
+            assert_eq!(
+                wallets[0].2,
+                reservations[1].user_currency_value.clone() + reservations[2].user_currency_value.clone()
+            );
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_quotes() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_quotes";
+        let now = chrono::Utc::now().naive_utc();
+        let later = now + chrono::Duration::seconds(60);
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            top_up(
+                conn,
+                &curr,
+                "test_quotes_1",
+                user_id,
+                Money::new(BigDecimal::from(100), "USD"),
+                None,
+                Default::default(),
+            )?;
+
+            let quote = create_quote(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(50), "EUR"),
+                "USD",
+                later,
+            )?;
+            assert_eq!(
+                quote.converted_value,
+                currency::round_value(&(BigDecimal::from(50) * &quote.rate), "USD")
+            );
+            assert_eq!(quote.rate_snapshot_id, curr.snapshot().snapshot_id);
+
+            // quote doesn't match the request
+            let expired = create_quote(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(50), "EUR"),
+                "USD",
+                now - chrono::Duration::seconds(1),
+            )?;
+            let smaller = create_quote(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(40), "EUR"),
+                "USD",
+                later,
+            )?;
+            let other_currency = create_quote(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(50), "GBP"),
+                "USD",
+                later,
+            )?;
+            let no_wallet = create_quote(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(50), "EUR"),
+                "RUB",
+                later,
+            )?;
+            let other_user = create_quote(
+                conn,
+                &curr,
+                "test_quotes_other",
+                Money::new(BigDecimal::from(50), "EUR"),
+                "USD",
+                later,
+            )?;
+            for quote_id in [
+                expired.id,
+                smaller.id,
+                other_currency.id,
+                no_wallet.id,
+                other_user.id,
+                1,
+            ] {
+                let res = reserve(
+                    conn,
+                    &curr,
+                    user_id,
+                    Money::new(BigDecimal::from(50), "EUR"),
+                    "test_quotes",
+                    None,
+                    None,
+                    Some(quote_id),
+                )?;
+                assert_eq!(res, ReserveResult::InvalidQuote);
+            }
+
+            // locked rate is used without reserve for rate changes
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(50), "EUR"),
+                "test_quotes",
+                None,
+                None,
+                Some(quote.id),
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            let reserved = queries::load_balance(conn, &curr, user_id)?;
+            assert_eq!(
+                reserved,
+                queries::tests::single_wallet(
+                    "USD",
+                    BigDecimal::from(100) - &quote.converted_value,
+                    quote.converted_value.clone()
+                )
+            );
+
+            // quote is taken by the reserved order item
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(50), "EUR"),
+                "test_quotes_2",
+                None,
+                None,
+                Some(quote.id),
+            )?;
+            assert_eq!(res, ReserveResult::InvalidQuote);
+            let taken = crate::schema::quote::table
+                .find(quote.id)
+                .first::<models::Quote>(conn)?;
+            assert_eq!(taken.order_id.as_deref(), Some("test_quotes"));
+            assert_eq!(taken.item_id.as_deref(), Some(""));
+
+            // quote into other currency than the wallet of the reservation is rejected, reservation is kept
+            let res = commit(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(50), "EUR"),
+                "test_quotes",
+                None,
+                None,
+                false,
+                Some(no_wallet.id),
+                Default::default(),
+            )?;
+            assert_eq!(res, CommitResult::InvalidQuote);
+            assert_eq!(queries::load_balance(conn, &curr, user_id)?, reserved);
+
+            let res = commit(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(50), "EUR"),
+                "test_quotes",
+                None,
+                None,
+                false,
+                Some(quote.id),
+                Default::default(),
+            )?;
+            let tx_id = match res {
+                CommitResult::Ok(tx_id) => tx_id,
+                res => panic!("unexpected commit result: {res:?}"),
+            };
+            let tx = {
+                use crate::schema::transaction::dsl::*;
+                transaction.find(tx_id).first::<models::Transaction>(conn)?
+            };
+            assert_eq!(tx.sender_value, Some(quote.converted_value.clone()));
+            assert_eq!(tx.rate_snapshot_id, Some(quote.rate_snapshot_id));
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_fx_rules() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let sender = "test_fx_rules_sender";
+        let recipient = "test_fx_rules_recipient";
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            queries::tests::insert_fx_rule(conn, "EUR", "USD", &["0.01", "0.05", "0"])?;
+            queries::tests::insert_fx_rule(conn, "USD", "USD", &["0", "0", "5"])?;
+            top_up(
+                conn,
+                &curr,
+                "test_fx_rules_1",
+                sender,
+                Money::new(BigDecimal::from(100), "USD"),
+                None,
+                Default::default(),
+            )?;
+            top_up(
+                conn,
+                &curr,
+                "test_fx_rules_2",
+                recipient,
+                Money::new(BigDecimal::from(10), "EUR"),
+                None,
+                Default::default(),
+            )?;
+            let wallet_value = |conn: &mut PgConnection, wallet_owner: &str, wallet_currency: &str| {
+                use crate::schema::balance::dsl::*;
+                balance
+                    .find((wallet_owner, wallet_currency))
+                    .select(current_value)
+                    .first::<BigDecimal>(conn)
+            };
+
+            // sender pays the spread on top of converted value, recipient wallet needs no conversion
+            let converted = curr.convert(&Money::new(BigDecimal::from(20), "EUR"), "USD").unwrap();
+            let tx_id = match transfer(
+                conn,
+                &curr,
+                "test_fx_rules_3",
+                sender,
+                recipient,
+                Money::new(BigDecimal::from(20), "EUR"),
+                Default::default(),
+            )? {
+                TransferResult::Ok(tx_id) => tx_id,
+                res => panic!("unexpected transfer result: {res:?}"),
+            };
+            let tx = {
+                use crate::schema::transaction::dsl::*;
+                transaction.find(tx_id).first::<models::Transaction>(conn)?
+            };
+            let sender_value = (&converted * &BigDecimal::from_str("1.01").unwrap()).round();
+            assert_eq!(tx.sender_value(), Some(sender_value.clone()));
+            assert_eq!(tx.recipient_value, Some(BigDecimal::from(20)));
+            let income: Vec<(String, BigDecimal)> = {
+                use crate::schema::spread_income::dsl::*;
+                spread_income
+                    .filter(transaction_id.eq(tx_id))
+                    .select((currency, value))
+                    .load(conn)?
+            };
+            assert_eq!(
+                income,
+                vec![("USD".to_string(), (sender_value - converted).into_amount())]
+            );
+
+            // reservation holds the spread and the margin of the pair
+            let res = reserve(
+                conn,
+                &curr,
+                sender,
+                Money::new(BigDecimal::from(10), "EUR"),
+                "test_fx_rules_1",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            let reservation = {
+                use crate::schema::balance_reserve::dsl::*;
+                balance_reserve
+                    .filter(order_id.eq("test_fx_rules_1"))
+                    .first::<models::BalanceReserve>(conn)?
+            };
+            let converted = curr.convert(&Money::new(BigDecimal::from(10), "EUR"), "USD").unwrap();
+            let charged = (&converted * &BigDecimal::from_str("1.01").unwrap()).round();
+            assert_eq!(
+                reservation.held(),
+                (&charged * &BigDecimal::from_str("1.05").unwrap()).round()
+            );
+
+            // wallet may go below zero within the overdraft of the pair, funds held by the reservation are not charged
+            let held = reservation.held().into_amount();
+            let overdraft_commit = wallet_value(conn, sender, "USD")? - &held + BigDecimal::from(3);
+            let res = commit(
+                conn,
+                &curr,
+                sender,
+                Money::new(overdraft_commit, "USD"),
+                "test_fx_rules_2",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, CommitResult::Ok(_)));
+            assert_eq!(wallet_value(conn, sender, "USD")?, &held - BigDecimal::from(3));
+
+            // commit without reservation can't go beyond the overdraft
+            let res = commit(
+                conn,
+                &curr,
+                sender,
+                Money::new(BigDecimal::from(3), "USD"),
+                "test_fx_rules_3",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert_eq!(res, CommitResult::InsufficientFunds);
+
+            // reserved foreign-currency commit can't go beyond the overdraft, the reservation stays
+            let res = commit(
+                conn,
+                &curr,
+                sender,
+                Money::new(BigDecimal::from(10), "EUR"),
+                "test_fx_rules_1",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert_eq!(res, CommitResult::InsufficientFunds);
+            let reservations = {
+                use crate::schema::balance_reserve::dsl::*;
+                balance_reserve
+                    .filter(order_id.eq("test_fx_rules_1"))
+                    .count()
+                    .get_result::<i64>(conn)?
+            };
+            assert_eq!(reservations, 1);
+
+            // quote rounds the converted value to minor units before the spread, as commit charges it
+            let quoted = Money::new(BigDecimal::from_str("10.37").unwrap(), "EUR");
+            let quote = create_quote(
+                conn,
+                &curr,
+                sender,
+                quoted.clone(),
+                "USD",
+                chrono::Utc::now().naive_utc() + chrono::Duration::seconds(60),
+            )?;
+            let converted = currency::round_value(&(quoted.amount() * &quote.rate), "USD");
+            assert_eq!(
+                quote.converted_value,
+                currency::round_value(&(converted * BigDecimal::from_str("1.01").unwrap()), "USD")
+            );
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_commit_other_reservations() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_commit_other_reservations";
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            queries::tests::insert_fx_rule(conn, "EUR", "USD", &["0", "0", "0"])?;
+            top_up(
+                conn,
+                &curr,
+                "test_commit_other_reservations",
+                user_id,
+                Money::new(BigDecimal::from(100), "USD"),
+                None,
+                Default::default(),
+            )?;
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(10), "EUR"),
+                "test_commit_other_reservations_1",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            let held = curr.convert(&Money::new(BigDecimal::from(10), "EUR"), "USD").unwrap();
+            // the rest of the wallet is held by the second order
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                &Money::new(BigDecimal::from(100), "USD") - &held,
+                "test_commit_other_reservations_2",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+
+            // spread added after the reservation makes the capture cost more than its hold,
+            // the difference can't be taken from the funds held for the second order
+            queries::tests::insert_fx_rule(conn, "EUR", "USD", &["0.5", "0", "0"])?;
+            let capture = |conn: &mut PgConnection| {
+                commit(
+                    conn,
+                    &curr,
+                    user_id,
+                    Money::new(BigDecimal::from(10), "EUR"),
+                    "test_commit_other_reservations_1",
+                    None,
+                    None,
+                    false,
+                    None,
+                    Default::default(),
+                )
+            };
+            assert_eq!(capture(conn)?, CommitResult::InsufficientFunds);
+
+            // released funds cover the difference
+            assert_eq!(
+                cancel(conn, user_id, "test_commit_other_reservations_2", None)?,
+                CancelResult::Ok
+            );
+            assert!(matches!(capture(conn)?, CommitResult::Ok(_)));
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_credit_limit() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_credit_limit_user";
+        let recipient = "test_credit_limit_recipient";
+        let usd = |value: &str| Money::new(BigDecimal::from_str(value).unwrap(), "USD");
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            top_up(
+                conn,
+                &curr,
+                "test_credit_limit_1",
+                user_id,
+                usd("50"),
+                None,
+                Default::default(),
+            )?;
+            top_up(
+                conn,
+                &curr,
+                "test_credit_limit_2",
+                recipient,
+                usd("1"),
+                None,
+                Default::default(),
+            )?;
+            let overdraft = |conn: &mut PgConnection| -> Result<Option<queries::Overdraft>, Error> {
+                Ok(queries::list_overdrafts(conn)?
+                    .into_iter()
+                    .find(|overdraft| overdraft.user_id == user_id))
+            };
+
+            assert_eq!(
+                set_credit_limit(conn, "test_credit_limit_unknown", usd("100"))?,
+                CreditLimitResult::UserNotFound
+            );
+
+            // without credit line the wallet can't go below zero
+            let res = reserve(conn, &curr, user_id, usd("80"), "test_credit_limit", None, None, None)?;
+            assert_eq!(res, ReserveResult::InsufficientFunds);
+
+            assert_eq!(set_credit_limit(conn, user_id, usd("100"))?, CreditLimitResult::Ok);
+            let res = reserve(conn, &curr, user_id, usd("80"), "test_credit_limit", None, None, None)?;
+            assert_eq!(res, ReserveResult::Ok);
+            let res = commit(
+                conn,
+                &curr,
+                user_id,
+                usd("80"),
+                "test_credit_limit",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, CommitResult::Ok(_)));
+
+            let balance = match queries::load_balance(conn, &curr, user_id)? {
+                queries::UserBalance::Ok(balance) => balance,
+                res => panic!("unexpected balance: {res:?}"),
+            };
+            assert_eq!(balance.balance, usd("-30"));
+            assert_eq!(balance.wallets[0].credit_limit, usd("100"));
+            let negative_since = match overdraft(conn)? {
+                Some(overdraft) => {
+                    assert_eq!(overdraft.balance, usd("-30"));
+                    assert_eq!(overdraft.credit_limit, usd("100"));
+                    overdraft.negative_since
+                }
+                None => panic!("overdraft is not reported"),
+            };
+
+            // credit line is used up exactly, the time the wallet went below zero is kept
+            let res = transfer(
+                conn,
+                &curr,
+                "test_credit_limit_3",
+                user_id,
+                recipient,
+                usd("70"),
+                Default::default(),
+            )?;
+            assert!(matches!(res, TransferResult::Ok(_)));
+            assert_eq!(
+                overdraft(conn)?.map(|overdraft| (overdraft.balance, overdraft.negative_since)),
+                Some((usd("-100"), negative_since))
+            );
+            let res = transfer(
+                conn,
+                &curr,
+                "test_credit_limit_4",
+                user_id,
+                recipient,
+                usd("0.01"),
+                Default::default(),
+            )?;
+            assert_eq!(res, TransferResult::InsufficientFunds);
+
+            // overdraft ends when the balance is back above zero
+            top_up(
+                conn,
+                &curr,
+                "test_credit_limit_5",
+                user_id,
+                usd("100"),
+                None,
+                Default::default(),
+            )?;
+            assert_eq!(overdraft(conn)?, None);
+
+            // charge without reservation can't take funds held for another order
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                usd("60"),
+                "test_credit_limit_reserved",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            let unreserved_commit = |conn: &mut PgConnection, value: &str| {
+                commit(
+                    conn,
+                    &curr,
+                    user_id,
+                    usd(value),
+                    "test_credit_limit_unreserved",
+                    None,
+                    None,
+                    false,
+                    None,
+                    Default::default(),
+                )
+            };
+            assert_eq!(unreserved_commit(conn, "50")?, CommitResult::InsufficientFunds);
+            assert!(matches!(unreserved_commit(conn, "40")?, CommitResult::Ok(_)));
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_account_status() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_account_status_user";
+        let payout = "test_account_status_payout";
+        let usd = |value: &str| Money::new(BigDecimal::from_str(value).unwrap(), "USD");
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            assert_eq!(open_account(conn, user_id, "USD")?, OpenAccountResult::Ok);
+            assert_eq!(open_account(conn, user_id, "USD")?, OpenAccountResult::AlreadyExists);
+            assert_eq!(open_account(conn, payout, "USD")?, OpenAccountResult::Ok);
+            let res = top_up(
+                conn,
+                &curr,
+                "test_account_status_1",
+                user_id,
+                usd("100"),
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, TopUpResult::Ok(_)));
+            let set_status = |conn: &mut PgConnection, status: &str, payout_user_id: Option<&str>| {
+                set_account_status(
+                    conn,
+                    &curr,
+                    user_id,
+                    "USD",
+                    status,
+                    "test",
+                    payout_user_id,
+                    Default::default(),
+                )
+            };
+
+            // frozen wallet still gets credits
+            assert_eq!(
+                set_status(conn, models::ACCOUNT_FROZEN_DEBITS, None)?,
+                AccountStatusResult::Ok
+            );
+            let res = reserve(conn, &curr, user_id, usd("10"), "test_account_status", None, None, None)?;
+            assert_eq!(res, ReserveResult::AccountBlocked);
+            let res = transfer(
+                conn,
+                &curr,
+                "test_account_status_2",
+                user_id,
+                payout,
+                usd("10"),
+                Default::default(),
+            )?;
+            assert_eq!(res, TransferResult::AccountBlocked);
+            let res = top_up(
+                conn,
+                &curr,
+                "test_account_status_3",
+                user_id,
+                usd("10"),
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, TopUpResult::Ok(_)));
+
+            assert_eq!(
+                set_status(conn, models::ACCOUNT_FROZEN_ALL, None)?,
+                AccountStatusResult::Ok
+            );
+            let res = top_up(
+                conn,
+                &curr,
+                "test_account_status_4",
+                user_id,
+                usd("10"),
+                None,
+                Default::default(),
+            )?;
+            assert_eq!(res, TopUpResult::AccountBlocked);
+            let res = transfer(
+                conn,
+                &curr,
+                "test_account_status_5",
+                payout,
+                user_id,
+                usd("0"),
+                Default::default(),
+            )?;
+            assert_eq!(res, TransferResult::AccountBlocked);
+
+            // reserved wallet can't be closed
+            assert_eq!(set_status(conn, models::ACCOUNT_ACTIVE, None)?, AccountStatusResult::Ok);
+            let res = reserve(conn, &curr, user_id, usd("10"), "test_account_status", None, None, None)?;
+            assert_eq!(res, ReserveResult::Ok);
+            assert_eq!(
+                set_status(conn, models::ACCOUNT_CLOSED, Some(payout))?,
+                AccountStatusResult::InvalidTransactionState
+            );
+            assert_eq!(cancel(conn, user_id, "test_account_status", None)?, CancelResult::Ok);
+
+            // remaining funds need a payout
+            assert_eq!(
+                set_status(conn, models::ACCOUNT_CLOSED, None)?,
+                AccountStatusResult::InvalidTransactionState
+            );
+            assert_eq!(
+                set_status(conn, models::ACCOUNT_CLOSED, Some(payout))?,
+                AccountStatusResult::Ok
+            );
+            let wallet_values =
+                |conn: &mut PgConnection, wallet_user_id: &str| -> Result<queries::WalletValues, Error> {
+                    match queries::load_balance(conn, &curr, wallet_user_id)? {
+                        queries::UserBalance::Ok(balance) => Ok(balance.wallets.into_iter().next().unwrap()),
+                        res => panic!("unexpected balance: {res:?}"),
+                    }
+                };
+            let wallet = wallet_values(conn, user_id)?;
+            assert_eq!(
+                (wallet.balance, wallet.status.as_str()),
+                (usd("0"), models::ACCOUNT_CLOSED)
+            );
+            assert_eq!(wallet_values(conn, payout)?.balance, usd("110"));
+
+            // closing is final
+            assert_eq!(
+                set_status(conn, models::ACCOUNT_ACTIVE, None)?,
+                AccountStatusResult::InvalidTransactionState
+            );
+            let res = top_up(
+                conn,
+                &curr,
+                "test_account_status_6",
+                user_id,
+                usd("10"),
+                None,
+                Default::default(),
+            )?;
+            assert_eq!(res, TopUpResult::AccountBlocked);
+            // closed user gets no new wallet in another currency
+            let res = top_up(
+                conn,
+                &curr,
+                "test_account_status_7",
+                user_id,
+                Money::new(BigDecimal::from(10), "EUR"),
+                None,
+                Default::default(),
+            )?;
+            assert_eq!(res, TopUpResult::AccountBlocked);
+            let wallets = {
+                use crate::schema::balance::dsl::*;
+                balance
+                    .filter(user_id.eq("test_account_status_user"))
+                    .count()
+                    .get_result::<i64>(conn)?
+            };
+            assert_eq!(wallets, 1);
+
+            let history: Vec<(Option<String>, String, Option<i64>)> = {
+                use crate::schema::balance_status_history::dsl::*;
+                balance_status_history
+                    .filter(user_id.eq("test_account_status_user"))
+                    .order(created_at)
+                    .select((old_status, new_status, payout_transaction_id))
+                    .load(conn)?
+            };
+            let statuses: Vec<_> = history
+                .iter()
+                .map(|(old, new, _)| (old.as_deref(), new.as_str()))
+                .collect();
+            assert_eq!(
+                statuses,
+                vec![
+                    (None, models::ACCOUNT_ACTIVE),
+                    (Some(models::ACCOUNT_ACTIVE), models::ACCOUNT_FROZEN_DEBITS),
+                    (Some(models::ACCOUNT_FROZEN_DEBITS), models::ACCOUNT_FROZEN_ALL),
+                    (Some(models::ACCOUNT_FROZEN_ALL), models::ACCOUNT_ACTIVE),
+                    (Some(models::ACCOUNT_ACTIVE), models::ACCOUNT_CLOSED),
+                ]
+            );
+            assert!(history[4].2.is_some());
+
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_transaction_details() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_transaction_details_user";
+        let recipient = "test_transaction_details_recipient";
+        let usd = |value: &str| Money::new(BigDecimal::from_str(value).unwrap(), "USD");
+
+        assert_eq!(TransactionDetails::new("", "gift", "").invalid_field(), None);
+        assert_eq!(
+            TransactionDetails::new("", "Gift", "").invalid_field(),
+            Some("category")
+        );
+        assert_eq!(
+            TransactionDetails::new("", "", &"x".repeat(65)).invalid_field(),
+            Some("source")
+        );
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            let res = top_up(
+                conn,
+                &curr,
+                "test_transaction_details_1",
+                user_id,
+                usd("100"),
+                Some(r#"{"merchant": "shop"}"#),
+                Default::default(),
+            )?;
+            assert!(matches!(res, TopUpResult::Ok(_)));
+            let res = top_up(
+                conn,
+                &curr,
+                "test_transaction_details_2",
+                recipient,
+                usd("1"),
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, TopUpResult::Ok(_)));
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                usd("20"),
+                "test_transaction_details",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            let res = commit(
+                conn,
+                &curr,
+                user_id,
+                usd("20"),
+                "test_transaction_details",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, CommitResult::Ok(_)));
+            let res = transfer(
+                conn,
+                &curr,
+                "test_transaction_details_3",
+                user_id,
+                recipient,
+                usd("5"),
+                TransactionDetails::new("Birthday present", "gift", "family"),
+            )?;
+            assert!(matches!(res, TransferResult::Ok(_)));
+
+            let details = |conn: &mut PgConnection,
+                           category: Option<&str>|
+             -> Result<Vec<models::NewTransactionDetails>, Error> {
+                let filter = queries::TransactionsFilter {
+                    user_id: user_id.to_string(),
+                    order: queries::TransactionsOrder::AmountDesc,
+                    min_ts: None,
+                    max_ts: None,
+                    category: category.map(str::to_string),
+                    after: None,
+                };
+                Ok(queries::list_transactions(conn, &filter, 10)?
+                    .transactions
+                    .into_iter()
+                    .map(|tx| models::NewTransactionDetails {
+                        description: tx.description,
+                        category: tx.category,
+                        source: tx.source,
+                    })
+                    .collect())
+            };
+            let expected = |description: &str, category: &str, source: Option<&str>| models::NewTransactionDetails {
+                description: Some(description.to_string()),
+                category: category.to_string(),
+                source: source.map(str::to_string),
+            };
+            assert_eq!(
+                details(conn, None)?,
+                vec![
+                    expected("Top-up of 100.00 USD via shop", models::CATEGORY_TOP_UP, Some("shop")),
+                    expected(
+                        "Payment of 20.00 USD for order test_transaction_details",
+                        models::CATEGORY_PURCHASE,
+                        None
+                    ),
+                    expected("Birthday present", "gift", Some("family")),
+                ]
+            );
+            assert_eq!(details(conn, Some("gift"))?.len(), 1);
+            assert_eq!(details(conn, Some(models::CATEGORY_TRANSFER))?, vec![]);
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_change_currency() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_change_currency_user";
+        let usd = |value: &str| Money::new(BigDecimal::from_str(value).unwrap(), "USD");
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            assert_eq!(
+                change_currency(conn, &curr, user_id, "USD", "EUR", "test", Default::default())?,
+                ChangeCurrencyResult::UserNotFound
+            );
+            let res = top_up(
+                conn,
+                &curr,
+                "test_change_currency_1",
+                user_id,
+                usd("100"),
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, TopUpResult::Ok(_)));
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                usd("30"),
+                "test_change_currency",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            assert_eq!(open_account(conn, user_id, "GBP")?, OpenAccountResult::Ok);
+            assert_eq!(
+                change_currency(conn, &curr, user_id, "USD", "GBP", "test", Default::default())?,
+                ChangeCurrencyResult::InvalidTransactionState
+            );
+
+            let tx_id = match change_currency(
+                conn,
+                &curr,
+                user_id,
+                "USD",
+                "EUR",
+                "test",
+                TransactionDetails::new("", "", "support"),
+            )? {
+                ChangeCurrencyResult::Ok(tx_id) => tx_id,
+                res => panic!("unexpected result: {res:?}"),
+            };
+            // old wallet is closed and can't be converted again
+            assert_eq!(
+                change_currency(conn, &curr, user_id, "USD", "RUB", "test", Default::default())?,
+                ChangeCurrencyResult::AccountBlocked
+            );
+
+            let balance = match queries::load_balance(conn, &curr, user_id)? {
+                queries::UserBalance::Ok(balance) => balance,
+                res => panic!("unexpected balance: {res:?}"),
+            };
+            let wallets: Vec<_> = balance
+                .wallets
+                .iter()
+                .map(|wallet| (wallet.balance.clone(), wallet.status.as_str()))
+                .collect();
+            assert_eq!(
+                wallets,
+                vec![
+                    (
+                        &curr.convert(&usd("100"), "EUR").unwrap() - &curr.convert(&usd("30"), "EUR").unwrap(),
+                        models::ACCOUNT_ACTIVE
+                    ),
+                    (Money::zero("GBP"), models::ACCOUNT_ACTIVE),
+                    (usd("0"), models::ACCOUNT_CLOSED),
+                ]
+            );
+
+            let reservation: models::BalanceReserve = {
+                use crate::schema::balance_reserve::dsl::*;
+                balance_reserve
+                    .filter(order_id.eq("test_change_currency"))
+                    .first(conn)?
+            };
+            assert_eq!(reservation.held(), curr.convert(&usd("30"), "EUR").unwrap());
+            let tx: models::Transaction = {
+                use crate::schema::transaction::dsl::*;
+                transaction.find(tx_id).first(conn)?
+            };
+            assert_eq!(
+                tx.rate,
+                Some(
+                    curr.snapshot()
+                        .rate("USD", "EUR")
+                        .unwrap()
+                        .with_scale(11)
+                        .round(10)
+                        .normalized()
+                )
+            );
+            assert_eq!(
+                (tx.sender_currency.as_deref(), tx.recipient_currency.as_deref()),
+                (Some("USD"), Some("EUR"))
+            );
+            assert_eq!(tx.category, models::CATEGORY_ADJUSTMENT);
+            assert_eq!(tx.source.as_deref(), Some("support"));
+
+            // reservation is released into the new wallet
+            assert_eq!(cancel(conn, user_id, "test_change_currency", None)?, CancelResult::Ok);
+            let eur_value = curr.convert(&usd("100"), "EUR").unwrap();
+            assert_eq!(crate::database::ledger::wallet_value(conn, user_id, "EUR")?, eur_value);
+
+            // closed wallet takes no top-ups until the user changes back to its currency
+            let res = top_up(
+                conn,
+                &curr,
+                "test_change_currency_2",
+                user_id,
+                usd("10"),
+                None,
+                Default::default(),
+            )?;
+            assert_eq!(res, TopUpResult::AccountBlocked);
+            assert!(matches!(
+                change_currency(conn, &curr, user_id, "EUR", "USD", "test", Default::default())?,
+                ChangeCurrencyResult::Ok(_)
+            ));
+            let res = top_up(
+                conn,
+                &curr,
+                "test_change_currency_3",
+                user_id,
+                usd("10"),
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, TopUpResult::Ok(_)));
+            let statuses: Vec<(String, String)> = {
+                use crate::schema::balance::dsl::*;
+                balance
+                    .filter(user_id.eq("test_change_currency_user"))
+                    .order(currency)
+                    .select((currency, status))
+                    .load(conn)?
+            };
+            assert_eq!(
+                statuses,
+                vec![
+                    ("EUR".to_string(), models::ACCOUNT_CLOSED.to_string()),
+                    ("GBP".to_string(), models::ACCOUNT_ACTIVE.to_string()),
+                    ("USD".to_string(), models::ACCOUNT_ACTIVE.to_string()),
+                ]
+            );
+            assert_eq!(
+                crate::database::ledger::wallet_value(conn, user_id, "USD")?,
+                &curr.convert(&eur_value, "USD").unwrap() + &usd("10")
+            );
+            Ok(())
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_ledger() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let curr = currency::create_currency_converter().await;
+
+        let user_id = "test_ledger_user";
+        let recipient = "test_ledger_recipient";
+        let money = |value: &str, currency: &str| Money::new(BigDecimal::from_str(value).unwrap(), currency);
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            // every journal entry is checked by the database as soon as it is written
+            diesel::sql_query("set constraints posting_balanced immediate").execute(conn)?;
+            let revenue = Account::company(ledger::REVENUE, "EUR");
+            let revenue_before = ledger::account_balance(conn, &revenue)?;
+
+            top_up(
+                conn,
+                &curr,
+                "test_ledger_1",
+                user_id,
+                money("100", "USD"),
+                None,
+                Default::default(),
+            )?;
+            top_up(
+                conn,
+                &curr,
+                "test_ledger_2",
+                recipient,
+                money("10", "EUR"),
+                None,
+                Default::default(),
+            )?;
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                money("20", "EUR"),
+                "test_ledger_1",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            assert!(ledger::account_balance(conn, &Account::hold(user_id, "USD"))?.amount() > &BigDecimal::from(0));
+            let res = commit(
+                conn,
+                &curr,
+                user_id,
+                money("20", "EUR"),
+                "test_ledger_1",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, CommitResult::Ok(_)));
+            let res = reserve(
+                conn,
+                &curr,
+                user_id,
+                money("5", "USD"),
+                "test_ledger_2",
+                None,
+                None,
+                None,
+            )?;
+            assert_eq!(res, ReserveResult::Ok);
+            assert_eq!(cancel(conn, user_id, "test_ledger_2", None)?, CancelResult::Ok);
+            let res = transfer(
+                conn,
+                &curr,
+                "test_ledger_3",
+                user_id,
+                recipient,
+                money("30", "USD"),
+                Default::default(),
+            )?;
+            assert!(matches!(res, TransferResult::Ok(_)));
+
+            // balances are the projection of the ledger, nothing is left on hold
+            let drift: Vec<ledger::WalletDrift> = ledger::wallet_drift(conn)?
+                .into_iter()
+                .filter(|drift| drift.user_id == user_id || drift.user_id == recipient)
+                .collect();
+            assert_eq!(drift, vec![]);
+            assert_eq!(
+                ledger::account_balance(conn, &Account::hold(user_id, "USD"))?,
+                money("0", "USD")
+            );
+            assert_eq!(
+                ledger::account_balance(conn, &Account::wallet(user_id, "USD"))?,
+                ledger::wallet_value(conn, user_id, "USD")?
+            );
+            assert_eq!(
+                ledger::account_balance(conn, &revenue)?,
+                revenue_before + money("20", "EUR")
+            );
+
+            // unbalanced entry is rejected by the database
+            let res = conn.transaction::<_, Error, _>(|conn| {
+                use crate::schema::journal_entry::dsl::*;
+                diesel::insert_into(journal_entry)
+                    .values(&models::NewJournalEntry {
+                        id: 1,
+                        operation: "test".to_string(),
+                        transaction_id: None,
+                        order_id: None,
+                        item_id: None,
+                    })
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+                diesel::insert_into(crate::schema::posting::table)
+                    .values(&models::NewPosting {
+                        id: idgen::next(),
+                        entry_id: 1,
+                        account_kind: ledger::WALLET.to_string(),
+                        user_id: user_id.to_string(),
+                        currency: "USD".to_string(),
+                        value: BigDecimal::from(1),
+                    })
+                    .execute(conn)
+            });
+            assert!(res.is_err());
 
             Ok(())
         })
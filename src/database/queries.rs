@@ -1,68 +1,576 @@
+use crate::currency::CurrencyConverter;
 use crate::database::models;
+use crate::fx::FxRule;
+use crate::money::Money;
 use bigdecimal::BigDecimal;
-use diesel::{result::Error, Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl};
+use chrono::NaiveDateTime;
+use diesel::dsl::sql;
+use diesel::sql_types::{BigInt, Bool, Integer, Nullable, Numeric, Timestamp, Varchar};
+use diesel::{
+    result::Error, BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, PgAnyJsonExpressionMethods,
+    PgConnection, QueryDsl, QueryableByName, RunQueryDsl,
+};
+use std::collections::BTreeMap;
 
 #[derive(PartialEq, Debug)]
 pub enum UserBalance {
     Ok(UserBalanceValues),
     NotFound,
 }
+// totals are in the primary wallet currency
 #[derive(PartialEq, Debug)]
 pub struct UserBalanceValues {
     pub currency: String,
-    pub balance: BigDecimal,
-    pub reserved: BigDecimal,
+    pub balance: Money,
+    pub reserved: Money,
+    pub wallets: Vec<WalletValues>,
+}
+#[derive(PartialEq, Debug)]
+pub struct WalletValues {
+    pub currency: String,
+    pub balance: Money,
+    pub reserved: Money,
+    pub credit_limit: Money,
+    pub status: String,
 }
 
-pub fn load_balance(conn: &mut PgConnection, req_user_id: &str) -> Result<UserBalance, Error> {
+pub fn load_balance(
+    conn: &mut PgConnection,
+    curr: &CurrencyConverter,
+    req_user_id: &str,
+) -> Result<UserBalance, Error> {
     // wrap in transaction
     conn.transaction::<_, Error, _>(|conn| {
-        // load balance
-        let balance = {
+        // load wallets, the oldest one that is not closed is primary
+        let mut wallets = {
             use crate::schema::balance::dsl::*;
             balance
                 .filter(user_id.eq(req_user_id))
-                .first::<models::Balance>(conn)
-                .optional()
+                .order((created_at, currency))
+                .load::<models::Balance>(conn)?
         };
-        let balance = match balance {
-            Ok(Some(balance)) => balance,
-            Ok(None) => return Ok(UserBalance::NotFound),
-            Err(e) => return Err(e),
+        wallets.sort_by_key(|wallet| wallet.status == models::ACCOUNT_CLOSED);
+        let primary_currency = match wallets.first() {
+            Some(primary) => primary.currency.clone(),
+            None => return Ok(UserBalance::NotFound),
         };
-        // load reserved
-        let reserved = {
+        // load reserved per wallet
+        let reservations = {
             use crate::schema::balance_reserve::dsl::*;
             balance_reserve
                 .filter(user_id.eq(req_user_id))
-                .load::<models::BalanceReserve>(conn)
-                .map(|recs| {
-                    recs.into_iter()
-                        .fold(BigDecimal::from(0), |acc, rec| acc + rec.user_currency_value)
-                })
+                .load::<models::BalanceReserve>(conn)?
         };
-        let reserved = match reserved {
-            Ok(reserved) => reserved,
-            Err(e) => return Err(e),
+        // subtract reserved from balance of every wallet
+        let wallets: Vec<WalletValues> = wallets
+            .into_iter()
+            .map(|wallet| {
+                let reserved = reservations
+                    .iter()
+                    .filter(|rec| rec.wallet_currency == wallet.currency)
+                    .fold(Money::zero(wallet.currency.clone()), |acc, rec| acc + rec.held());
+                WalletValues {
+                    balance: &wallet.value() - &reserved,
+                    reserved,
+                    credit_limit: wallet.credit_limit(),
+                    currency: wallet.currency,
+                    status: wallet.status,
+                }
+            })
+            .collect();
+        let total = |value: fn(&WalletValues) -> &Money| {
+            wallets
+                .iter()
+                .try_fold(Money::zero(primary_currency.clone()), |acc, wallet| {
+                    Ok::<_, Error>(acc + curr.convert(value(wallet), &primary_currency)?)
+                })
         };
-        // subtract reserved from balance
         Ok(UserBalance::Ok(UserBalanceValues {
-            currency: balance.currency,
-            balance: balance.current_value - reserved.clone(),
-            reserved,
+            balance: total(|wallet| &wallet.balance)?,
+            reserved: total(|wallet| &wallet.reserved)?,
+            currency: primary_currency.clone(),
+            wallets,
         }))
     })
 }
 
+// wallet that is below zero
+#[derive(PartialEq, Debug)]
+pub struct Overdraft {
+    pub user_id: String,
+    pub balance: Money,
+    pub credit_limit: Money,
+    pub negative_since: NaiveDateTime,
+}
+
+// wallets below zero of all users, the longest overdrafts come first
+pub fn list_overdrafts(conn: &mut PgConnection) -> Result<Vec<Overdraft>, Error> {
+    use crate::schema::balance::dsl::*;
+    let wallets = balance
+        .filter(negative_since.is_not_null())
+        .order((negative_since, user_id, currency))
+        .load::<models::Balance>(conn)?;
+    Ok(wallets
+        .into_iter()
+        .filter_map(|wallet| {
+            Some(Overdraft {
+                balance: wallet.value(),
+                credit_limit: wallet.credit_limit(),
+                negative_since: wallet.negative_since?,
+                user_id: wallet.user_id,
+            })
+        })
+        .collect())
+}
+
+// currencies of all wallets and reservations
+pub fn held_currencies(conn: &mut PgConnection) -> Result<Vec<String>, Error> {
+    let mut currencies: Vec<String> = {
+        use crate::schema::balance::dsl::*;
+        balance.select(currency).distinct().load(conn)?
+    };
+    currencies.extend({
+        use crate::schema::balance_reserve::dsl::*;
+        balance_reserve.select(currency).distinct().load::<String>(conn)?
+    });
+    currencies.sort();
+    currencies.dedup();
+    Ok(currencies)
+}
+
+// wallets of all users
+pub fn list_wallets(conn: &mut PgConnection) -> Result<Vec<models::Balance>, Error> {
+    use crate::schema::balance::dsl::*;
+    balance.order((user_id, currency)).load::<models::Balance>(conn)
+}
+
+// transactions that changed user wallets in the order they were applied, ids grow with time
+pub fn wallet_transactions(conn: &mut PgConnection) -> Result<Vec<models::Transaction>, Error> {
+    use crate::schema::transaction::dsl::*;
+    transaction
+        .filter(sender_id.is_not_null().or(recipient_id.is_not_null()))
+        .order(id)
+        .load::<models::Transaction>(conn)
+}
+
+// values held by reservations per user wallet, keyed by (user_id, currency)
+pub fn wallet_reserved(conn: &mut PgConnection) -> Result<BTreeMap<(String, String), BigDecimal>, Error> {
+    use crate::schema::balance_reserve::dsl::*;
+    balance_reserve
+        .group_by((user_id, wallet_currency))
+        .select((user_id, wallet_currency, diesel::dsl::sum(user_currency_value)))
+        .load::<(String, String, Option<BigDecimal>)>(conn)
+        .map(|sums| {
+            sums.into_iter()
+                .map(|(sum_user_id, sum_currency, sum)| ((sum_user_id, sum_currency), sum.unwrap_or_default()))
+                .collect()
+        })
+}
+
+#[derive(QueryableByName, PartialEq, Debug)]
+pub struct ServiceRevenue {
+    #[diesel(sql_type = Varchar)]
+    pub item_id: String,
+    #[diesel(sql_type = Varchar)]
+    pub currency: String,
+    #[diesel(sql_type = Numeric)]
+    pub total: BigDecimal,
+}
+
+impl ServiceRevenue {
+    pub fn value(&self) -> Money {
+        Money::new(self.total.clone(), self.currency.clone())
+    }
+}
+
+// sums committed values per service (item_id) and transaction currency for the given month
+pub fn monthly_revenue(conn: &mut PgConnection, year: i32, month: i32) -> Result<Vec<ServiceRevenue>, Error> {
+    // the where clause matches transaction_order_data_item_id_index expression
+    diesel::sql_query(
+        "select order_data ->> 'item_id' as item_id, transaction_currency as currency, \
+                sum(transaction_value) as total \
+         from transaction \
+         where date_trunc('month', created_at) = make_timestamp($1, $2, 1, 0, 0, 0) \
+           and (order_data ->> 'item_id') is not null \
+         group by 1, 2 \
+         order by 1, 2",
+    )
+    .bind::<Integer, _>(year)
+    .bind::<Integer, _>(month)
+    .load::<ServiceRevenue>(conn)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TransactionsOrder {
+    DateDesc,
+    DateAsc,
+    AmountDesc,
+    AmountAsc,
+}
+
+// position of the last transaction on the previous page
+#[derive(Clone, PartialEq, Debug)]
+pub struct TransactionsPosition {
+    pub id: i64,
+    pub created_at: NaiveDateTime,
+    pub user_currency_value: BigDecimal,
+}
+
+#[derive(Debug)]
+pub struct TransactionsFilter {
+    pub user_id: String,
+    pub order: TransactionsOrder,
+    pub min_ts: Option<NaiveDateTime>,
+    pub max_ts: Option<NaiveDateTime>,
+    pub category: Option<String>,
+    pub after: Option<TransactionsPosition>,
+}
+
+pub struct TransactionsPage {
+    pub transactions: Vec<models::Transaction>,
+    pub has_more: bool,
+    pub total: i64,
+}
+
+// returns pagination position of the transaction from user's point of view
+pub fn transaction_position(tx: &models::Transaction, req_user_id: &str) -> TransactionsPosition {
+    let value = if tx.sender_id.as_deref() == Some(req_user_id) {
+        tx.sender_value.clone()
+    } else {
+        tx.recipient_value.clone()
+    };
+    TransactionsPosition {
+        id: tx.id,
+        created_at: tx.created_at,
+        user_currency_value: value.unwrap_or_default(),
+    }
+}
+
+// lists user's transactions (both sent and received) using keyset pagination
+pub fn list_transactions(
+    conn: &mut PgConnection,
+    filter: &TransactionsFilter,
+    limit: i64,
+) -> Result<TransactionsPage, Error> {
+    use crate::schema::transaction::dsl::*;
+
+    // sender_id/recipient_id conditions are served by (sender_id, created_at) and (recipient_id, created_at) indexes
+    let filtered = || {
+        let mut query = transaction
+            .filter(sender_id.eq(&filter.user_id).or(recipient_id.eq(&filter.user_id)))
+            .into_boxed();
+        if let Some(min_ts) = filter.min_ts {
+            query = query.filter(created_at.ge(min_ts));
+        }
+        if let Some(max_ts) = filter.max_ts {
+            query = query.filter(created_at.lt(max_ts));
+        }
+        if let Some(filter_category) = &filter.category {
+            query = query.filter(category.eq(filter_category));
+        }
+        query
+    };
+
+    // transaction value in user's balance currency: sender side for debits, recipient side for credits
+    let user_currency_value = || {
+        sql::<Nullable<Numeric>>("(case when sender_id = ")
+            .bind::<Varchar, _>(filter.user_id.clone())
+            .sql(" then sender_value else recipient_value end)")
+    };
+
+    conn.transaction::<_, Error, _>(|conn| {
+        let total = filtered().count().get_result::<i64>(conn)?;
+
+        let mut query = filtered();
+        // continue after the last seen row, (key, id) pairs are unique so no rows are skipped or repeated
+        if let Some(after) = &filter.after {
+            let op = match filter.order {
+                TransactionsOrder::DateDesc | TransactionsOrder::AmountDesc => " < (",
+                TransactionsOrder::DateAsc | TransactionsOrder::AmountAsc => " > (",
+            };
+            query = match filter.order {
+                TransactionsOrder::DateDesc | TransactionsOrder::DateAsc => query.filter(
+                    sql::<Bool>("(created_at, id)")
+                        .sql(op)
+                        .bind::<Timestamp, _>(after.created_at)
+                        .sql(", ")
+                        .bind::<BigInt, _>(after.id)
+                        .sql(")"),
+                ),
+                TransactionsOrder::AmountDesc | TransactionsOrder::AmountAsc => query.filter(
+                    sql::<Bool>("((case when sender_id = ")
+                        .bind::<Varchar, _>(filter.user_id.clone())
+                        .sql(" then sender_value else recipient_value end), id)")
+                        .sql(op)
+                        .bind::<Numeric, _>(after.user_currency_value.clone())
+                        .sql(", ")
+                        .bind::<BigInt, _>(after.id)
+                        .sql(")"),
+                ),
+            };
+        }
+        query = match filter.order {
+            TransactionsOrder::DateDesc => query.order((created_at.desc(), id.desc())),
+            TransactionsOrder::DateAsc => query.order((created_at.asc(), id.asc())),
+            TransactionsOrder::AmountDesc => query.order((user_currency_value().desc(), id.desc())),
+            TransactionsOrder::AmountAsc => query.order((user_currency_value().asc(), id.asc())),
+        };
+
+        // load one extra row to find out if there is a next page
+        let mut transactions = query.limit(limit + 1).load::<models::Transaction>(conn)?;
+        let has_more = transactions.len() as i64 > limit;
+        transactions.truncate(limit as usize);
+
+        Ok(TransactionsPage {
+            transactions,
+            has_more,
+            total,
+        })
+    })
+}
+
+pub fn load_client_by_key_hash(
+    conn: &mut PgConnection,
+    req_api_key_hash: &str,
+) -> Result<Option<models::Client>, Error> {
+    use crate::schema::clients::dsl::*;
+    clients
+        .filter(api_key_hash.eq(req_api_key_hash))
+        .first::<models::Client>(conn)
+        .optional()
+}
+
+pub fn load_client(conn: &mut PgConnection, req_client_id: &str) -> Result<Option<models::Client>, Error> {
+    use crate::schema::clients::dsl::*;
+    clients
+        .filter(id.eq(req_client_id))
+        .first::<models::Client>(conn)
+        .optional()
+}
+
+#[derive(PartialEq, Debug)]
+pub enum OrderStatus {
+    Unknown,
+    Reserved,
+    Committed,
+    Cancelled,
+    Expired,
+}
+
+#[derive(PartialEq, Debug)]
+pub struct OrderState {
+    pub item_id: String,
+    pub status: OrderStatus,
+    pub user_id: String,
+    // in the order currency
+    pub reserved_value: Money,
+    pub captured_value: Money,
+    pub expires_at: Option<NaiveDateTime>,
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+// current reservation or final state of every item of the order, empty for unknown orders
+pub fn load_order_state(conn: &mut PgConnection, req_order_id: &str) -> Result<Vec<OrderState>, Error> {
+    let mut items: BTreeMap<String, OrderState> = BTreeMap::new();
+
+    // items committed without reservation
+    let committed = {
+        use crate::schema::transaction::dsl::*;
+        transaction
+            .filter(order_data.retrieve_as_text("order_id").eq(req_order_id))
+            .order(id.asc())
+            .load::<models::Transaction>(conn)?
+    };
+    for tx in committed {
+        let tx_item_id = tx
+            .order_data
+            .as_ref()
+            .and_then(|data| data.get("item_id"))
+            .and_then(|value| value.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let item = items.entry(tx_item_id.clone()).or_insert_with(|| OrderState {
+            item_id: tx_item_id,
+            status: OrderStatus::Committed,
+            user_id: tx.sender_id.clone().unwrap_or_default(),
+            reserved_value: Money::zero(tx.transaction_currency.clone()),
+            captured_value: Money::zero(tx.transaction_currency.clone()),
+            expires_at: None,
+            finished_at: None,
+        });
+        item.captured_value += &tx.value();
+        item.finished_at = Some(tx.created_at);
+    }
+
+    let history = {
+        use crate::schema::order_history::dsl::*;
+        order_history
+            .filter(order_id.eq(req_order_id))
+            .order(finished_at.asc())
+            .load::<models::OrderHistory>(conn)?
+    };
+    for history in history {
+        let status = match history.state.as_str() {
+            models::ORDER_COMMITTED => OrderStatus::Committed,
+            models::ORDER_CANCELLED => OrderStatus::Cancelled,
+            models::ORDER_EXPIRED => OrderStatus::Expired,
+            _ => OrderStatus::Unknown,
+        };
+        items.insert(
+            history.item_id.clone(),
+            OrderState {
+                reserved_value: Money::zero(history.currency.clone()),
+                captured_value: history.captured(),
+                item_id: history.item_id,
+                status,
+                user_id: history.user_id,
+                expires_at: None,
+                finished_at: Some(history.finished_at),
+            },
+        );
+    }
+
+    let reservations = {
+        use crate::schema::balance_reserve::dsl::*;
+        balance_reserve
+            .filter(order_id.eq(req_order_id))
+            .load::<models::BalanceReserve>(conn)?
+    };
+    for reservation in reservations {
+        items.insert(
+            reservation.item_id.clone(),
+            OrderState {
+                reserved_value: reservation.reserved(),
+                captured_value: reservation.captured(),
+                item_id: reservation.item_id,
+                status: OrderStatus::Reserved,
+                user_id: reservation.user_id,
+                expires_at: reservation.expires_at,
+                finished_at: None,
+            },
+        );
+    }
+
+    Ok(items.into_values().collect())
+}
+
+#[derive(PartialEq, Debug)]
+pub enum HistoricalRate {
+    Ok {
+        snapshot_id: i64,
+        created_at: NaiveDateTime,
+        // value of one unit of the source currency in the target currency
+        rate: BigDecimal,
+    },
+    // no rates were loaded before the requested time
+    NoSnapshot,
+    UnknownCurrency(String),
+}
+
+// rate of the currency pair from the last snapshot loaded not later than req_at
+pub fn rate_at(
+    conn: &mut PgConnection,
+    req_from_currency: &str,
+    req_to_currency: &str,
+    req_at: NaiveDateTime,
+) -> Result<HistoricalRate, Error> {
+    use crate::schema::currency_rate::dsl::*;
+    let snapshot = currency_rate
+        .filter(created_at.le(req_at))
+        .order((created_at.desc(), snapshot_id.desc()))
+        .select((snapshot_id, base_currency, created_at))
+        .first::<(i64, String, NaiveDateTime)>(conn)
+        .optional()?;
+    let (req_snapshot_id, snapshot_base_currency, snapshot_created_at) = match snapshot {
+        Some(snapshot) => snapshot,
+        None => return Ok(HistoricalRate::NoSnapshot),
+    };
+    let rates: Vec<(String, BigDecimal)> = currency_rate
+        .filter(snapshot_id.eq(req_snapshot_id))
+        .filter(currency.eq_any([req_from_currency, req_to_currency]))
+        .select((currency, rate))
+        .load(conn)?;
+    // rates are relative to the base currency of the snapshot
+    let rate_of = |req_currency: &str| {
+        if req_currency == snapshot_base_currency {
+            return Some(BigDecimal::from(1));
+        }
+        rates
+            .iter()
+            .find(|(rate_currency, _)| rate_currency == req_currency)
+            .map(|(_, rate_value)| rate_value.clone())
+    };
+    let from_rate = match rate_of(req_from_currency) {
+        Some(from_rate) => from_rate,
+        None => return Ok(HistoricalRate::UnknownCurrency(req_from_currency.to_string())),
+    };
+    let to_rate = match rate_of(req_to_currency) {
+        Some(to_rate) => to_rate,
+        None => return Ok(HistoricalRate::UnknownCurrency(req_to_currency.to_string())),
+    };
+    Ok(HistoricalRate::Ok {
+        snapshot_id: req_snapshot_id,
+        created_at: snapshot_created_at,
+        rate: (to_rate / from_rate).with_scale(11).round(10).normalized(),
+    })
+}
+
+// the most specific rule for the pair wins: exact pair, then (from, *), then (*, to), then (*, *)
+pub fn load_fx_rule(conn: &mut PgConnection, req_from_currency: &str, req_to_currency: &str) -> Result<FxRule, Error> {
+    use crate::schema::fx_rule::dsl::*;
+    let rules: Vec<(String, String, BigDecimal, BigDecimal, BigDecimal)> = fx_rule
+        .filter(from_currency.eq_any([req_from_currency, "*"]))
+        .filter(to_currency.eq_any([req_to_currency, "*"]))
+        .select((from_currency, to_currency, spread, reserve_margin, max_overdraft))
+        .load(conn)?;
+    let rule = rules
+        .into_iter()
+        .max_by_key(|(rule_from, rule_to, ..)| (rule_from != "*", rule_to != "*"))
+        .map(|(_, _, rule_spread, rule_margin, rule_overdraft)| FxRule {
+            spread: rule_spread,
+            reserve_margin: rule_margin,
+            max_overdraft: rule_overdraft,
+        })
+        .unwrap_or_default();
+    // nothing is converted within the same currency, only the overdraft applies
+    if req_from_currency == req_to_currency {
+        return Ok(FxRule {
+            max_overdraft: rule.max_overdraft,
+            ..FxRule::default()
+        });
+    }
+    Ok(rule)
+}
+
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
-    use crate::database;
     use crate::database::mutations;
+    use crate::{currency, database};
     use bigdecimal::BigDecimal;
+    use chrono::Datelike;
     use diesel::result::Error;
     use diesel::Connection;
     use std::ops::DerefMut;
+    use std::str::FromStr;
+
+    // balance of a user with a single wallet
+    pub fn single_wallet(currency: &str, balance: BigDecimal, reserved: BigDecimal) -> UserBalance {
+        let balance = Money::new(balance, currency);
+        let reserved = Money::new(reserved, currency);
+        UserBalance::Ok(UserBalanceValues {
+            currency: currency.to_string(),
+            balance: balance.clone(),
+            reserved: reserved.clone(),
+            wallets: vec![WalletValues {
+                currency: currency.to_string(),
+                balance,
+                reserved,
+                credit_limit: Money::zero(currency),
+                status: models::ACCOUNT_ACTIVE.to_string(),
+            }],
+        })
+    }
 
     #[actix_web::test]
     async fn test_load_balance() {
@@ -83,21 +591,261 @@ mod tests {
                 &curr,
                 idempotency_key,
                 user_id,
-                currency,
-                value.clone(),
+                Money::new(value.clone(), currency),
                 merchant_data,
+                Default::default(),
             )?;
-            assert!(tx_id > 0);
+            assert!(matches!(tx_id, mutations::TopUpResult::Ok(id) if id > 0));
             // load balance
-            let balance = load_balance(conn.deref_mut(), user_id)?;
+            let balance = load_balance(conn.deref_mut(), &curr, user_id)?;
             assert_eq!(
                 balance,
-                UserBalance::Ok(UserBalanceValues {
-                    currency: currency.to_string(),
-                    balance: BigDecimal::from(100),
-                    reserved: BigDecimal::from(0),
-                })
+                single_wallet(currency, BigDecimal::from(100), BigDecimal::from(0))
+            );
+            Ok(())
+        });
+    }
+
+    #[actix_web::test]
+    async fn test_monthly_revenue() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+        let curr = crate::currency::create_currency_converter().await;
+        let user_id = "test_monthly_revenue";
+        let now = chrono::Utc::now();
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            mutations::top_up(
+                conn,
+                &curr,
+                user_id,
+                user_id,
+                Money::new(BigDecimal::from(100), "USD"),
+                None,
+                Default::default(),
+            )?;
+            for (order_id, item_id, value) in [
+                ("test_monthly_revenue_1", "test_revenue_a", 10),
+                ("test_monthly_revenue_2", "test_revenue_a", 15),
+                // second item of the first order is credited to its own service
+                ("test_monthly_revenue_1", "test_revenue_b", 20),
+            ] {
+                let res = mutations::commit(
+                    conn,
+                    &curr,
+                    user_id,
+                    Money::new(BigDecimal::from(value), "USD"),
+                    order_id,
+                    Some(item_id),
+                    None,
+                    false,
+                    None,
+                    Default::default(),
+                )?;
+                assert!(matches!(res, mutations::CommitResult::Ok(_)));
+            }
+
+            let revenue = monthly_revenue(conn, now.year(), now.month() as i32)?;
+            let revenue: Vec<_> = revenue
+                .into_iter()
+                .filter(|r| r.item_id.starts_with("test_revenue_"))
+                .collect();
+            assert_eq!(
+                revenue,
+                vec![
+                    ServiceRevenue {
+                        item_id: "test_revenue_a".to_string(),
+                        currency: "USD".to_string(),
+                        total: BigDecimal::from(25),
+                    },
+                    ServiceRevenue {
+                        item_id: "test_revenue_b".to_string(),
+                        currency: "USD".to_string(),
+                        total: BigDecimal::from(20),
+                    },
+                ]
+            );
+            Ok(())
+        });
+    }
+
+    #[actix_web::test]
+    async fn test_list_transactions() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+        let curr = crate::currency::create_currency_converter().await;
+        let user_id = "test_list_transactions";
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            for (idempotency_key, value) in [("test_list_1", 30), ("test_list_2", 10), ("test_list_3", 20)] {
+                mutations::top_up(
+                    conn,
+                    &curr,
+                    idempotency_key,
+                    user_id,
+                    Money::new(BigDecimal::from(value), "USD"),
+                    None,
+                    Default::default(),
+                )?;
+            }
+            let res = mutations::commit(
+                conn,
+                &curr,
+                user_id,
+                Money::new(BigDecimal::from(5), "USD"),
+                "test_list_order",
+                None,
+                None,
+                false,
+                None,
+                Default::default(),
+            )?;
+            assert!(matches!(res, mutations::CommitResult::Ok(_)));
+
+            // walks through all pages and collects user currency values
+            let mut collect_pages = |order: TransactionsOrder| -> Result<Vec<BigDecimal>, Error> {
+                let mut filter = TransactionsFilter {
+                    user_id: user_id.to_string(),
+                    order,
+                    min_ts: None,
+                    max_ts: None,
+                    category: None,
+                    after: None,
+                };
+                let mut values = vec![];
+                loop {
+                    let page = list_transactions(conn, &filter, 3)?;
+                    assert_eq!(page.total, 4);
+                    values.extend(
+                        page.transactions
+                            .iter()
+                            .map(|tx| transaction_position(tx, user_id).user_currency_value),
+                    );
+                    if !page.has_more {
+                        break;
+                    }
+                    filter.after = page.transactions.last().map(|tx| transaction_position(tx, user_id));
+                }
+                Ok(values)
+            };
+
+            let amount_desc = collect_pages(TransactionsOrder::AmountDesc)?;
+            assert_eq!(amount_desc, [30, 20, 10, 5].map(BigDecimal::from));
+            let amount_asc = collect_pages(TransactionsOrder::AmountAsc)?;
+            assert_eq!(amount_asc, [5, 10, 20, 30].map(BigDecimal::from));
+            let date_asc = collect_pages(TransactionsOrder::DateAsc)?;
+            assert_eq!(date_asc.len(), 4);
+            let mut date_desc = collect_pages(TransactionsOrder::DateDesc)?;
+            date_desc.reverse();
+            assert_eq!(date_desc, date_asc);
+
+            Ok(())
+        });
+    }
+
+    #[actix_web::test]
+    async fn test_rate_at() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let day = |month: u32, day: u32| {
+            chrono::NaiveDate::from_ymd_opt(2000, month, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        let snapshot = |snapshot_id: i64, created_at: NaiveDateTime, rates: &[(&str, &str)]| currency::Rates {
+            snapshot_id,
+            fetched_at: created_at,
+            base_currency: "EUR".to_string(),
+            rates: rates
+                .iter()
+                .map(|(rate_currency, rate)| (rate_currency.to_string(), BigDecimal::from_str(rate).unwrap()))
+                .collect(),
+        };
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            mutations::save_rates(conn, &snapshot(1, day(1, 1), &[("USD", "1.1"), ("GBP", "0.9")]))?;
+            mutations::save_rates(conn, &snapshot(2, day(2, 1), &[("USD", "1.2")]))?;
+
+            assert_eq!(
+                rate_at(conn, "USD", "GBP", day(1, 15))?,
+                HistoricalRate::Ok {
+                    snapshot_id: 1,
+                    created_at: day(1, 1),
+                    rate: BigDecimal::from_str("0.8181818182").unwrap(),
+                }
+            );
+            assert_eq!(
+                rate_at(conn, "EUR", "USD", day(2, 1))?,
+                HistoricalRate::Ok {
+                    snapshot_id: 2,
+                    created_at: day(2, 1),
+                    rate: BigDecimal::from_str("1.2").unwrap(),
+                }
+            );
+            assert_eq!(
+                rate_at(conn, "GBP", "EUR", day(2, 15))?,
+                HistoricalRate::UnknownCurrency("GBP".to_string())
             );
+            assert_eq!(
+                rate_at(conn, "EUR", "USD", day(1, 1) - chrono::Duration::days(1))?,
+                HistoricalRate::NoSnapshot
+            );
+
+            Ok(())
+        });
+    }
+
+    pub fn insert_fx_rule(conn: &mut PgConnection, from: &str, to: &str, rule: &[&str; 3]) -> Result<(), Error> {
+        use crate::schema::fx_rule::dsl::*;
+        diesel::insert_into(fx_rule)
+            .values((
+                from_currency.eq(from),
+                to_currency.eq(to),
+                spread.eq(BigDecimal::from_str(rule[0]).unwrap()),
+                reserve_margin.eq(BigDecimal::from_str(rule[1]).unwrap()),
+                max_overdraft.eq(BigDecimal::from_str(rule[2]).unwrap()),
+            ))
+            .on_conflict((from_currency, to_currency))
+            .do_update()
+            .set((
+                spread.eq(BigDecimal::from_str(rule[0]).unwrap()),
+                reserve_margin.eq(BigDecimal::from_str(rule[1]).unwrap()),
+                max_overdraft.eq(BigDecimal::from_str(rule[2]).unwrap()),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn test_load_fx_rule() {
+        dotenvy::dotenv().ok();
+
+        let conn = database::connect::create_db_connection_pool();
+
+        let rule = |rule: &[&str; 3]| FxRule {
+            spread: BigDecimal::from_str(rule[0]).unwrap(),
+            reserve_margin: BigDecimal::from_str(rule[1]).unwrap(),
+            max_overdraft: BigDecimal::from_str(rule[2]).unwrap(),
+        };
+
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            insert_fx_rule(conn, "*", "*", &["0.01", "0.05", "0"])?;
+            insert_fx_rule(conn, "USD", "*", &["0.02", "0.05", "0"])?;
+            insert_fx_rule(conn, "*", "EUR", &["0.03", "0.05", "5"])?;
+            insert_fx_rule(conn, "USD", "EUR", &["0.04", "0.1", "10"])?;
+
+            assert_eq!(load_fx_rule(conn, "USD", "EUR")?, rule(&["0.04", "0.1", "10"]));
+            assert_eq!(load_fx_rule(conn, "USD", "GBP")?, rule(&["0.02", "0.05", "0"]));
+            assert_eq!(load_fx_rule(conn, "GBP", "EUR")?, rule(&["0.03", "0.05", "5"]));
+            assert_eq!(load_fx_rule(conn, "GBP", "RUB")?, rule(&["0.01", "0.05", "0"]));
+            // same currency keeps only the overdraft
+            assert_eq!(load_fx_rule(conn, "EUR", "EUR")?, rule(&["0", "0", "5"]));
+
             Ok(())
         });
     }
@@ -0,0 +1,255 @@
+use std::fmt;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use tracing::error;
+
+use crate::currency::UnknownCurrency;
+use crate::responses;
+
+// clients that expect errors with 200 OK (as before status codes were introduced) send "X-Error-Status: 200"
+pub const ERROR_STATUS_HEADER: &str = "x-error-status";
+
+// errors of the http api, each one is a variant of Error.one_error in the response envelope
+#[derive(Debug)]
+pub enum ServiceError {
+    Unauthorized,
+    BadParameter(String),
+    UserNotFound,
+    NotEnoughMoney,
+    InvalidCurrency(String),
+    InvalidState,
+    InvalidQuote,
+    AccountBlocked,
+    Internal(anyhow::Error),
+}
+
+impl ServiceError {
+    pub fn bad_parameter(name: impl Into<String>) -> Self {
+        ServiceError::BadParameter(name.into())
+    }
+
+    pub fn invalid_currency(currency: &str) -> Self {
+        ServiceError::InvalidCurrency(currency.to_string())
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::Unauthorized => write!(f, "unauthorized"),
+            ServiceError::BadParameter(name) => write!(f, "bad parameter: {name}"),
+            ServiceError::UserNotFound => write!(f, "user not found"),
+            ServiceError::NotEnoughMoney => write!(f, "not enough money"),
+            ServiceError::InvalidCurrency(currency) => write!(f, "invalid currency: {currency}"),
+            ServiceError::InvalidState => write!(f, "invalid state"),
+            ServiceError::InvalidQuote => write!(f, "invalid quote"),
+            ServiceError::AccountBlocked => write!(f, "account blocked"),
+            ServiceError::Internal(e) => write!(f, "internal error: {e}"),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ServiceError {
+    fn from(e: anyhow::Error) -> Self {
+        ServiceError::Internal(e)
+    }
+}
+
+impl From<diesel::result::Error> for ServiceError {
+    fn from(e: diesel::result::Error) -> Self {
+        ServiceError::Internal(e.into())
+    }
+}
+
+impl From<UnknownCurrency> for ServiceError {
+    fn from(e: UnknownCurrency) -> Self {
+        ServiceError::Internal(e.into())
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for ServiceError {
+    fn from(e: diesel::r2d2::PoolError) -> Self {
+        ServiceError::Internal(e.into())
+    }
+}
+
+impl From<BlockingError> for ServiceError {
+    fn from(e: BlockingError) -> Self {
+        ServiceError::Internal(anyhow::anyhow!("{e}"))
+    }
+}
+
+impl ResponseError for ServiceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ServiceError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ServiceError::BadParameter(_) => StatusCode::BAD_REQUEST,
+            ServiceError::UserNotFound => StatusCode::NOT_FOUND,
+            ServiceError::NotEnoughMoney => StatusCode::PAYMENT_REQUIRED,
+            ServiceError::InvalidCurrency(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ServiceError::InvalidState => StatusCode::CONFLICT,
+            ServiceError::InvalidQuote => StatusCode::UNPROCESSABLE_ENTITY,
+            ServiceError::AccountBlocked => StatusCode::FORBIDDEN,
+            ServiceError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // json envelope, ErrorFormat middleware re-encodes it when protobuf or 200 OK is requested
+    fn error_response(&self) -> HttpResponse {
+        if let ServiceError::Internal(e) = self {
+            error!("{e}");
+        }
+        responses::error_http_response(self, false)
+    }
+}
+
+// encodes service errors according to Accept and X-Error-Status request headers
+pub struct ErrorFormat;
+
+impl<S, B> Transform<S, ServiceRequest> for ErrorFormat
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = ErrorFormatMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ErrorFormatMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct ErrorFormatMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for ErrorFormatMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let is_protobuf = responses::accepts_protobuf(req.headers());
+        let always_ok = req
+            .headers()
+            .get(ERROR_STATUS_HEADER)
+            .is_some_and(|value| value.as_bytes() == b"200");
+        let res = self.service.call(req);
+
+        Box::pin(async move {
+            let res = res.await?;
+            let replacement = match res.response().error().and_then(|e| e.as_error::<ServiceError>()) {
+                Some(err) if is_protobuf || always_ok => {
+                    let mut replacement = responses::error_http_response(err, is_protobuf);
+                    if always_ok {
+                        *replacement.status_mut() = StatusCode::OK;
+                    }
+                    Some(replacement)
+                }
+                _ => None,
+            };
+            match replacement {
+                Some(replacement) => Ok(res.into_response(replacement).map_into_right_body()),
+                None => Ok(res.map_into_left_body()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{error::OneError, GenericOutput};
+    use actix_web::http::header;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{get, App};
+    use prost::Message;
+
+    #[get("/not-enough-money")]
+    async fn failing_handler() -> Result<HttpResponse, ServiceError> {
+        Err(ServiceError::NotEnoughMoney)
+    }
+
+    #[actix_web::test]
+    async fn test_error_format() {
+        let app = init_service(App::new().wrap(ErrorFormat).service(failing_handler)).await;
+
+        let req = TestRequest::get().uri("/not-enough-money").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(
+            read_body(res).await,
+            "{\"error\":{\"oneError\":{\"notEnoughMoney\":{}}},\"userBalance\":null}"
+        );
+
+        let req = TestRequest::get()
+            .uri("/not-enough-money")
+            .insert_header((header::ACCEPT, "application/x-protobuf"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+        let output = GenericOutput::decode(read_body(res).await).unwrap();
+        assert!(matches!(
+            output.error.and_then(|e| e.one_error),
+            Some(OneError::NotEnoughMoney(_))
+        ));
+
+        // compatibility mode
+        let req = TestRequest::get()
+            .uri("/not-enough-money")
+            .insert_header((ERROR_STATUS_HEADER, "200"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            read_body(res).await,
+            "{\"error\":{\"oneError\":{\"notEnoughMoney\":{}}},\"userBalance\":null}"
+        );
+    }
+
+    #[test]
+    fn test_status_codes() {
+        assert_eq!(ServiceError::Unauthorized.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            ServiceError::bad_parameter("value").status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(ServiceError::UserNotFound.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(ServiceError::InvalidState.status_code(), StatusCode::CONFLICT);
+        assert_eq!(
+            ServiceError::InvalidCurrency("XXX".to_string()).status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            ServiceError::InvalidQuote.status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(ServiceError::AccountBlocked.status_code(), StatusCode::FORBIDDEN);
+        let err = ServiceError::Internal(anyhow::anyhow!("connection refused"));
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        // internal details are not sent to clients
+        let body = serde_json::to_string(&responses::error_output(&err)).unwrap();
+        assert_eq!(
+            body,
+            "{\"error\":{\"oneError\":{\"internal\":{}}},\"userBalance\":null}"
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::env;
+use std::ops::DerefMut;
+use std::time::Duration;
+
+use actix_web::web;
+use chrono::NaiveDateTime;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use once_cell::sync::Lazy;
+use tracing::{error, info};
+
+use crate::database::mutations;
+use crate::errors::ServiceError;
+
+// ttl of reservations that don't set one, by default reservations never expire
+static DEFAULT_TTL_SECONDS: Lazy<u32> = Lazy::new(|| env_number("RESERVATION_TTL_SECONDS", 0));
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("{name} must be a number")),
+        Err(_) => default,
+    }
+}
+
+// expiration time of a new reservation, ttl_seconds = 0 means the default ttl
+pub fn expires_at(ttl_seconds: u32, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let ttl_seconds = if ttl_seconds > 0 {
+        ttl_seconds
+    } else {
+        *DEFAULT_TTL_SECONDS
+    };
+    (ttl_seconds > 0).then(|| now + chrono::Duration::seconds(ttl_seconds.into()))
+}
+
+// how long the rate of a quote stays locked
+static QUOTE_TTL_SECONDS: Lazy<u32> = Lazy::new(|| env_number("QUOTE_TTL_SECONDS", 300));
+
+pub fn quote_expires_at(now: NaiveDateTime) -> NaiveDateTime {
+    now + chrono::Duration::seconds((*QUOTE_TTL_SECONDS).into())
+}
+
+// releases one batch of expired reservations
+async fn sweep(db: Pool<ConnectionManager<PgConnection>>, batch_size: i64) -> Result<usize, ServiceError> {
+    web::block(move || {
+        let mut conn = db.get()?;
+        let now = chrono::Utc::now().naive_utc();
+        Ok(mutations::expire_reservations(conn.deref_mut(), now, batch_size)?)
+    })
+    .await?
+}
+
+// starts background task that periodically releases expired reservations
+pub fn spawn_sweeper(db: Pool<ConnectionManager<PgConnection>>) {
+    let interval = Duration::from_secs(env_number("RESERVATION_SWEEP_INTERVAL_SECONDS", 60));
+    let batch_size: i64 = env_number("RESERVATION_SWEEP_BATCH_SIZE", 100);
+
+    actix_web::rt::spawn(async move {
+        let mut timer = actix_web::rt::time::interval(interval);
+        loop {
+            timer.tick().await;
+            // full batch means there may be more expired reservations
+            loop {
+                match sweep(db.clone(), batch_size).await {
+                    Ok(released) if released as i64 == batch_size => info!("released {released} expired reservations"),
+                    Ok(released) => {
+                        if released > 0 {
+                            info!("released {released} expired reservations");
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        error!("reservation sweeper failed: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expires_at() {
+        let now = chrono::NaiveDate::from_ymd_opt(2023, 1, 16)
+            .unwrap()
+            .and_hms_opt(9, 0, 0)
+            .unwrap();
+        assert_eq!(expires_at(90, now), Some(now + chrono::Duration::seconds(90)));
+    }
+}
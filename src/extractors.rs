@@ -0,0 +1,117 @@
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use prost::Message;
+use serde::de::DeserializeOwned;
+
+use crate::errors::ServiceError;
+
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+// request body decoded from protobuf or json depending on Content-Type header
+#[derive(Debug)]
+pub struct ProtoOrJson<T>(pub T);
+
+impl<T> Deref for ProtoOrJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for ProtoOrJson<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> FromRequest for ProtoOrJson<T>
+where
+    T: Message + Default + DeserializeOwned + 'static,
+{
+    // body that can't be read or decoded is reported as BadParameterError
+    type Error = ServiceError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let is_protobuf_body = req.content_type() == PROTOBUF_CONTENT_TYPE;
+        let body = web::Bytes::from_request(req, payload);
+
+        Box::pin(async move {
+            let body = body.await.map_err(|_| ServiceError::bad_parameter("body is invalid"))?;
+            let decoded = if is_protobuf_body {
+                T::decode(body).ok()
+            } else {
+                serde_json::from_slice::<T>(&body).ok()
+            };
+            decoded
+                .map(ProtoOrJson)
+                .ok_or_else(|| ServiceError::bad_parameter("body is invalid"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto;
+    use actix_web::body::MessageBody;
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+    use actix_web::ResponseError;
+
+    fn top_up_input() -> proto::TopUpInput {
+        proto::TopUpInput {
+            user_id: "test_user".to_string(),
+            currency: "USD".to_string(),
+            value: "100".to_string(),
+            merchant_data: "".to_string(),
+            idempotency_key: "test".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_protobuf_body() {
+        let (req, mut payload) = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE))
+            .set_payload(top_up_input().encode_to_vec())
+            .to_http_parts();
+        let input = ProtoOrJson::<proto::TopUpInput>::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        assert_eq!(input.0, top_up_input());
+    }
+
+    #[actix_web::test]
+    async fn test_json_body() {
+        let (req, mut payload) = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload(serde_json::to_vec(&top_up_input()).unwrap())
+            .to_http_parts();
+        let input = ProtoOrJson::<proto::TopUpInput>::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        assert_eq!(input.0, top_up_input());
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_body() {
+        let (req, mut payload) = TestRequest::post()
+            .insert_header((header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE))
+            .set_payload("{\"userId\": \"test_user\"}")
+            .to_http_parts();
+        let err = ProtoOrJson::<proto::TopUpInput>::from_request(&req, &mut payload)
+            .await
+            .unwrap_err();
+        let body = err.error_response().into_body().try_into_bytes().unwrap();
+        assert_eq!(
+            body,
+            "{\"error\":{\"oneError\":{\"badParameter\":{\"name\":\"body is invalid\"}}},\"userBalance\":null}"
+        );
+    }
+}
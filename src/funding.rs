@@ -0,0 +1,76 @@
+use std::env;
+
+use once_cell::sync::Lazy;
+
+// order in which user wallets are tried when paying for reservations, commits and transfers
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FundingStrategy {
+    // wallet in the requested currency, then the others with conversion starting from the primary one
+    ExactFirst,
+    // primary wallet with conversion, then the one in the requested currency, then the others
+    PrimaryFirst,
+}
+
+impl FundingStrategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "exact_first" => Some(FundingStrategy::ExactFirst),
+            "primary_first" => Some(FundingStrategy::PrimaryFirst),
+            _ => None,
+        }
+    }
+}
+
+// "exact_first" (default) or "primary_first"
+pub static FUNDING_STRATEGY: Lazy<FundingStrategy> = Lazy::new(|| match env::var("FUNDING_STRATEGY") {
+    Ok(name) => FundingStrategy::from_name(name.as_str())
+        .unwrap_or_else(|| panic!("FUNDING_STRATEGY must be exact_first or primary_first")),
+    Err(_) => FundingStrategy::ExactFirst,
+});
+
+// indexes of wallet currencies in the order they should be tried,
+// currencies are ordered by wallet creation so the first one is the primary wallet
+pub fn wallet_order(strategy: FundingStrategy, currencies: &[&str], currency: &str) -> Vec<usize> {
+    let exact = currencies
+        .iter()
+        .position(|wallet_currency| *wallet_currency == currency);
+    let first = match strategy {
+        FundingStrategy::ExactFirst => [exact, Some(0)],
+        FundingStrategy::PrimaryFirst => [Some(0), exact],
+    };
+    let mut order: Vec<usize> = Vec::with_capacity(currencies.len());
+    for idx in first.into_iter().flatten().chain(0..currencies.len()) {
+        if idx < currencies.len() && !order.contains(&idx) {
+            order.push(idx);
+        }
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wallet_order() {
+        let currencies = ["USD", "EUR", "RUB"];
+        assert_eq!(
+            wallet_order(FundingStrategy::ExactFirst, &currencies, "RUB"),
+            vec![2, 0, 1]
+        );
+        assert_eq!(
+            wallet_order(FundingStrategy::PrimaryFirst, &currencies, "RUB"),
+            vec![0, 2, 1]
+        );
+        assert_eq!(
+            wallet_order(FundingStrategy::ExactFirst, &currencies, "GBP"),
+            vec![0, 1, 2]
+        );
+        assert!(wallet_order(FundingStrategy::ExactFirst, &[], "GBP").is_empty());
+        assert_eq!(
+            FundingStrategy::from_name("primary_first"),
+            Some(FundingStrategy::PrimaryFirst)
+        );
+        assert_eq!(FundingStrategy::from_name("cheapest"), None);
+    }
+}
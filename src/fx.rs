@@ -0,0 +1,89 @@
+use bigdecimal::BigDecimal;
+
+use crate::money::Money;
+
+// conversion rules of a currency pair from fx_rule table, spread and margin are shares of converted value
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct FxRule {
+    // charged on top of debits and held back from credits, booked as spread income
+    pub spread: BigDecimal,
+    // held by reservations in addition to the charge in case the rate moves before commit
+    pub reserve_margin: BigDecimal,
+    // how far below zero commits and transfers may take the wallet, in wallet currency
+    pub max_overdraft: BigDecimal,
+}
+
+// value applied to the wallet and the spread income taken from it, both in wallet currency
+#[derive(PartialEq, Debug)]
+pub struct Conversion {
+    pub value: Money,
+    pub spread_income: Money,
+}
+
+impl FxRule {
+    // taken from the wallet for a value converted into its currency
+    pub fn debit(&self, converted: &Money) -> Conversion {
+        let value = (converted * &(BigDecimal::from(1) + &self.spread)).round();
+        let spread_income = &value - &converted.round();
+        Conversion { value, spread_income }
+    }
+
+    // added to the wallet for a value converted into its currency
+    pub fn credit(&self, converted: &Money) -> Conversion {
+        let value = (converted * &(BigDecimal::from(1) - &self.spread)).round();
+        let spread_income = &converted.round() - &value;
+        Conversion { value, spread_income }
+    }
+
+    // held by a reservation that is charged at the live rate on commit
+    pub fn reserve(&self, converted: &Money) -> Money {
+        (&self.debit(converted).value * &(BigDecimal::from(1) + &self.reserve_margin)).round()
+    }
+
+    pub fn allows_balance(&self, balance_after: &Money) -> bool {
+        *balance_after.amount() >= -self.max_overdraft.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_fx_rule() {
+        let rule = FxRule {
+            spread: BigDecimal::from_str("0.01").unwrap(),
+            reserve_margin: BigDecimal::from_str("0.05").unwrap(),
+            max_overdraft: BigDecimal::from(10),
+        };
+        let usd = |value: &str| Money::new(BigDecimal::from_str(value).unwrap(), "USD");
+        let converted = usd("100.004");
+        assert_eq!(
+            rule.debit(&converted),
+            Conversion {
+                value: usd("101.00"),
+                spread_income: usd("1.00"),
+            }
+        );
+        assert_eq!(
+            rule.credit(&converted),
+            Conversion {
+                value: usd("99.00"),
+                spread_income: usd("1.00"),
+            }
+        );
+        assert_eq!(rule.reserve(&converted), usd("106.05"));
+        // rounded to minor units of the wallet currency
+        assert_eq!(
+            rule.debit(&Money::new(BigDecimal::from(1234), "JPY")).value,
+            Money::new(BigDecimal::from(1246), "JPY")
+        );
+        assert!(rule.allows_balance(&usd("-10")));
+        assert!(!rule.allows_balance(&usd("-10.01")));
+
+        let same_currency = FxRule::default();
+        assert_eq!(same_currency.debit(&converted).spread_income, usd("0"));
+        assert_eq!(same_currency.reserve(&converted), usd("100.00"));
+    }
+}
@@ -0,0 +1,888 @@
+use std::net::SocketAddr;
+
+use actix_web::web;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use tonic::{Request, Response, Status};
+use tracing::{error, instrument};
+
+use crate::auth::{self, Scope};
+use crate::currency::CurrencyConverter;
+use crate::database::{mutations, queries};
+use crate::errors::ServiceError;
+use crate::money::Money;
+use crate::proto::balance_service_server::{BalanceService, BalanceServiceServer};
+use crate::proto::{
+    CancelReservationInput, ChangeAccountCurrencyInput, CommitReservationInput, ExchangeRateOutput, GenericOutput,
+    GetBalanceInput, GetExchangeRateInput, GetOrderStateInput, GetStatisticsInput, ListOverdraftsInput,
+    ListTransactionsInput, ListTransactionsOutput, OpenAccountInput, OrderStateOutput, OverdraftsOutput, QuoteInput,
+    QuoteOutput, ReconcileInput, ReconciliationOutput, ReserveInput, SetAccountStatusInput, SetCreditLimitInput,
+    StatisticsOutput, TopUpInput, TransferInput,
+};
+use crate::{cursor, expiry, reconcile, responses};
+
+// grpc counterpart of the http routes, shares the database layer and currency converter with them
+pub struct BalanceGrpcService {
+    db: Pool<ConnectionManager<PgConnection>>,
+    curr: CurrencyConverter,
+}
+
+impl BalanceGrpcService {
+    pub fn new(db: Pool<ConnectionManager<PgConnection>>, curr: CurrencyConverter) -> Self {
+        Self { db, curr }
+    }
+
+    // runs database queries on the blocking thread pool
+    async fn blocking<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut PgConnection, &CurrencyConverter) -> Result<T, diesel::result::Error> + Send + 'static,
+    {
+        let db = self.db.clone();
+        let curr = self.curr.clone();
+        web::block(move || {
+            let mut conn = db.get()?;
+            f(&mut conn, &curr).map_err(anyhow::Error::from)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|res| res)
+        .map_err(|e| {
+            error!("{e}");
+            Status::internal("internal error")
+        })
+    }
+
+    // grpc clients authenticate with "authorization: Bearer <api key>" metadata
+    async fn authorize<T>(&self, request: &Request<T>, scope: Option<Scope>) -> Result<(), Status> {
+        let api_key = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(auth::bearer_token)
+            .ok_or_else(|| Status::unauthenticated("api key is missing"))?;
+        let client = self
+            .blocking(move |conn, _| auth::authenticate_api_key(conn, api_key.as_str()))
+            .await?
+            .ok_or_else(|| Status::unauthenticated("api key is invalid"))?;
+        match scope {
+            Some(scope) if !auth::has_scope(&client, scope) => Err(Status::permission_denied(format!(
+                "client has no {} scope",
+                scope.as_str()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn optional_id(id: &str) -> Option<&str> {
+    if id.is_empty() {
+        None
+    } else {
+        Some(id)
+    }
+}
+
+// empty quote id means no quote, None for malformed ids
+fn parse_quote_id(quote_id: &str) -> Option<Option<i64>> {
+    match quote_id {
+        "" => Some(None),
+        quote_id => quote_id.parse().ok().map(Some),
+    }
+}
+
+fn bad_parameter(field: impl Into<String>) -> Response<GenericOutput> {
+    Response::new(responses::bad_parameter_output(field))
+}
+
+fn invalid_currency(currency: &str) -> Response<GenericOutput> {
+    Response::new(responses::invalid_currency_output(currency))
+}
+
+#[tonic::async_trait]
+impl BalanceService for BalanceGrpcService {
+    #[instrument(skip(self))]
+    async fn get_balance(&self, request: Request<GetBalanceInput>) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, None).await?;
+        let input = request.into_inner();
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id is empty"));
+        }
+
+        let user_id = input.user_id.clone();
+        let balance = self
+            .blocking(move |conn, curr| queries::load_balance(conn, curr, input.user_id.as_str()))
+            .await?;
+        Ok(Response::new(responses::user_balance_output(balance, user_id.as_str())))
+    }
+
+    #[instrument(skip(self))]
+    async fn top_up(&self, request: Request<TopUpInput>) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, Some(Scope::Billing)).await?;
+        let input = request.into_inner();
+        if input.idempotency_key.is_empty() {
+            return Ok(bad_parameter("idempotency_key"));
+        }
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id"));
+        }
+        if !self.curr.is_currency_valid(&input.currency) {
+            return Ok(invalid_currency(&input.currency));
+        }
+        let value = match Money::parse(input.value.as_str(), &input.currency) {
+            Some(value) => value,
+            None => return Ok(bad_parameter("value")),
+        };
+        if !input.merchant_data.is_empty()
+            && serde_json::from_str::<serde_json::Value>(input.merchant_data.as_str()).is_err()
+        {
+            return Ok(bad_parameter("merchant_data"));
+        }
+        if let Some(field) =
+            mutations::TransactionDetails::new(&input.description, &input.category, &input.source).invalid_field()
+        {
+            return Ok(bad_parameter(format!("{field} is invalid")));
+        }
+
+        let user_id = input.user_id.clone();
+        let res = self
+            .blocking(move |conn, curr| {
+                let res = mutations::top_up(
+                    conn,
+                    curr,
+                    input.idempotency_key.as_str(),
+                    input.user_id.as_str(),
+                    value,
+                    optional_id(input.merchant_data.as_str()),
+                    mutations::TransactionDetails::new(&input.description, &input.category, &input.source),
+                )?;
+                match res {
+                    mutations::TopUpResult::Ok(_) => queries::load_balance(conn, curr, input.user_id.as_str()).map(Ok),
+                    mutations::TopUpResult::AccountBlocked => Ok(Err(mutations::ReserveResult::AccountBlocked)),
+                }
+            })
+            .await?;
+        Ok(Response::new(match res {
+            Ok(balance) => responses::user_balance_output(balance, user_id.as_str()),
+            Err(res) => responses::reserve_error_output(res),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn reserve(&self, request: Request<ReserveInput>) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, Some(Scope::Service)).await?;
+        let input = request.into_inner();
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id is empty"));
+        }
+        if !self.curr.is_currency_valid(&input.currency) {
+            return Ok(invalid_currency(&input.currency));
+        }
+        let value = match Money::parse(input.value.as_str(), &input.currency) {
+            Some(value) => value,
+            None => return Ok(bad_parameter("value is invalid")),
+        };
+        if input.order_id.is_empty() {
+            return Ok(bad_parameter("order_id is empty"));
+        }
+        let quote_id = match parse_quote_id(input.quote_id.as_str()) {
+            Some(quote_id) => quote_id,
+            None => return Ok(bad_parameter("quote_id is invalid")),
+        };
+
+        let user_id = input.user_id.clone();
+        let res = self
+            .blocking(move |conn, curr| {
+                let res = mutations::reserve(
+                    conn,
+                    curr,
+                    input.user_id.as_str(),
+                    value,
+                    input.order_id.as_str(),
+                    optional_id(input.item_id.as_str()),
+                    expiry::expires_at(input.ttl_seconds, chrono::Utc::now().naive_utc()),
+                    quote_id,
+                )?;
+                match res {
+                    mutations::ReserveResult::Ok => queries::load_balance(conn, curr, input.user_id.as_str()).map(Ok),
+                    res => Ok(Err(res)),
+                }
+            })
+            .await?;
+        Ok(Response::new(match res {
+            Ok(balance) => responses::user_balance_output(balance, user_id.as_str()),
+            Err(res) => responses::reserve_error_output(res),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn cancel_reservation(
+        &self,
+        request: Request<CancelReservationInput>,
+    ) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, Some(Scope::Service)).await?;
+        let input = request.into_inner();
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id is empty"));
+        }
+        if input.order_id.is_empty() {
+            return Ok(bad_parameter("order_id is empty"));
+        }
+
+        let user_id = input.user_id.clone();
+        let res = self
+            .blocking(move |conn, curr| {
+                let res = mutations::cancel(
+                    conn,
+                    input.user_id.as_str(),
+                    input.order_id.as_str(),
+                    optional_id(input.item_id.as_str()),
+                )?;
+                match res {
+                    mutations::CancelResult::Ok => queries::load_balance(conn, curr, input.user_id.as_str()).map(Ok),
+                    mutations::CancelResult::UserNotFound => Ok(Err(mutations::ReserveResult::UserNotFound)),
+                    mutations::CancelResult::InvalidTransactionState => {
+                        Ok(Err(mutations::ReserveResult::InvalidTransactionState))
+                    }
+                }
+            })
+            .await?;
+        Ok(Response::new(match res {
+            Ok(balance) => responses::user_balance_output(balance, user_id.as_str()),
+            Err(res) => responses::reserve_error_output(res),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn commit_reservation(
+        &self,
+        request: Request<CommitReservationInput>,
+    ) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, Some(Scope::Service)).await?;
+        let input = request.into_inner();
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id is empty"));
+        }
+        if !self.curr.is_currency_valid(&input.currency) {
+            return Ok(invalid_currency(&input.currency));
+        }
+        let value = match Money::parse(input.value.as_str(), &input.currency) {
+            Some(value) => value,
+            None => return Ok(bad_parameter("value is invalid")),
+        };
+        if input.order_id.is_empty() {
+            return Ok(bad_parameter("order_id is empty"));
+        }
+        if input.keep_reservation && input.idempotency_key.is_empty() {
+            return Ok(bad_parameter("idempotency_key is empty"));
+        }
+        let quote_id = match parse_quote_id(input.quote_id.as_str()) {
+            Some(quote_id) => quote_id,
+            None => return Ok(bad_parameter("quote_id is invalid")),
+        };
+        if let Some(field) =
+            mutations::TransactionDetails::new(&input.description, &input.category, &input.source).invalid_field()
+        {
+            return Ok(bad_parameter(format!("{field} is invalid")));
+        }
+
+        let user_id = input.user_id.clone();
+        let res = self
+            .blocking(move |conn, curr| {
+                let res = mutations::commit(
+                    conn,
+                    curr,
+                    input.user_id.as_str(),
+                    value,
+                    input.order_id.as_str(),
+                    optional_id(input.item_id.as_str()),
+                    optional_id(input.idempotency_key.as_str()),
+                    input.keep_reservation,
+                    quote_id,
+                    mutations::TransactionDetails::new(&input.description, &input.category, &input.source),
+                )?;
+                let error = match res {
+                    mutations::CommitResult::Ok(_) => {
+                        return queries::load_balance(conn, curr, input.user_id.as_str()).map(Ok);
+                    }
+                    mutations::CommitResult::UserNotFound => mutations::ReserveResult::UserNotFound,
+                    mutations::CommitResult::InsufficientFunds => mutations::ReserveResult::InsufficientFunds,
+                    mutations::CommitResult::InvalidTransactionState => {
+                        mutations::ReserveResult::InvalidTransactionState
+                    }
+                    mutations::CommitResult::ItemRequired => {
+                        return Ok(Err(responses::bad_parameter_output("item_id is empty")));
+                    }
+                    mutations::CommitResult::InvalidQuote => mutations::ReserveResult::InvalidQuote,
+                    mutations::CommitResult::AccountBlocked => mutations::ReserveResult::AccountBlocked,
+                };
+                Ok(Err(responses::reserve_error_output(error)))
+            })
+            .await?;
+        Ok(Response::new(match res {
+            Ok(balance) => responses::user_balance_output(balance, user_id.as_str()),
+            Err(output) => output,
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn transfer(&self, request: Request<TransferInput>) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, Some(Scope::Billing)).await?;
+        let input = request.into_inner();
+        if input.idempotency_key.is_empty() {
+            return Ok(bad_parameter("idempotency_key"));
+        }
+        if input.sender_user_id.is_empty() {
+            return Ok(bad_parameter("sender_user_id"));
+        }
+        if input.recipient_user_id.is_empty() || input.recipient_user_id == input.sender_user_id {
+            return Ok(bad_parameter("recipient_user_id"));
+        }
+        if !self.curr.is_currency_valid(&input.currency) {
+            return Ok(invalid_currency(&input.currency));
+        }
+        let value = match Money::parse(input.value.as_str(), &input.currency) {
+            Some(value) => value,
+            None => return Ok(bad_parameter("value")),
+        };
+        if let Some(field) =
+            mutations::TransactionDetails::new(&input.description, &input.category, &input.source).invalid_field()
+        {
+            return Ok(bad_parameter(format!("{field} is invalid")));
+        }
+
+        let user_id = input.sender_user_id.clone();
+        let res = self
+            .blocking(move |conn, curr| {
+                let res = mutations::transfer(
+                    conn,
+                    curr,
+                    input.idempotency_key.as_str(),
+                    input.sender_user_id.as_str(),
+                    input.recipient_user_id.as_str(),
+                    value,
+                    mutations::TransactionDetails::new(&input.description, &input.category, &input.source),
+                )?;
+                match res {
+                    mutations::TransferResult::Ok(_) => {
+                        queries::load_balance(conn, curr, input.sender_user_id.as_str()).map(Ok)
+                    }
+                    mutations::TransferResult::UserNotFound => Ok(Err(mutations::ReserveResult::UserNotFound)),
+                    mutations::TransferResult::InsufficientFunds => {
+                        Ok(Err(mutations::ReserveResult::InsufficientFunds))
+                    }
+                    mutations::TransferResult::AccountBlocked => Ok(Err(mutations::ReserveResult::AccountBlocked)),
+                }
+            })
+            .await?;
+        Ok(Response::new(match res {
+            Ok(balance) => responses::user_balance_output(balance, user_id.as_str()),
+            Err(res) => responses::reserve_error_output(res),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_statistics(&self, request: Request<GetStatisticsInput>) -> Result<Response<StatisticsOutput>, Status> {
+        self.authorize(&request, Some(Scope::Accounting)).await?;
+        let input = request.into_inner();
+        let bad_parameter = |field: &str| {
+            Response::new(StatisticsOutput {
+                error: responses::bad_parameter_output(field).error,
+                ..Default::default()
+            })
+        };
+        let invalid_currency = |currency: &str| {
+            Response::new(StatisticsOutput {
+                error: responses::invalid_currency_output(currency).error,
+                ..Default::default()
+            })
+        };
+        if !(2000..=9999).contains(&input.year) {
+            return Ok(bad_parameter("year is invalid"));
+        }
+        if !(1..=12).contains(&input.month) {
+            return Ok(bad_parameter("month is invalid"));
+        }
+        let report_currency = match input.currency.as_str() {
+            "" => self.curr.base_currency(),
+            currency => currency.to_string(),
+        };
+        if !self.curr.is_currency_valid(&report_currency) {
+            return Ok(invalid_currency(&report_currency));
+        }
+
+        let revenue = self
+            .blocking(move |conn, _| queries::monthly_revenue(conn, input.year, input.month))
+            .await?;
+        let totals = responses::revenue_totals(revenue, &self.curr, report_currency.as_str()).map_err(|e| {
+            error!("{e}");
+            Status::internal("internal error")
+        })?;
+        Ok(Response::new(responses::statistics_output(
+            totals.into_iter().collect(),
+        )))
+    }
+
+    #[instrument(skip(self))]
+    async fn list_transactions(
+        &self,
+        request: Request<ListTransactionsInput>,
+    ) -> Result<Response<ListTransactionsOutput>, Status> {
+        self.authorize(&request, None).await?;
+        let input = request.into_inner();
+        let bad_parameter = |field: &str| {
+            Response::new(ListTransactionsOutput {
+                error: responses::bad_parameter_output(field).error,
+                ..Default::default()
+            })
+        };
+        if !(1..=100).contains(&input.limit) {
+            return Ok(bad_parameter("limit is invalid"));
+        }
+        let filter = if input.cursor.is_empty() {
+            if input.user_id.is_empty() {
+                return Ok(bad_parameter("user_id is empty"));
+            }
+            let order = match cursor::order_from_proto(input.order) {
+                Some(order) => order,
+                None => return Ok(bad_parameter("order is invalid")),
+            };
+            let min_ts = match input.min_ts.map(cursor::naive_utc) {
+                Some(None) => return Ok(bad_parameter("min_ts is invalid")),
+                min_ts => min_ts.flatten(),
+            };
+            let max_ts = match input.max_ts.map(cursor::naive_utc) {
+                Some(None) => return Ok(bad_parameter("max_ts is invalid")),
+                max_ts => max_ts.flatten(),
+            };
+            queries::TransactionsFilter {
+                user_id: input.user_id,
+                order,
+                min_ts,
+                max_ts,
+                category: Some(input.category).filter(|category| !category.is_empty()),
+                after: None,
+            }
+        } else {
+            match cursor::decode(input.cursor.as_str()) {
+                Some(filter) if input.user_id.is_empty() || input.user_id == filter.user_id => filter,
+                _ => return Ok(bad_parameter("cursor is invalid")),
+            }
+        };
+
+        let limit = input.limit as i64;
+        let (filter, balance, page) = self
+            .blocking(move |conn, curr| {
+                let balance = queries::load_balance(conn, curr, filter.user_id.as_str())?;
+                if balance == queries::UserBalance::NotFound {
+                    return Ok((filter, balance, None));
+                }
+                let page = queries::list_transactions(conn, &filter, limit)?;
+                Ok((filter, balance, Some(page)))
+            })
+            .await?;
+        Ok(Response::new(responses::transactions_output(balance, page, &filter)))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_order_state(
+        &self,
+        request: Request<GetOrderStateInput>,
+    ) -> Result<Response<OrderStateOutput>, Status> {
+        self.authorize(&request, Some(Scope::Service)).await?;
+        let input = request.into_inner();
+        if input.order_id.is_empty() {
+            return Ok(Response::new(OrderStateOutput {
+                error: responses::bad_parameter_output("order_id is empty").error,
+                ..Default::default()
+            }));
+        }
+
+        let order_id = input.order_id.clone();
+        let items = self
+            .blocking(move |conn, _| queries::load_order_state(conn, input.order_id.as_str()))
+            .await?;
+        Ok(Response::new(responses::order_state_output(items, order_id.as_str())))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_exchange_rate(
+        &self,
+        request: Request<GetExchangeRateInput>,
+    ) -> Result<Response<ExchangeRateOutput>, Status> {
+        self.authorize(&request, Some(Scope::Accounting)).await?;
+        let input = request.into_inner();
+        let error_output = |err: ServiceError| {
+            Response::new(ExchangeRateOutput {
+                error: responses::error_output(&err).error,
+                ..Default::default()
+            })
+        };
+        let at = match input.at.clone().map(cursor::naive_utc) {
+            Some(None) => return Ok(error_output(ServiceError::bad_parameter("at is invalid"))),
+            at => at.flatten().unwrap_or_else(|| chrono::Utc::now().naive_utc()),
+        };
+
+        let (from_currency, to_currency) = (input.from_currency.clone(), input.to_currency.clone());
+        let rate = self
+            .blocking(move |conn, _| queries::rate_at(conn, &from_currency, &to_currency, at))
+            .await?;
+        match responses::exchange_rate_output(rate, &input.from_currency, &input.to_currency) {
+            Ok(output) => Ok(Response::new(output)),
+            Err(err) => Ok(error_output(err)),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn create_quote(&self, request: Request<QuoteInput>) -> Result<Response<QuoteOutput>, Status> {
+        self.authorize(&request, Some(Scope::Service)).await?;
+        let input = request.into_inner();
+        let bad_parameter = |field: &str| {
+            Response::new(QuoteOutput {
+                error: responses::bad_parameter_output(field).error,
+                ..Default::default()
+            })
+        };
+        let invalid_currency = |currency: &str| {
+            Response::new(QuoteOutput {
+                error: responses::invalid_currency_output(currency).error,
+                ..Default::default()
+            })
+        };
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id is empty"));
+        }
+        if !self.curr.is_currency_valid(&input.from_currency) {
+            return Ok(invalid_currency(&input.from_currency));
+        }
+        if !self.curr.is_currency_valid(&input.to_currency) {
+            return Ok(invalid_currency(&input.to_currency));
+        }
+        let value = match Money::parse(input.value.as_str(), &input.from_currency) {
+            Some(value) => value,
+            None => return Ok(bad_parameter("value is invalid")),
+        };
+
+        let quote = self
+            .blocking(move |conn, curr| {
+                let now = chrono::Utc::now().naive_utc();
+                mutations::create_quote(
+                    conn,
+                    curr,
+                    input.user_id.as_str(),
+                    value,
+                    input.to_currency.as_str(),
+                    expiry::quote_expires_at(now),
+                )
+            })
+            .await?;
+        Ok(Response::new(responses::quote_output(quote)))
+    }
+
+    #[instrument(skip(self))]
+    async fn set_credit_limit(&self, request: Request<SetCreditLimitInput>) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, Some(Scope::Admin)).await?;
+        let input = request.into_inner();
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id"));
+        }
+        if !self.curr.is_currency_valid(&input.currency) {
+            return Ok(invalid_currency(&input.currency));
+        }
+        let credit_limit = match Money::parse_non_negative(input.credit_limit.as_str(), &input.currency) {
+            Some(credit_limit) => credit_limit,
+            None => return Ok(bad_parameter("credit_limit")),
+        };
+
+        let user_id = input.user_id.clone();
+        let balance = self
+            .blocking(move |conn, curr| {
+                // unknown user is reported by the balance query
+                mutations::set_credit_limit(conn, input.user_id.as_str(), credit_limit)?;
+                queries::load_balance(conn, curr, input.user_id.as_str())
+            })
+            .await?;
+        Ok(Response::new(responses::user_balance_output(balance, user_id.as_str())))
+    }
+
+    #[instrument(skip(self))]
+    async fn list_overdrafts(
+        &self,
+        request: Request<ListOverdraftsInput>,
+    ) -> Result<Response<OverdraftsOutput>, Status> {
+        self.authorize(&request, Some(Scope::Accounting)).await?;
+
+        let overdrafts = self.blocking(|conn, _| queries::list_overdrafts(conn)).await?;
+        let now = chrono::Utc::now().naive_utc();
+        Ok(Response::new(responses::overdrafts_output(overdrafts, now)))
+    }
+
+    #[instrument(skip(self))]
+    async fn reconcile(&self, request: Request<ReconcileInput>) -> Result<Response<ReconciliationOutput>, Status> {
+        self.authorize(&request, Some(Scope::Admin)).await?;
+
+        let (report, findings) = self.blocking(|conn, _| reconcile::reconcile(conn)).await?;
+        Ok(Response::new(responses::reconciliation_output(report, findings)))
+    }
+
+    #[instrument(skip(self))]
+    async fn open_account(&self, request: Request<OpenAccountInput>) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, Some(Scope::Billing)).await?;
+        let input = request.into_inner();
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id"));
+        }
+        if !self.curr.is_currency_valid(&input.currency) {
+            return Ok(invalid_currency(&input.currency));
+        }
+
+        let user_id = input.user_id.clone();
+        let res = self
+            .blocking(move |conn, curr| {
+                match mutations::open_account(conn, input.user_id.as_str(), input.currency.as_str())? {
+                    mutations::OpenAccountResult::Ok => {
+                        queries::load_balance(conn, curr, input.user_id.as_str()).map(Ok)
+                    }
+                    mutations::OpenAccountResult::AlreadyExists => {
+                        Ok(Err(mutations::ReserveResult::InvalidTransactionState))
+                    }
+                }
+            })
+            .await?;
+        Ok(Response::new(match res {
+            Ok(balance) => responses::user_balance_output(balance, user_id.as_str()),
+            Err(res) => responses::reserve_error_output(res),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn set_account_status(
+        &self,
+        request: Request<SetAccountStatusInput>,
+    ) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, Some(Scope::Admin)).await?;
+        let input = request.into_inner();
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id"));
+        }
+        if !self.curr.is_currency_valid(&input.currency) {
+            return Ok(invalid_currency(&input.currency));
+        }
+        let status = match responses::account_status_from_proto(input.status) {
+            Some(status) => status,
+            None => return Ok(bad_parameter("status")),
+        };
+        if input.reason.is_empty() {
+            return Ok(bad_parameter("reason"));
+        }
+        if let Some(field) =
+            mutations::TransactionDetails::new(&input.description, &input.category, &input.source).invalid_field()
+        {
+            return Ok(bad_parameter(format!("{field} is invalid")));
+        }
+
+        let user_id = input.user_id.clone();
+        let res = self
+            .blocking(move |conn, curr| {
+                let res = mutations::set_account_status(
+                    conn,
+                    curr,
+                    input.user_id.as_str(),
+                    input.currency.as_str(),
+                    status,
+                    input.reason.as_str(),
+                    optional_id(input.payout_user_id.as_str()),
+                    mutations::TransactionDetails::new(&input.description, &input.category, &input.source),
+                )?;
+                match res {
+                    mutations::AccountStatusResult::Ok => {
+                        queries::load_balance(conn, curr, input.user_id.as_str()).map(Ok)
+                    }
+                    mutations::AccountStatusResult::UserNotFound => Ok(Err(mutations::ReserveResult::UserNotFound)),
+                    mutations::AccountStatusResult::InvalidTransactionState => {
+                        Ok(Err(mutations::ReserveResult::InvalidTransactionState))
+                    }
+                    mutations::AccountStatusResult::AccountBlocked => Ok(Err(mutations::ReserveResult::AccountBlocked)),
+                }
+            })
+            .await?;
+        Ok(Response::new(match res {
+            Ok(balance) => responses::user_balance_output(balance, user_id.as_str()),
+            Err(res) => responses::reserve_error_output(res),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn change_account_currency(
+        &self,
+        request: Request<ChangeAccountCurrencyInput>,
+    ) -> Result<Response<GenericOutput>, Status> {
+        self.authorize(&request, Some(Scope::Admin)).await?;
+        let input = request.into_inner();
+        if input.user_id.is_empty() {
+            return Ok(bad_parameter("user_id"));
+        }
+        if !self.curr.is_currency_valid(&input.currency) {
+            return Ok(invalid_currency(&input.currency));
+        }
+        if !self.curr.is_currency_valid(&input.to_currency) {
+            return Ok(invalid_currency(&input.to_currency));
+        }
+        if input.to_currency == input.currency {
+            return Ok(bad_parameter("to_currency"));
+        }
+        if input.reason.is_empty() {
+            return Ok(bad_parameter("reason"));
+        }
+        if let Some(field) =
+            mutations::TransactionDetails::new(&input.description, &input.category, &input.source).invalid_field()
+        {
+            return Ok(bad_parameter(format!("{field} is invalid")));
+        }
+
+        let user_id = input.user_id.clone();
+        let res = self
+            .blocking(move |conn, curr| {
+                let res = mutations::change_currency(
+                    conn,
+                    curr,
+                    input.user_id.as_str(),
+                    input.currency.as_str(),
+                    input.to_currency.as_str(),
+                    input.reason.as_str(),
+                    mutations::TransactionDetails::new(&input.description, &input.category, &input.source),
+                )?;
+                match res {
+                    mutations::ChangeCurrencyResult::Ok(_) => {
+                        queries::load_balance(conn, curr, input.user_id.as_str()).map(Ok)
+                    }
+                    mutations::ChangeCurrencyResult::UserNotFound => Ok(Err(mutations::ReserveResult::UserNotFound)),
+                    mutations::ChangeCurrencyResult::InvalidTransactionState => {
+                        Ok(Err(mutations::ReserveResult::InvalidTransactionState))
+                    }
+                    mutations::ChangeCurrencyResult::AccountBlocked => {
+                        Ok(Err(mutations::ReserveResult::AccountBlocked))
+                    }
+                }
+            })
+            .await?;
+        Ok(Response::new(match res {
+            Ok(balance) => responses::user_balance_output(balance, user_id.as_str()),
+            Err(res) => responses::reserve_error_output(res),
+        }))
+    }
+}
+
+pub async fn serve(
+    addr: SocketAddr,
+    db: Pool<ConnectionManager<PgConnection>>,
+    curr: CurrencyConverter,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(BalanceServiceServer::new(BalanceGrpcService::new(db, curr)))
+        .serve(addr)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::create_test_client;
+    use crate::proto::error::OneError;
+    use crate::{currency, database};
+
+    fn authorized<T>(message: T, api_key: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert("authorization", format!("Bearer {api_key}").parse().unwrap());
+        request
+    }
+
+    #[actix_web::test]
+    async fn test_get_balance() {
+        dotenvy::dotenv().ok();
+
+        let db = database::connect::create_test_db_connection_pool();
+        let api_key = create_test_client(&mut db.get().unwrap(), "test_grpc_get_balance", &[]);
+        let service = BalanceGrpcService::new(db, currency::create_currency_converter().await);
+
+        let res = service
+            .get_balance(authorized(
+                GetBalanceInput {
+                    user_id: "test_grpc_unknown_user".to_string(),
+                },
+                api_key.as_str(),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(matches!(
+            res.error.and_then(|e| e.one_error),
+            Some(OneError::UserNotFound(_))
+        ));
+
+        let res = service
+            .get_balance(authorized(GetBalanceInput::default(), api_key.as_str()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(matches!(
+            res.error.and_then(|e| e.one_error),
+            Some(OneError::BadParameter(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_top_up_validation() {
+        dotenvy::dotenv().ok();
+
+        let db = database::connect::create_test_db_connection_pool();
+        let api_key = create_test_client(&mut db.get().unwrap(), "test_grpc_top_up", &[Scope::Billing]);
+        let service = BalanceGrpcService::new(db, currency::create_currency_converter().await);
+
+        let res = service
+            .top_up(authorized(
+                TopUpInput {
+                    user_id: "test_grpc_user".to_string(),
+                    currency: "XXX".to_string(),
+                    value: "10".to_string(),
+                    merchant_data: "".to_string(),
+                    idempotency_key: "test_grpc".to_string(),
+                    ..Default::default()
+                },
+                api_key.as_str(),
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        match res.error.and_then(|e| e.one_error) {
+            Some(OneError::InvalidCurrency(err)) => assert_eq!(err.currency, "XXX"),
+            err => panic!("unexpected error: {err:?}"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_authorization() {
+        dotenvy::dotenv().ok();
+
+        let db = database::connect::create_test_db_connection_pool();
+        let api_key = create_test_client(&mut db.get().unwrap(), "test_grpc_authorization", &[Scope::Service]);
+        let service = BalanceGrpcService::new(db, currency::create_currency_converter().await);
+
+        let status = service.top_up(Request::new(TopUpInput::default())).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = service
+            .top_up(authorized(TopUpInput::default(), "wrong key"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = service
+            .top_up(authorized(TopUpInput::default(), api_key.as_str()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+}
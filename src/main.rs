@@ -9,11 +9,22 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::Registry;
 
 use crate::database::connect::{create_db_connection_pool, run_migrations};
-use crate::routes::{balance_handler, commit_handler, reserve_handler, top_up_handler};
 
+mod auth;
 mod currency;
+mod cursor;
 mod database;
+mod errors;
+mod expiry;
+mod extractors;
+mod funding;
+mod fx;
+mod grpc;
+mod money;
+mod openapi;
 mod proto;
+mod rates;
+mod reconcile;
 mod responses;
 mod routes;
 mod schema;
@@ -33,20 +44,47 @@ async fn main() {
     let db = create_db_connection_pool();
     run_migrations(&db);
 
+    // releases expired reservations in background
+    expiry::spawn_sweeper(db.clone());
+    // checks balances against transactions, the ledger and reservations
+    reconcile::spawn_reconciler(db.clone());
+
     let currency_converter = currency::create_currency_converter().await;
+    // rates in use are kept in history for audit
+    rates::store(db.clone(), currency_converter.snapshot())
+        .await
+        .expect("Failed to store currency rates");
+    rates::check_held_currencies(db.clone(), currency_converter.snapshot())
+        .await
+        .expect("Currency rates don't cover wallets and reservations");
+    rates::spawn_refresher(db.clone(), currency_converter.clone(), rates::provider_from_env());
+
+    // grpc server is optional and runs next to the http one
+    if let Ok(grpc_bind_address) = env::var("GRPC_BIND_ADDRESS") {
+        let grpc_server = grpc::serve(
+            grpc_bind_address
+                .parse()
+                .expect("GRPC_BIND_ADDRESS must be a socket address"),
+            db.clone(),
+            currency_converter.clone(),
+        );
+        actix_web::rt::spawn(async move {
+            if let Err(e) = grpc_server.await {
+                tracing::error!("grpc server failed: {e}");
+            }
+        });
+    }
 
     let server = actix_web::HttpServer::new(move || {
         let db = db.clone();
 
         actix_web::App::new()
+            .wrap(errors::ErrorFormat)
             .wrap(RequestIdentifier::with_uuid().use_incoming_id(IdReuse::UseIncoming))
             .wrap(actix_web::middleware::Logger::default())
             .app_data(Data::new(db.clone()))
             .app_data(Data::new(currency_converter.clone()))
-            .service(balance_handler)
-            .service(top_up_handler)
-            .service(reserve_handler)
-            .service(commit_handler)
+            .configure(routes::configure)
     });
 
     server
@@ -0,0 +1,182 @@
+use bigdecimal::{BigDecimal, Signed, Zero};
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub};
+use std::str::FromStr;
+
+use crate::currency;
+
+// amount in a currency, arithmetic and comparison are defined only for amounts of the same currency,
+// the amount changes currency only through CurrencyConverter
+#[derive(Clone, Debug, PartialEq)]
+pub struct Money {
+    amount: BigDecimal,
+    currency: String,
+}
+
+impl Money {
+    pub fn new(amount: BigDecimal, currency: impl Into<String>) -> Self {
+        Self {
+            amount,
+            currency: currency.into(),
+        }
+    }
+
+    pub fn zero(currency: impl Into<String>) -> Self {
+        Self::new(BigDecimal::from(0), currency)
+    }
+
+    // positive amount in the proto string format with no more digits after dot than the currency has
+    pub fn parse(value: &str, currency: &str) -> Option<Self> {
+        BigDecimal::from_str(value)
+            .ok()
+            .filter(|amount| currency::is_value_valid(amount, currency))
+            .map(|amount| Self::new(amount, currency))
+    }
+
+    // same as parse, but zero is accepted too, e.g. for limits where zero turns the limit off
+    pub fn parse_non_negative(value: &str, currency: &str) -> Option<Self> {
+        BigDecimal::from_str(value)
+            .ok()
+            .filter(|amount| amount.is_zero() || currency::is_value_valid(amount, currency))
+            .map(|amount| Self::new(amount, currency))
+    }
+
+    pub fn amount(&self) -> &BigDecimal {
+        &self.amount
+    }
+
+    pub fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    pub fn into_amount(self) -> BigDecimal {
+        self.amount
+    }
+
+    // rounded to minor units of the currency
+    pub fn round(&self) -> Self {
+        Self::new(
+            currency::round_value(&self.amount, &self.currency),
+            self.currency.clone(),
+        )
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.amount.is_negative()
+    }
+
+    fn assert_same_currency(&self, other: &Money) {
+        assert_eq!(
+            self.currency, other.currency,
+            "mixed currency arithmetic: {} and {}",
+            self.currency, other.currency
+        );
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&currency::format_value(&self.amount, &self.currency))
+    }
+}
+
+impl Add<&Money> for &Money {
+    type Output = Money;
+
+    fn add(self, other: &Money) -> Money {
+        self.assert_same_currency(other);
+        Money::new(&self.amount + &other.amount, self.currency.clone())
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        &self + &other
+    }
+}
+
+impl AddAssign<&Money> for Money {
+    fn add_assign(&mut self, other: &Money) {
+        self.assert_same_currency(other);
+        self.amount += &other.amount;
+    }
+}
+
+impl Sub<&Money> for &Money {
+    type Output = Money;
+
+    fn sub(self, other: &Money) -> Money {
+        self.assert_same_currency(other);
+        Money::new(&self.amount - &other.amount, self.currency.clone())
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        &self - &other
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money::new(-self.amount, self.currency)
+    }
+}
+
+// share of the amount, not rounded
+impl Mul<&BigDecimal> for &Money {
+    type Output = Money;
+
+    fn mul(self, factor: &BigDecimal) -> Money {
+        Money::new(&self.amount * factor, self.currency.clone())
+    }
+}
+
+impl PartialOrd for Money {
+    fn partial_cmp(&self, other: &Money) -> Option<Ordering> {
+        self.assert_same_currency(other);
+        self.amount.partial_cmp(&other.amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_money() {
+        let usd = |value: &str| Money::new(BigDecimal::from_str(value).unwrap(), "USD");
+
+        assert_eq!(Money::parse("10.25", "USD"), Some(usd("10.25")));
+        assert_eq!(Money::parse("10.5", "JPY"), None);
+        assert_eq!(Money::parse("-1", "USD"), None);
+        assert_eq!(Money::parse("abc", "USD"), None);
+        assert_eq!(Money::parse("0", "USD"), None);
+        assert_eq!(Money::parse_non_negative("0", "USD"), Some(usd("0")));
+        assert_eq!(Money::parse_non_negative("-1", "USD"), None);
+
+        assert_eq!(usd("10.25") + usd("0.75"), usd("11"));
+        assert_eq!(usd("10.25") - usd("20"), usd("-9.75"));
+        assert!(usd("1") < usd("1.01"));
+        assert!((usd("1") - usd("2")).is_negative());
+        assert_eq!(
+            (&usd("10") * &BigDecimal::from_str("0.333").unwrap()).round(),
+            usd("3.33")
+        );
+        assert_eq!(usd("7").to_string(), "7.00");
+        assert_eq!(Money::zero("JPY").to_string(), "0");
+    }
+
+    #[test]
+    #[should_panic(expected = "mixed currency arithmetic")]
+    fn test_mixed_currency() {
+        let _ = Money::new(BigDecimal::from(1), "USD") + Money::new(BigDecimal::from(1), "EUR");
+    }
+}
@@ -0,0 +1,930 @@
+use std::collections::BTreeSet;
+
+use once_cell::sync::Lazy;
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+use crate::auth::Scope;
+use crate::proto;
+
+const TIMESTAMP_TYPE: &str = ".google.protobuf.Timestamp";
+
+// status codes of ServiceError variants
+const ERROR_RESPONSES: &[(&str, &str)] = &[
+    ("400", "bad parameter"),
+    ("401", "missing or invalid credentials"),
+    ("402", "not enough money"),
+    ("403", "account is frozen or closed for the operation"),
+    ("404", "user not found"),
+    ("409", "order is already processed or can't be changed"),
+    (
+        "422",
+        "currency is not supported or quote is expired or doesn't match the request",
+    ),
+    ("500", "internal error"),
+];
+
+pub struct Parameter {
+    pub name: &'static str,
+    // "path" or "query"
+    pub location: &'static str,
+    pub schema_type: &'static str,
+    pub description: &'static str,
+}
+
+pub struct RequestBody {
+    pub message: &'static str,
+    // checks that json is decoded by the handler, used to find required fields
+    pub accepts: fn(&Value) -> bool,
+}
+
+fn accepts<T: DeserializeOwned>(value: &Value) -> bool {
+    serde_json::from_value::<T>(value.clone()).is_ok()
+}
+
+// http api operations, tests check them against the handlers registered in routes.rs
+pub struct Operation {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub summary: &'static str,
+    pub scope: Option<Scope>,
+    pub parameters: &'static [Parameter],
+    pub request: Option<RequestBody>,
+    pub response: &'static str,
+    // report can be downloaded as csv
+    pub csv: bool,
+}
+
+pub const OPERATIONS: &[Operation] = &[
+    Operation {
+        method: "get",
+        path: "/balance/{user_id}",
+        summary: "Get user balance",
+        scope: None,
+        parameters: &[Parameter {
+            name: "user_id",
+            location: "path",
+            schema_type: "string",
+            description: "user id",
+        }],
+        request: None,
+        response: "GenericOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/top-up",
+        summary: "Add money to user balance, creates the balance on first top-up",
+        scope: Some(Scope::Billing),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "TopUpInput",
+            accepts: accepts::<proto::TopUpInput>,
+        }),
+        response: "GenericOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/reserve",
+        summary: "Reserve money for an order",
+        scope: Some(Scope::Service),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "ReserveInput",
+            accepts: accepts::<proto::ReserveInput>,
+        }),
+        response: "GenericOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/commit",
+        summary: "Charge reserved money for an order, whole or in several captures",
+        scope: Some(Scope::Service),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "CommitReservationInput",
+            accepts: accepts::<proto::CommitReservationInput>,
+        }),
+        response: "GenericOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/cancel",
+        summary: "Cancel reservation or release what is left of it after captures",
+        scope: Some(Scope::Service),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "CancelReservationInput",
+            accepts: accepts::<proto::CancelReservationInput>,
+        }),
+        response: "GenericOutput",
+        csv: false,
+    },
+    Operation {
+        method: "get",
+        path: "/statistics/{year}/{month}",
+        summary: "Monthly revenue per service",
+        scope: Some(Scope::Accounting),
+        parameters: &[
+            Parameter {
+                name: "year",
+                location: "path",
+                schema_type: "integer",
+                description: "report year",
+            },
+            Parameter {
+                name: "month",
+                location: "path",
+                schema_type: "integer",
+                description: "report month, 1 to 12",
+            },
+            Parameter {
+                name: "currency",
+                location: "query",
+                schema_type: "string",
+                description: "report currency, defaults to base currency of the rates",
+            },
+            Parameter {
+                name: "format",
+                location: "query",
+                schema_type: "string",
+                description: "\"csv\" to download report as a file",
+            },
+        ],
+        request: None,
+        response: "StatisticsOutput",
+        csv: true,
+    },
+    Operation {
+        method: "post",
+        path: "/transactions",
+        summary: "User transaction history, optionally of one category, paginated with a cursor",
+        scope: None,
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "ListTransactionsInput",
+            accepts: accepts::<proto::ListTransactionsInput>,
+        }),
+        response: "ListTransactionsOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/transfer",
+        summary: "Transfer money between users, converting currency if needed",
+        scope: Some(Scope::Billing),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "TransferInput",
+            accepts: accepts::<proto::TransferInput>,
+        }),
+        response: "GenericOutput",
+        csv: false,
+    },
+    Operation {
+        method: "get",
+        path: "/order/{order_id}",
+        summary: "Current reservation or final state of every item of an order: committed, cancelled or expired",
+        scope: Some(Scope::Service),
+        parameters: &[Parameter {
+            name: "order_id",
+            location: "path",
+            schema_type: "string",
+            description: "order id",
+        }],
+        request: None,
+        response: "OrderStateOutput",
+        csv: false,
+    },
+    Operation {
+        method: "get",
+        path: "/rates/{from_currency}/{to_currency}",
+        summary: "Exchange rate that applied to a currency pair at the given time",
+        scope: Some(Scope::Accounting),
+        parameters: &[
+            Parameter {
+                name: "from_currency",
+                location: "path",
+                schema_type: "string",
+                description: "source currency",
+            },
+            Parameter {
+                name: "to_currency",
+                location: "path",
+                schema_type: "string",
+                description: "target currency",
+            },
+            Parameter {
+                name: "at",
+                location: "query",
+                schema_type: "string",
+                description: "RFC 3339 time, defaults to now",
+            },
+        ],
+        request: None,
+        response: "ExchangeRateOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/quote",
+        summary: "Lock exchange rate for reserve and commit requests of the user that pass the quote id",
+        scope: Some(Scope::Service),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "QuoteInput",
+            accepts: accepts::<proto::QuoteInput>,
+        }),
+        response: "QuoteOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/credit-limit",
+        summary: "Set how far below zero a user wallet may go on reserve, commit and transfer",
+        scope: Some(Scope::Admin),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "SetCreditLimitInput",
+            accepts: accepts::<proto::SetCreditLimitInput>,
+        }),
+        response: "GenericOutput",
+        csv: false,
+    },
+    Operation {
+        method: "get",
+        path: "/overdrafts",
+        summary: "User wallets below zero and how long they have been negative",
+        scope: Some(Scope::Accounting),
+        parameters: &[],
+        request: None,
+        response: "OverdraftsOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/admin/reconcile",
+        summary: "Check balances against transaction history, the ledger and reservations and save the findings",
+        scope: Some(Scope::Admin),
+        parameters: &[],
+        request: None,
+        response: "ReconciliationOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/accounts",
+        summary: "Open user wallet in the currency, the first wallet of the user is the primary one",
+        scope: Some(Scope::Billing),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "OpenAccountInput",
+            accepts: accepts::<proto::OpenAccountInput>,
+        }),
+        response: "GenericOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/accounts/status",
+        summary: "Freeze, unfreeze or close user wallet, the change is logged with the reason",
+        scope: Some(Scope::Admin),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "SetAccountStatusInput",
+            accepts: accepts::<proto::SetAccountStatusInput>,
+        }),
+        response: "GenericOutput",
+        csv: false,
+    },
+    Operation {
+        method: "post",
+        path: "/accounts/currency",
+        summary: "Move user wallet with its reservations into another currency at the current rate",
+        scope: Some(Scope::Admin),
+        parameters: &[],
+        request: Some(RequestBody {
+            message: "ChangeAccountCurrencyInput",
+            accepts: accepts::<proto::ChangeAccountCurrencyInput>,
+        }),
+        response: "GenericOutput",
+        csv: false,
+    },
+];
+
+static API_FILE: Lazy<FileDescriptorProto> = Lazy::new(|| {
+    FileDescriptorSet::decode(proto::FILE_DESCRIPTOR_SET)
+        .expect("descriptor set is generated by build.rs")
+        .file
+        .into_iter()
+        .find(|file| file.name() == "api.proto")
+        .expect("descriptor set contains api.proto")
+});
+
+pub static DOCUMENT: Lazy<Value> = Lazy::new(document);
+
+// same conversion as serde(rename_all = "camelCase")
+fn camel_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn schema_ref(type_name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", type_name.trim_start_matches(".api.")) })
+}
+
+fn comment(path: &[i32]) -> Option<String> {
+    API_FILE
+        .source_code_info
+        .as_ref()?
+        .location
+        .iter()
+        .find(|location| location.path == path)
+        .and_then(|location| {
+            location
+                .trailing_comments
+                .as_deref()
+                .or(location.leading_comments.as_deref())
+        })
+        .map(|comment| comment.trim().to_string())
+        .filter(|comment| !comment.is_empty())
+}
+
+fn find_message(type_name: &str) -> Option<(usize, &'static DescriptorProto)> {
+    API_FILE
+        .message_type
+        .iter()
+        .enumerate()
+        .find(|(_, message)| format!(".api.{}", message.name()) == type_name)
+}
+
+fn find_enum(type_name: &str) -> Option<(usize, &'static EnumDescriptorProto)> {
+    API_FILE
+        .enum_type
+        .iter()
+        .enumerate()
+        .find(|(_, e)| format!(".api.{}", e.name()) == type_name)
+}
+
+// map<k, v> fields are repeated messages of a nested entry type
+fn map_entry<'a>(message: &'a DescriptorProto, field: &FieldDescriptorProto) -> Option<&'a DescriptorProto> {
+    message.nested_type.iter().find(|nested| {
+        nested.options.as_ref().and_then(|o| o.map_entry).unwrap_or_default()
+            && field.type_name().ends_with(&format!(".{}", nested.name()))
+    })
+}
+
+fn value_schema(field: &FieldDescriptorProto) -> Value {
+    match field.r#type() {
+        Type::String => json!({ "type": "string" }),
+        Type::Bool => json!({ "type": "boolean" }),
+        Type::Int32 | Type::Sint32 | Type::Sfixed32 | Type::Uint32 | Type::Fixed32 => {
+            json!({ "type": "integer", "format": "int32" })
+        }
+        Type::Int64 | Type::Sint64 | Type::Sfixed64 | Type::Uint64 | Type::Fixed64 => {
+            json!({ "type": "integer", "format": "int64" })
+        }
+        Type::Double | Type::Float => json!({ "type": "number" }),
+        Type::Bytes => json!({ "type": "array", "items": { "type": "integer" } }),
+        Type::Message if field.type_name() == TIMESTAMP_TYPE => json!({ "type": "string", "format": "date-time" }),
+        Type::Message | Type::Enum | Type::Group => schema_ref(field.type_name()),
+    }
+}
+
+fn field_schema(message: &DescriptorProto, field: &FieldDescriptorProto) -> Value {
+    if field.label() == Label::Repeated {
+        return match map_entry(message, field) {
+            Some(entry) => json!({ "type": "object", "additionalProperties": value_schema(&entry.field[1]) }),
+            None => json!({ "type": "array", "items": value_schema(field) }),
+        };
+    }
+    let mut schema = value_schema(field);
+    // singular message fields are optional and serialized as null when missing
+    if field.r#type() == Type::Message {
+        if schema.get("$ref").is_some() {
+            schema = json!({ "allOf": [schema] });
+        }
+        schema["nullable"] = json!(true);
+    }
+    schema
+}
+
+fn message_schema(index: usize, message: &DescriptorProto) -> Value {
+    let mut properties = Map::new();
+    let mut oneofs = vec![Map::new(); message.oneof_decl.len()];
+    for (field_index, field) in message.field.iter().enumerate() {
+        // oneof variant is never null, the oneof itself is
+        let mut schema = match field.oneof_index {
+            Some(_) => value_schema(field),
+            None => field_schema(message, field),
+        };
+        if let Some(description) = comment(&[4, index as i32, 2, field_index as i32]) {
+            // siblings of $ref are ignored
+            if schema.get("$ref").is_some() {
+                schema = json!({ "allOf": [schema] });
+            }
+            schema["description"] = json!(description);
+        }
+        match field.oneof_index {
+            Some(oneof_index) => oneofs[oneof_index as usize].insert(camel_case(field.name()), schema),
+            None => properties.insert(camel_case(field.name()), schema),
+        };
+    }
+    // oneof is serialized as an object with exactly one of the variants set
+    for (oneof, variants) in message.oneof_decl.iter().zip(oneofs) {
+        properties.insert(
+            camel_case(oneof.name()),
+            json!({
+                "type": "object",
+                "nullable": true,
+                "properties": variants,
+                "minProperties": 1,
+                "maxProperties": 1,
+            }),
+        );
+    }
+
+    let mut schema = json!({ "type": "object", "properties": properties });
+    if let Some(description) = comment(&[4, index as i32]) {
+        schema["description"] = json!(description);
+    }
+    schema
+}
+
+fn enum_schema(index: usize, e: &EnumDescriptorProto) -> Value {
+    let values = e.value.iter().map(|v| v.number()).collect::<Vec<_>>();
+    let names = e
+        .value
+        .iter()
+        .map(|v| format!("{} - {}", v.number(), v.name()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let description = match comment(&[5, index as i32]) {
+        Some(comment) => format!("{comment}: {names}"),
+        None => names,
+    };
+    json!({ "type": "integer", "enum": values, "description": description })
+}
+
+// messages and enums used by the message, including itself
+fn collect_types(type_name: &str, types: &mut BTreeSet<String>) {
+    if !types.insert(type_name.to_string()) {
+        return;
+    }
+    if let Some((_, message)) = find_message(type_name) {
+        for field in &message.field {
+            let field_type = match map_entry(message, field) {
+                Some(entry) => &entry.field[1],
+                None => field,
+            };
+            if matches!(field_type.r#type(), Type::Message | Type::Enum) && field_type.type_name() != TIMESTAMP_TYPE {
+                collect_types(field_type.type_name(), types);
+            }
+        }
+    }
+}
+
+// example value accepted by serde for the schema
+fn sample(schema: &Value) -> Value {
+    if schema.get("nullable") == Some(&json!(true)) {
+        return Value::Null;
+    }
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let type_name = format!(".api.{}", reference.trim_start_matches("#/components/schemas/"));
+        return match find_message(&type_name) {
+            Some((index, message)) => sample(&message_schema(index, message)),
+            None => json!(0),
+        };
+    }
+    match schema.get("type").and_then(Value::as_str) {
+        Some("object") => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            let properties = properties.into_iter().flatten();
+            Value::Object(
+                properties
+                    .map(|(name, schema)| (name.clone(), sample(schema)))
+                    .collect(),
+            )
+        }
+        Some("array") => json!([]),
+        Some("string") if schema.get("format") == Some(&json!("date-time")) => json!("2023-01-01T00:00:00Z"),
+        Some("string") => json!(""),
+        Some("boolean") => json!(false),
+        _ => json!(0),
+    }
+}
+
+// fields the handler can't decode the body without
+fn required_fields(schema: &Value, request: &RequestBody) -> Vec<String> {
+    let full = sample(schema);
+    let full = full.as_object().expect("messages are objects");
+    full.keys()
+        .filter(|name| {
+            let mut partial = full.clone();
+            partial.remove(name.as_str());
+            !(request.accepts)(&Value::Object(partial))
+        })
+        .cloned()
+        .collect()
+}
+
+fn binary_content() -> Value {
+    json!({ "schema": { "type": "string", "format": "binary" } })
+}
+
+fn operation(op: &Operation) -> Value {
+    let parameters = op
+        .parameters
+        .iter()
+        .map(|p| {
+            json!({
+                "name": p.name,
+                "in": p.location,
+                "required": p.location == "path",
+                "description": p.description,
+                "schema": { "type": p.schema_type },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut content = Map::new();
+    content.insert(
+        "application/json".to_string(),
+        json!({ "schema": schema_ref(op.response) }),
+    );
+    content.insert("application/x-protobuf".to_string(), binary_content());
+    if op.csv {
+        content.insert(
+            "text/csv".to_string(),
+            json!({ "schema": { "type": "string", "description": "\";\" separated item_id and total" } }),
+        );
+    }
+
+    let unauthorized = match op.scope {
+        Some(scope) => format!(
+            "missing or invalid credentials, or client has no {} scope",
+            scope.as_str()
+        ),
+        None => "missing or invalid credentials".to_string(),
+    };
+    let mut responses = Map::new();
+    responses.insert(
+        "200".to_string(),
+        json!({ "description": "format is chosen by Accept header", "content": content }),
+    );
+    // errors are sent in GenericOutput envelope
+    for (status, description) in ERROR_RESPONSES {
+        let description = match *status {
+            "401" => unauthorized.as_str(),
+            _ => description,
+        };
+        responses.insert(
+            status.to_string(),
+            json!({
+                "description": description,
+                "content": {
+                    "application/json": { "schema": schema_ref("GenericOutput") },
+                    "application/x-protobuf": binary_content(),
+                },
+            }),
+        );
+    }
+    let mut operation = json!({
+        "summary": op.summary,
+        "parameters": parameters,
+        "responses": responses,
+        "security": [{ "apiKey": [] }, { "hmacSignature": [] }],
+    });
+    if let Some(scope) = op.scope {
+        operation["x-required-scope"] = json!(scope.as_str());
+    }
+    if let Some(request) = &op.request {
+        operation["requestBody"] = json!({
+            "required": true,
+            "description": "json or protobuf depending on Content-Type header",
+            "content": {
+                "application/json": { "schema": schema_ref(request.message) },
+                "application/x-protobuf": binary_content(),
+            },
+        });
+    }
+    operation
+}
+
+fn document() -> Value {
+    let mut paths = Map::new();
+    let mut types = BTreeSet::new();
+    for op in OPERATIONS {
+        paths.entry(op.path).or_insert_with(|| json!({}))[op.method] = operation(op);
+        collect_types(&format!(".api.{}", op.response), &mut types);
+        collect_types(".api.GenericOutput", &mut types);
+        if let Some(request) = &op.request {
+            collect_types(&format!(".api.{}", request.message), &mut types);
+        }
+    }
+
+    let mut schemas = Map::new();
+    for type_name in types {
+        let schema = match (find_message(&type_name), find_enum(&type_name)) {
+            (Some((index, message)), _) => message_schema(index, message),
+            (_, Some((index, e))) => enum_schema(index, e),
+            _ => continue,
+        };
+        schemas.insert(type_name.trim_start_matches(".api.").to_string(), schema);
+    }
+    for request in OPERATIONS.iter().filter_map(|op| op.request.as_ref()) {
+        let required = required_fields(&schemas[request.message], request);
+        if !required.is_empty() {
+            schemas[request.message]["required"] = json!(required);
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Balance service",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Errors are returned with 4xx/5xx status codes, send \"X-Error-Status: 200\" header to receive them with 200 OK instead.",
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "apiKey": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "api key issued to the client",
+                },
+                "hmacSignature": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "X-Signature",
+                    "description": "hex encoded HMAC-SHA256 of \"METHOD\\nPATH?QUERY\\nTIMESTAMP\\nBODY\" keyed with the client secret, sent together with X-Client-Id and X-Timestamp (unix seconds) headers",
+                },
+            },
+        },
+    })
+}
+
+pub const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>Balance service API</title>
+  <link rel="stylesheet" href="/docs/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="/docs/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+// content type and content of the swagger ui files embedded into the binary
+pub fn swagger_ui_asset(name: &str) -> Option<(&'static str, &'static [u8])> {
+    match name {
+        "swagger-ui.css" => Some(("text/css; charset=utf-8", include_bytes!("swagger-ui/swagger-ui.css"))),
+        "swagger-ui-bundle.js" => Some((
+            "application/javascript; charset=utf-8",
+            include_bytes!("swagger-ui/swagger-ui-bundle.js"),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::create_test_client;
+    use crate::{currency, database, responses, routes};
+    use actix_request_identifier::RequestIdentifier;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App};
+    use serde::Serialize;
+
+    fn resolve(schema: &Value) -> &Value {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            return resolve(&DOCUMENT["components"]["schemas"][name]);
+        }
+        match schema.get("allOf").and_then(|all| all.get(0)) {
+            Some(inner) => resolve(inner),
+            None => schema,
+        }
+    }
+
+    // checks that the value has exactly the shape described by the schema
+    fn validate(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+        if value.is_null() {
+            return match schema.get("nullable") == Some(&json!(true)) {
+                true => Ok(()),
+                false => Err(format!("{path} is null")),
+            };
+        }
+        let schema = resolve(schema);
+        if let Some(variants) = schema.get("oneOf").and_then(Value::as_array) {
+            return match variants.iter().any(|variant| validate(value, variant, path).is_ok()) {
+                true => Ok(()),
+                false => Err(format!("{path} matches none of the documented schemas")),
+            };
+        }
+        match (schema["type"].as_str(), value) {
+            (Some("object"), Value::Object(object)) => {
+                if let Some(additional) = schema.get("additionalProperties") {
+                    for (key, value) in object {
+                        validate(value, additional, &format!("{path}.{key}"))?;
+                    }
+                    return Ok(());
+                }
+                let properties = schema["properties"].as_object().cloned().unwrap_or_default();
+                for (key, value) in object {
+                    let property = properties
+                        .get(key)
+                        .ok_or_else(|| format!("{path}.{key} is not documented"))?;
+                    validate(value, property, &format!("{path}.{key}"))?;
+                }
+                // oneof has a single variant set, other objects have every documented field
+                let expected = schema.get("maxProperties").and_then(Value::as_u64);
+                if expected.is_none() {
+                    if let Some(missing) = properties.keys().find(|key| !object.contains_key(*key)) {
+                        return Err(format!("{path}.{missing} is missing"));
+                    }
+                } else if expected != Some(object.len() as u64) {
+                    return Err(format!("{path} should have a single property"));
+                }
+                Ok(())
+            }
+            (Some("array"), Value::Array(items)) => items
+                .iter()
+                .enumerate()
+                .try_for_each(|(i, item)| validate(item, &schema["items"], &format!("{path}[{i}]"))),
+            (Some("string"), Value::String(_)) | (Some("boolean"), Value::Bool(_)) => Ok(()),
+            (Some("integer"), Value::Number(n)) if n.is_i64() || n.is_u64() => Ok(()),
+            (Some("number"), Value::Number(_)) => Ok(()),
+            (expected, value) => Err(format!("{path} is {value}, expected {expected:?}")),
+        }
+    }
+
+    fn schema(name: &str) -> Value {
+        schema_ref(name)
+    }
+
+    fn assert_fields<T: Default + Serialize>(name: &str) {
+        let value = serde_json::to_value(T::default()).unwrap();
+        validate(&value, &schema(name), name).unwrap();
+    }
+
+    #[test]
+    fn test_messages_match_serde() {
+        assert_fields::<proto::GenericOutput>("GenericOutput");
+        assert_fields::<proto::StatisticsOutput>("StatisticsOutput");
+        assert_fields::<proto::ListTransactionsOutput>("ListTransactionsOutput");
+        assert_fields::<proto::OrderStateOutput>("OrderStateOutput");
+        assert_fields::<proto::OrderItemState>("OrderItemState");
+        assert_fields::<proto::ExchangeRateOutput>("ExchangeRateOutput");
+        assert_fields::<proto::QuoteInput>("QuoteInput");
+        assert_fields::<proto::QuoteOutput>("QuoteOutput");
+        assert_fields::<proto::SetCreditLimitInput>("SetCreditLimitInput");
+        assert_fields::<proto::OverdraftsOutput>("OverdraftsOutput");
+        assert_fields::<proto::ReconciliationOutput>("ReconciliationOutput");
+        assert_fields::<proto::UserBalanceData>("UserBalanceData");
+        assert_fields::<proto::WalletBalance>("WalletBalance");
+        assert_fields::<proto::UserTransaction>("UserTransaction");
+        assert_fields::<proto::BadParameterError>("BadParameterError");
+        assert_fields::<proto::TopUpInput>("TopUpInput");
+        assert_fields::<proto::ReserveInput>("ReserveInput");
+        assert_fields::<proto::CommitReservationInput>("CommitReservationInput");
+        assert_fields::<proto::CancelReservationInput>("CancelReservationInput");
+        assert_fields::<proto::TransferInput>("TransferInput");
+        assert_fields::<proto::OpenAccountInput>("OpenAccountInput");
+        assert_fields::<proto::SetAccountStatusInput>("SetAccountStatusInput");
+        assert_fields::<proto::ChangeAccountCurrencyInput>("ChangeAccountCurrencyInput");
+
+        let error = serde_json::to_value(responses::bad_parameter_output("value")).unwrap();
+        validate(&error, &schema("GenericOutput"), "GenericOutput").unwrap();
+
+        // documented required fields are exactly the ones serde can't do without
+        for request in OPERATIONS.iter().filter_map(|op| op.request.as_ref()) {
+            let message = &DOCUMENT["components"]["schemas"][request.message];
+            let mut body = sample(message);
+            assert!((request.accepts)(&body), "{} sample is rejected", request.message);
+            let required = message["required"].as_array().cloned().unwrap_or_default();
+            body.as_object_mut()
+                .unwrap()
+                .retain(|key, _| required.contains(&json!(key)));
+            assert!(
+                (request.accepts)(&body),
+                "{} needs undocumented fields",
+                request.message
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_handlers_match_document() {
+        dotenvy::dotenv().ok();
+
+        let db = database::connect::create_test_db_connection_pool();
+        let api_key = create_test_client(
+            &mut db.get().unwrap(),
+            "test_openapi",
+            &[Scope::Billing, Scope::Service, Scope::Accounting, Scope::Admin],
+        );
+        let app = init_service(
+            App::new()
+                .wrap(RequestIdentifier::with_uuid())
+                .app_data(web::Data::new(db))
+                .app_data(web::Data::new(currency::create_currency_converter().await))
+                .configure(routes::configure),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/openapi.json").to_request()).await;
+        let document: Value = read_body_json(res).await;
+        assert_eq!(document, *DOCUMENT);
+
+        // swagger ui is served without cdn
+        for uri in ["/docs", "/docs/swagger-ui.css", "/docs/swagger-ui-bundle.js"] {
+            let res = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{uri}");
+        }
+        let res = call_service(&app, TestRequest::get().uri("/docs/unknown.js").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = call_service(&app, TestRequest::get().uri("/unknown").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        for op in OPERATIONS {
+            // values are chosen so that handlers never change balances
+            let uri = op
+                .path
+                .replace("{user_id}", "test_openapi_unknown_user")
+                .replace("{year}", "2023")
+                .replace("{month}", "1")
+                .replace("{order_id}", "test_openapi_unknown_order")
+                .replace("{from_currency}", "USD")
+                .replace("{to_currency}", "EUR");
+            let request = |authorized: bool| {
+                let mut req = match op.method {
+                    "get" => TestRequest::get(),
+                    _ => TestRequest::post(),
+                }
+                .uri(&uri)
+                .insert_header((header::ACCEPT, "application/json"));
+                if authorized {
+                    req = req.insert_header((header::AUTHORIZATION, format!("Bearer {api_key}")));
+                }
+                if let Some(body) = &op.request {
+                    req = req.set_json(sample(&DOCUMENT["components"]["schemas"][body.message]));
+                }
+                req.to_request()
+            };
+
+            // documented path is served, paths without a handler are 404 before authorization
+            let res = call_service(&app, request(false)).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{} {}", op.method, op.path);
+            let body: Value = read_body_json(res).await;
+            validate(&body, &schema("GenericOutput"), op.path).unwrap();
+
+            let res = call_service(&app, request(true)).await;
+            let status = res.status().as_str().to_string();
+            let body: Value = read_body_json(res).await;
+            let documented = &DOCUMENT["paths"][op.path][op.method]["responses"][&status];
+            assert!(
+                documented.is_object(),
+                "{} {} status {status} is not documented",
+                op.method,
+                op.path
+            );
+            validate(&body, &documented["content"]["application/json"]["schema"], op.path).unwrap();
+            assert_ne!(
+                body["error"]["oneError"]["badParameter"]["name"],
+                json!("body is invalid"),
+                "{} {} doesn't accept documented body",
+                op.method,
+                op.path
+            );
+        }
+    }
+}
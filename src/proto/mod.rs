@@ -1 +1,3 @@
 include!(concat!(env!("OUT_DIR"), "/api.rs"));
+
+pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/api_descriptor.bin"));
@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::env;
+use std::ops::DerefMut;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use anyhow::{anyhow, bail, Context};
+use bigdecimal::BigDecimal;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use tracing::{error, info};
+
+use crate::currency::{CurrencyConverter, Rates};
+use crate::database::{mutations, queries};
+use crate::errors::ServiceError;
+
+const DEFAULT_API_URL: &str = "https://api.apilayer.com/exchangerates_data/latest";
+
+// source of exchange rates
+#[async_trait::async_trait]
+pub trait RateProvider: Send + Sync {
+    async fn fetch(&self) -> anyhow::Result<Rates>;
+}
+
+// rates built into the binary, taken from exchangerates_data on 2022-11-20
+pub struct StubProvider;
+
+#[async_trait::async_trait]
+impl RateProvider for StubProvider {
+    async fn fetch(&self) -> anyhow::Result<Rates> {
+        parse_exchangerates_json(&serde_json::from_str(STUB_CURRENCY_RATES_JSON)?)
+    }
+}
+
+// local file, either in exchangerates_data json format or csv with "base,<currency>" first line
+// and "<currency>,<rate>" lines after it
+pub struct FileProvider {
+    path: String,
+}
+
+impl FileProvider {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateProvider for FileProvider {
+    async fn fetch(&self) -> anyhow::Result<Rates> {
+        let path = self.path.clone();
+        let content = web::block(move || std::fs::read_to_string(path))
+            .await
+            .map_err(|e| anyhow!("{e}"))?
+            .with_context(|| format!("failed to read {}", self.path))?;
+        if self.path.ends_with(".csv") {
+            parse_csv(&content)
+        } else {
+            parse_exchangerates_json(&serde_json::from_str(&content)?)
+        }
+    }
+}
+
+// apilayer exchangerates_data api
+pub struct HttpProvider {
+    client: reqwest::Client,
+    url: String,
+    api_key: String,
+    base_currency: String,
+}
+
+impl HttpProvider {
+    pub fn new(url: String, api_key: String, base_currency: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("Failed to create http client");
+        Self {
+            client,
+            url,
+            api_key,
+            base_currency,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateProvider for HttpProvider {
+    async fn fetch(&self) -> anyhow::Result<Rates> {
+        let json = self
+            .client
+            .get(self.url.as_str())
+            .query(&[("base", self.base_currency.as_str())])
+            .header("apikey", self.api_key.as_str())
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+        parse_exchangerates_json(&json)
+    }
+}
+
+fn parse_exchangerates_json(json: &serde_json::Value) -> anyhow::Result<Rates> {
+    if json["success"] == false {
+        bail!("rates request failed: {}", json["error"]);
+    }
+    let base_currency = json["base"].as_str().context("base is missing")?.to_string();
+    let rates = json["rates"]
+        .as_object()
+        .context("rates are missing")?
+        .iter()
+        .map(|(k, v)| {
+            // numbers keep their text, so rates are parsed without going through f64
+            match v {
+                serde_json::Value::Number(rate) => BigDecimal::from_str(&rate.to_string()).ok(),
+                _ => None,
+            }
+            .map(|rate| (k.to_string(), rate))
+            .with_context(|| format!("invalid rate of {k}"))
+        })
+        .collect::<anyhow::Result<HashMap<_, _>>>()?;
+    validate(Rates::new(base_currency, rates))
+}
+
+fn parse_csv(content: &str) -> anyhow::Result<Rates> {
+    let mut lines = content.lines().map(str::trim).filter(|line| !line.is_empty());
+    let base_currency = match lines.next().and_then(|line| line.split_once(',')) {
+        Some(("base", base_currency)) => base_currency.trim().to_string(),
+        _ => bail!("first line must be base,<currency>"),
+    };
+    let rates = lines
+        .map(|line| {
+            let (currency, rate) = line.split_once(',').with_context(|| format!("invalid line {line}"))?;
+            let rate = BigDecimal::from_str(rate.trim()).with_context(|| format!("invalid rate of {currency}"))?;
+            Ok((currency.trim().to_string(), rate))
+        })
+        .collect::<anyhow::Result<HashMap<_, _>>>()?;
+    validate(Rates::new(base_currency, rates))
+}
+
+// division by rates must be safe
+fn validate(rates: Rates) -> anyhow::Result<Rates> {
+    if rates.rates.is_empty() {
+        bail!("no rates");
+    }
+    if let Some((currency, _)) = rates.rates.iter().find(|(_, rate)| *rate <= &BigDecimal::from(0)) {
+        bail!("rate of {currency} is not positive");
+    }
+    Ok(rates)
+}
+
+// refreshed rates must keep every currency of the previous ones, wallets and reservations may be held in any of them
+fn validate_refresh(previous: &Rates, rates: Rates) -> anyhow::Result<Rates> {
+    if let Some(currency) = previous.currencies().find(|currency| !rates.has_currency(currency)) {
+        bail!("rate of {currency} is missing");
+    }
+    Ok(rates)
+}
+
+// RATES_PROVIDER is "stub" (default), "file" (reads RATES_FILE) or "http" (RATES_API_URL with RATES_API_KEY,
+// rates relative to RATES_BASE_CURRENCY)
+pub fn provider_from_env() -> Box<dyn RateProvider> {
+    match env::var("RATES_PROVIDER").as_deref() {
+        Ok("stub") | Err(_) => Box::new(StubProvider),
+        Ok("file") => Box::new(FileProvider::new(
+            env::var("RATES_FILE").expect("RATES_FILE must be set"),
+        )),
+        Ok("http") => Box::new(HttpProvider::new(
+            env::var("RATES_API_URL").unwrap_or_else(|_| DEFAULT_API_URL.to_string()),
+            env::var("RATES_API_KEY").expect("RATES_API_KEY must be set"),
+            env::var("RATES_BASE_CURRENCY").unwrap_or_else(|_| "EUR".to_string()),
+        )),
+        Ok(_) => panic!("RATES_PROVIDER must be stub, file or http"),
+    }
+}
+
+// writes the snapshot to rate history, so conversions referencing it can be audited later
+pub async fn store(db: Pool<ConnectionManager<PgConnection>>, rates: Arc<Rates>) -> Result<(), ServiceError> {
+    web::block(move || {
+        let mut conn = db.get()?;
+        mutations::save_rates(conn.deref_mut(), &rates)?;
+        Ok(())
+    })
+    .await?
+}
+
+// rates must convert every currency of wallets and reservations
+pub async fn check_held_currencies(
+    db: Pool<ConnectionManager<PgConnection>>,
+    rates: Arc<Rates>,
+) -> Result<(), ServiceError> {
+    let held = web::block(move || {
+        let mut conn = db.get()?;
+        Ok::<_, ServiceError>(queries::held_currencies(conn.deref_mut())?)
+    })
+    .await??;
+    match held.iter().find(|currency| !rates.has_currency(currency)) {
+        Some(currency) => Err(anyhow!("rate of {currency} is missing").into()),
+        None => Ok(()),
+    }
+}
+
+// starts background task that periodically replaces converter rates,
+// new rates are used only after they are stored, failed refresh keeps the previous rates.
+// rates that miss a currency of the previous ones are rejected
+pub fn spawn_refresher(
+    db: Pool<ConnectionManager<PgConnection>>,
+    curr: CurrencyConverter,
+    provider: Box<dyn RateProvider>,
+) {
+    let interval_seconds: u64 = match env::var("RATES_REFRESH_INTERVAL_SECONDS") {
+        Ok(value) => value.parse().expect("RATES_REFRESH_INTERVAL_SECONDS must be a number"),
+        Err(_) => 3600,
+    };
+    if interval_seconds == 0 {
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        let mut timer = actix_web::rt::time::interval(Duration::from_secs(interval_seconds));
+        // first tick is immediate and initial rates are already loaded
+        timer.tick().await;
+        loop {
+            timer.tick().await;
+            let fetched = provider.fetch().await;
+            let rates = match fetched.and_then(|rates| validate_refresh(&curr.snapshot(), rates)) {
+                Ok(rates) => Arc::new(rates),
+                Err(e) => {
+                    error!("failed to refresh currency rates: {e}");
+                    continue;
+                }
+            };
+            match store(db.clone(), rates.clone()).await {
+                Ok(()) => {
+                    info!("currency rates refreshed, snapshot {}", rates.snapshot_id);
+                    curr.set_rates(Arc::unwrap_or_clone(rates));
+                }
+                Err(e) => error!("failed to store currency rates: {e}"),
+            }
+        }
+    });
+}
+
+const STUB_CURRENCY_RATES_JSON: &str = r#"{
+  "base": "EUR",
+  "date": "2022-11-20",
+  "rates": {
+    "AED": 3.799913,
+    "AFN": 91.040516,
+    "ALL": 117.162584,
+    "AMD": 408.830211,
+    "ANG": 1.862988,
+    "AOA": 528.802751,
+    "ARS": 168.470072,
+    "AUD": 1.549912,
+    "AWG": 1.862191,
+    "AZN": 1.756403,
+    "BAM": 1.950345,
+    "BBD": 2.08717,
+    "BDT": 106.527193,
+    "BGN": 1.960269,
+    "BHD": 0.389326,
+    "BIF": 2123.932057,
+    "BMD": 1.03455,
+    "BND": 1.41915,
+    "BOB": 7.143049,
+    "BRL": 5.568784,
+    "BSD": 1.033723,
+    "BTC": 6.2510943e-05,
+    "BTN": 84.429292,
+    "BWP": 13.390494,
+    "BYN": 2.610758,
+    "BYR": 20277.188664,
+    "BZD": 2.08368,
+    "CAD": 1.387281,
+    "CDF": 2114.620859,
+    "CHF": 0.987777,
+    "CLF": 0.035311,
+    "CLP": 974.339479,
+    "CNY": 7.3659,
+    "COP": 5161.196282,
+    "CRC": 631.117254,
+    "CUC": 1.03455,
+    "CUP": 27.415587,
+    "CVE": 110.541277,
+    "CZK": 24.399839,
+    "DJF": 183.860512,
+    "DKK": 7.453415,
+    "DOP": 56.385041,
+    "DZD": 143.858337,
+    "EGP": 25.412711,
+    "ERN": 15.518257,
+    "ETB": 54.800194,
+    "EUR": 1,
+    "FJD": 2.307824,
+    "FKP": 0.869885,
+    "GBP": 0.870211,
+    "GEL": 2.814406,
+    "GGP": 0.869885,
+    "GHS": 15.000588,
+    "GIP": 0.869885,
+    "GMD": 63.626077,
+    "GNF": 9078.179937,
+    "GTQ": 8.071317,
+    "GYD": 216.27176,
+    "HKD": 8.092202,
+    "HNL": 25.708943,
+    "HRK": 7.526667,
+    "HTG": 142.655555,
+    "HUF": 407.343721,
+    "IDR": 16181.817284,
+    "ILS": 3.586375,
+    "IMP": 0.869885,
+    "INR": 84.336707,
+    "IQD": 1510.443645,
+    "IRR": 43864.938367,
+    "ISK": 149.202686,
+    "JEP": 0.869885,
+    "JMD": 158.931096,
+    "JOD": 0.733449,
+    "JPY": 145.193963,
+    "KES": 126.370682,
+    "KGS": 87.360855,
+    "KHR": 4283.038765,
+    "KMF": 492.960672,
+    "KPW": 931.095413,
+    "KRW": 1386.328965,
+    "KWD": 0.318431,
+    "KYD": 0.861407,
+    "KZT": 477.53698,
+    "LAK": 19087.455773,
+    "LBP": 850.922872,
+    "LKR": 379.902419,
+    "LRD": 159.320487,
+    "LSL": 17.97059,
+    "LTL": 3.054759,
+    "LVL": 0.625789,
+    "LYD": 5.064168,
+    "MAD": 11.08469,
+    "MDL": 19.821741,
+    "MGA": 4466.154127,
+    "MKD": 61.442201,
+    "MMK": 2170.870312,
+    "MNT": 3531.935,
+    "MOP": 8.328658,
+    "MRO": 369.33433,
+    "MUR": 45.147845,
+    "MVR": 15.947597,
+    "MWK": 1059.379798,
+    "MXN": 20.112179,
+    "MYR": 4.711139,
+    "MZN": 66.035672,
+    "NAD": 17.969926,
+    "NGN": 457.953764,
+    "NIO": 37.243989,
+    "NOK": 10.545328,
+    "NPR": 135.088648,
+    "NZD": 1.681513,
+    "OMR": 0.397771,
+    "PAB": 1.033713,
+    "PEN": 3.940344,
+    "PGK": 3.641493,
+    "PHP": 59.183546,
+    "PKR": 230.13581,
+    "PLN": 4.708911,
+    "PYG": 7412.496478,
+    "QAR": 3.766285,
+    "RON": 4.951383,
+    "RSD": 117.32505,
+    "RUB": 62.952522,
+    "RWF": 1091.450716,
+    "SAR": 3.888389,
+    "SBD": 8.514963,
+    "SCR": 14.975175,
+    "SDG": 589.17846,
+    "SEK": 11.003891,
+    "SGD": 1.423647,
+    "SHP": 1.424989,
+    "SLE": 18.715042,
+    "SLL": 18647.772106,
+    "SOS": 588.14321,
+    "SRD": 31.699142,
+    "STD": 21413.105401,
+    "SVC": 9.044563,
+    "SYP": 2599.337015,
+    "SZL": 17.970319,
+    "THB": 37.046883,
+    "TJS": 10.551326,
+    "TMT": 3.631272,
+    "TND": 3.273836,
+    "TOP": 2.454315,
+    "TRY": 19.264674,
+    "TTD": 7.016602,
+    "TWD": 32.201729,
+    "TZS": 2412.571578,
+    "UAH": 38.177783,
+    "UGX": 3860.941532,
+    "USD": 1.03455,
+    "UYU": 41.131521,
+    "UZS": 11607.656055,
+    "VEF": 1011298.968166,
+    "VND": 25664.610091,
+    "VUV": 122.823144,
+    "WST": 2.881748,
+    "XAF": 654.133131,
+    "XAG": 0.049449,
+    "XAU": 0.000591,
+    "XCD": 2.795925,
+    "XDR": 0.788801,
+    "XOF": 663.672391,
+    "XPF": 119.645986,
+    "YER": 258.922152,
+    "ZAR": 17.856723,
+    "ZMK": 9312.187622,
+    "ZMW": 17.227433,
+    "ZWL": 333.12482
+  },
+  "success": true,
+  "timestamp": 1668963843
+}"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::idgen;
+    use crate::money::Money;
+    use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpServer};
+
+    // unique path in the temp dir, so concurrent test runs don't overwrite each other's files
+    fn temp_path(extension: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!(
+            "test_file_provider_{}_{}.{extension}",
+            std::process::id(),
+            idgen::next()
+        ))
+    }
+
+    #[actix_web::test]
+    async fn test_file_provider() {
+        let path = temp_path("csv");
+        std::fs::write(&path, "base,EUR\nUSD,1.08\n\nGBP, 0.88\n").unwrap();
+        let rates = FileProvider::new(path.to_str().unwrap().to_string())
+            .fetch()
+            .await
+            .unwrap();
+        assert_eq!(rates.base_currency, "EUR");
+        assert_eq!(rates.rates.len(), 2);
+        assert_eq!(rates.rates["GBP"], BigDecimal::from_str("0.88").unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let path = temp_path("json");
+        std::fs::write(&path, r#"{"base": "USD", "rates": {"EUR": 0.925925925925925925926}}"#).unwrap();
+        let rates = FileProvider::new(path.to_str().unwrap().to_string())
+            .fetch()
+            .await
+            .unwrap();
+        assert_eq!(rates.base_currency, "USD");
+        assert_eq!(
+            rates.rates["EUR"],
+            BigDecimal::from_str("0.925925925925925925926").unwrap()
+        );
+
+        std::fs::write(&path, r#"{"base": "USD", "rates": {"EUR": 0}}"#).unwrap();
+        assert!(FileProvider::new(path.to_str().unwrap().to_string())
+            .fetch()
+            .await
+            .is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[get("/latest")]
+    async fn latest(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        if req.headers().get("apikey").map(|value| value.as_bytes()) != Some(b"test_key") {
+            return HttpResponse::Unauthorized()
+                .json(serde_json::json!({"message": "Invalid authentication credentials"}));
+        }
+        HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "base": query["base"],
+            "date": "2023-01-20",
+            "rates": {"USD": 1.08, "GBP": 0.88}
+        }))
+    }
+
+    #[actix_web::test]
+    async fn test_http_provider() {
+        let server = HttpServer::new(|| App::new().service(latest))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let url = format!("http://{}/latest", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let provider = HttpProvider::new(url.clone(), "test_key".to_string(), "EUR".to_string());
+        let rates = provider.fetch().await.unwrap();
+        assert_eq!(rates.base_currency, "EUR");
+        assert_eq!(rates.rates["USD"], BigDecimal::from_str("1.08").unwrap());
+
+        // refreshed rates replace the previous ones only when they keep all previous currencies
+        let curr = CurrencyConverter::new(StubProvider.fetch().await.unwrap());
+        assert!(curr.is_currency_valid("RUB"));
+        assert!(validate_refresh(&curr.snapshot(), rates.clone()).is_err());
+        let previous = Rates::new(
+            "GBP".to_string(),
+            HashMap::from([("EUR".to_string(), BigDecimal::from(1))]),
+        );
+        let rates = validate_refresh(&previous, rates).unwrap();
+        curr.set_rates(rates);
+        assert!(!curr.is_currency_valid("RUB"));
+        assert_eq!(
+            curr.convert(&Money::new(BigDecimal::from(100), "EUR"), "GBP").unwrap(),
+            Money::new(BigDecimal::from(88), "GBP")
+        );
+
+        let provider = HttpProvider::new(url, "wrong_key".to_string(), "EUR".to_string());
+        assert!(provider.fetch().await.is_err());
+
+        handle.stop(false).await;
+    }
+}
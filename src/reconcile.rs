@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::env;
+use std::ops::DerefMut;
+use std::time::Duration;
+
+use actix_web::web;
+use bigdecimal::BigDecimal;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::result::Error;
+use diesel::PgConnection;
+use tracing::{error, info, warn};
+
+use crate::database::ledger;
+use crate::database::{models, mutations, queries};
+use crate::errors::ServiceError;
+
+// balance before a transaction is not the balance after the previous one of the wallet
+pub const BROKEN_CHAIN: &str = "broken_chain";
+// balance after a transaction is not the balance before it changed by the transaction value
+pub const VALUE_MISMATCH: &str = "value_mismatch";
+// current balance is not the balance after the last transaction of the wallet
+pub const BALANCE_DRIFT: &str = "balance_drift";
+// current balance is not the one derived from the ledger
+pub const LEDGER_DRIFT: &str = "ledger_drift";
+// reserved total is not the value of the hold account
+pub const RESERVE_DRIFT: &str = "reserve_drift";
+
+#[derive(PartialEq, Debug)]
+pub struct Finding {
+    pub user_id: String,
+    pub currency: String,
+    pub kind: &'static str,
+    // transaction the finding was made at, none for the wallet as a whole
+    pub transaction_id: Option<i64>,
+    pub expected: BigDecimal,
+    pub actual: BigDecimal,
+}
+
+impl Finding {
+    fn new(
+        wallet: &models::Balance,
+        kind: &'static str,
+        transaction_id: Option<i64>,
+        expected: BigDecimal,
+        actual: BigDecimal,
+    ) -> Self {
+        Self {
+            user_id: wallet.user_id.clone(),
+            currency: wallet.currency.clone(),
+            kind,
+            transaction_id,
+            expected,
+            actual,
+        }
+    }
+}
+
+// replays wallet transactions from an empty balance, missing balances of a transaction count as zero
+fn replay(wallet: &models::Balance, history: &[&models::Transaction]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut balance = BigDecimal::default();
+    for tx in history {
+        let (before, after, change) = if tx.sender_id.as_deref() == Some(wallet.user_id.as_str())
+            && tx.sender_currency.as_deref() == Some(wallet.currency.as_str())
+        {
+            (
+                tx.sender_balance_before.clone().unwrap_or_default(),
+                tx.sender_balance_after.clone().unwrap_or_default(),
+                -tx.sender_value.clone().unwrap_or_default(),
+            )
+        } else {
+            (
+                tx.recipient_balance_before.clone().unwrap_or_default(),
+                tx.recipient_balance_after.clone().unwrap_or_default(),
+                tx.recipient_value.clone().unwrap_or_default(),
+            )
+        };
+        if before != balance {
+            findings.push(Finding::new(wallet, BROKEN_CHAIN, Some(tx.id), balance, before.clone()));
+        }
+        let expected_after = &before + &change;
+        if after != expected_after {
+            findings.push(Finding::new(
+                wallet,
+                VALUE_MISMATCH,
+                Some(tx.id),
+                expected_after,
+                after.clone(),
+            ));
+        }
+        balance = after;
+    }
+    if wallet.current_value != balance {
+        findings.push(Finding::new(
+            wallet,
+            BALANCE_DRIFT,
+            None,
+            balance,
+            wallet.current_value.clone(),
+        ));
+    }
+    findings
+}
+
+// checks every wallet against its transactions, the ledger and its reservations,
+// returns the number of checked wallets and the findings
+pub fn check_wallets(conn: &mut PgConnection) -> Result<(i64, Vec<Finding>), Error> {
+    let wallets = queries::list_wallets(conn)?;
+    let transactions = queries::wallet_transactions(conn)?;
+    let reserved = queries::wallet_reserved(conn)?;
+    let held = ledger::hold_balances(conn)?;
+
+    // history of every wallet, a transaction within one wallet is replayed once
+    let mut histories: HashMap<(&str, &str), Vec<&models::Transaction>> = HashMap::new();
+    for tx in &transactions {
+        let sender = tx.sender_id.as_deref().zip(tx.sender_currency.as_deref());
+        let recipient = tx.recipient_id.as_deref().zip(tx.recipient_currency.as_deref());
+        for wallet in sender
+            .into_iter()
+            .chain(recipient.filter(|recipient| Some(*recipient) != sender))
+        {
+            histories.entry(wallet).or_default().push(tx);
+        }
+    }
+
+    let mut findings = Vec::new();
+    for wallet in &wallets {
+        let history = histories
+            .get(&(wallet.user_id.as_str(), wallet.currency.as_str()))
+            .map_or(&[][..], Vec::as_slice);
+        findings.extend(replay(wallet, history));
+
+        let key = (wallet.user_id.clone(), wallet.currency.clone());
+        let wallet_reserved = reserved.get(&key).cloned().unwrap_or_default();
+        let wallet_held = held.get(&key).cloned().unwrap_or_default();
+        if wallet_reserved != wallet_held {
+            findings.push(Finding::new(wallet, RESERVE_DRIFT, None, wallet_held, wallet_reserved));
+        }
+    }
+    findings.extend(ledger::wallet_drift(conn)?.into_iter().map(|drift| Finding {
+        user_id: drift.user_id,
+        currency: drift.currency,
+        kind: LEDGER_DRIFT,
+        transaction_id: None,
+        expected: drift.ledger_value,
+        actual: drift.current_value,
+    }));
+    Ok((wallets.len() as i64, findings))
+}
+
+// checks all wallets on a single snapshot and saves the findings report
+pub fn reconcile(
+    conn: &mut PgConnection,
+) -> Result<(models::ReconciliationReport, Vec<models::ReconciliationFinding>), Error> {
+    let (checked_wallets, findings) = conn
+        .build_transaction()
+        .repeatable_read()
+        .read_only()
+        .run(check_wallets)?;
+    mutations::save_reconciliation_report(conn, checked_wallets, findings)
+}
+
+async fn run(
+    db: Pool<ConnectionManager<PgConnection>>,
+) -> Result<(models::ReconciliationReport, Vec<models::ReconciliationFinding>), ServiceError> {
+    web::block(move || {
+        let mut conn = db.get()?;
+        Ok(reconcile(conn.deref_mut())?)
+    })
+    .await?
+}
+
+// starts background task that periodically reconciles wallets
+pub fn spawn_reconciler(db: Pool<ConnectionManager<PgConnection>>) {
+    let interval_seconds: u64 = match env::var("RECONCILE_INTERVAL_SECONDS") {
+        Ok(value) => value.parse().expect("RECONCILE_INTERVAL_SECONDS must be a number"),
+        Err(_) => 86400,
+    };
+    if interval_seconds == 0 {
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        let mut timer = actix_web::rt::time::interval(Duration::from_secs(interval_seconds));
+        // first tick is immediate, wallets are checked once the interval passes
+        timer.tick().await;
+        loop {
+            timer.tick().await;
+            match run(db.clone()).await {
+                Ok((report, findings)) if findings.is_empty() => {
+                    info!("reconciled {} wallets, report {}", report.checked_wallets, report.id)
+                }
+                Ok((report, findings)) => warn!(
+                    "reconciled {} wallets with {} findings, report {}",
+                    report.checked_wallets,
+                    findings.len(),
+                    report.id
+                ),
+                Err(e) => error!("reconciliation failed: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Money;
+    use crate::{currency, database};
+    use bigdecimal::FromPrimitive;
+    use diesel::{Connection, ExpressionMethods, QueryDsl, RunQueryDsl};
+
+    #[actix_web::test]
+    async fn test_check_wallets() {
+        dotenvy::dotenv().ok();
+        let conn = database::connect::create_db_connection_pool();
+        let curr = currency::create_currency_converter().await;
+        conn.get().unwrap().test_transaction::<_, Error, _>(|conn| {
+            let number = |value: i32| BigDecimal::from_i32(value).unwrap();
+            let top_up = |conn: &mut PgConnection, key: &str, user: &str, value: i32| {
+                mutations::top_up(
+                    conn,
+                    &curr,
+                    key,
+                    user,
+                    Money::new(number(value), "USD"),
+                    None,
+                    Default::default(),
+                )
+            };
+            let findings = |conn: &mut PgConnection| -> Result<Vec<Finding>, Error> {
+                let (checked, findings) = check_wallets(conn)?;
+                assert!(checked >= 2);
+                Ok(findings
+                    .into_iter()
+                    .filter(|finding| finding.user_id.starts_with("reconcile-"))
+                    .collect())
+            };
+
+            top_up(conn, "reconcile-1", "reconcile-a", 10)?;
+            top_up(conn, "reconcile-2", "reconcile-a", 5)?;
+            top_up(conn, "reconcile-3", "reconcile-b", 7)?;
+            assert_eq!(findings(conn)?, vec![]);
+
+            // balance changed without a transaction
+            {
+                use crate::schema::balance::dsl::*;
+                diesel::update(balance.filter(user_id.eq("reconcile-b")))
+                    .set(current_value.eq(number(9)))
+                    .execute(conn)?;
+            }
+            // first top-up is recorded with a wrong balance after it
+            let first_tx_id = {
+                use crate::schema::transaction::dsl::*;
+                let first_tx_id = transaction
+                    .filter(recipient_id.eq("reconcile-a"))
+                    .select(id)
+                    .order(id)
+                    .first::<i64>(conn)?;
+                diesel::update(transaction.filter(id.eq(first_tx_id)))
+                    .set(recipient_balance_after.eq(number(11)))
+                    .execute(conn)?;
+                first_tx_id
+            };
+            // reservation changed without the hold account
+            top_up(conn, "reconcile-4", "reconcile-c", 10)?;
+            mutations::reserve(
+                conn,
+                &curr,
+                "reconcile-c",
+                Money::new(number(4), "USD"),
+                "reconcile-c",
+                None,
+                None,
+                None,
+            )?;
+            {
+                use crate::schema::balance_reserve::dsl::*;
+                diesel::update(balance_reserve.filter(user_id.eq("reconcile-c")))
+                    .set(user_currency_value.eq(number(3)))
+                    .execute(conn)?;
+            }
+
+            let found = findings(conn)?;
+            let kinds: Vec<_> = found
+                .iter()
+                .map(|finding| (finding.user_id.as_str(), finding.kind))
+                .collect();
+            assert_eq!(
+                kinds,
+                vec![
+                    ("reconcile-a", VALUE_MISMATCH),
+                    ("reconcile-a", BROKEN_CHAIN),
+                    ("reconcile-b", BALANCE_DRIFT),
+                    ("reconcile-c", RESERVE_DRIFT),
+                    ("reconcile-b", LEDGER_DRIFT),
+                ]
+            );
+            assert_eq!(found[0].transaction_id, Some(first_tx_id));
+            assert_eq!(
+                (found[0].expected.clone(), found[0].actual.clone()),
+                (number(10), number(11))
+            );
+            assert_eq!(
+                (found[3].expected.clone(), found[3].actual.clone()),
+                (number(4), number(3))
+            );
+            assert_eq!(
+                (found[4].expected.clone(), found[4].actual.clone()),
+                (number(7), number(9))
+            );
+            Ok(())
+        });
+    }
+}
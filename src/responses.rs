@@ -1,12 +1,26 @@
+use crate::currency::{CurrencyConverter, UnknownCurrency};
+use crate::cursor;
+use crate::database::models;
 use crate::database::mutations::ReserveResult;
-use crate::database::queries::UserBalance;
-use actix_web::HttpResponse;
-use bigdecimal::Signed;
+use crate::database::queries::{
+    HistoricalRate, OrderState, OrderStatus, Overdraft, ServiceRevenue, TransactionsFilter, TransactionsPage,
+    UserBalance, UserBalanceValues,
+};
+use crate::errors::ServiceError;
+use crate::money::Money;
+use crate::proto;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{HttpResponse, ResponseError};
+use chrono::NaiveDateTime;
 use prost::Message;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::proto::{
-    error, BadParameterError, Error, GenericOutput, InvalidStateError, NotEnoughMoneyError, UserBalanceData,
-    UserNotFoundError,
+    error, AccountBlockedError, BadParameterError, Error, ExchangeRateOutput, GenericOutput, InternalError,
+    InvalidCurrencyError, InvalidQuoteError, InvalidStateError, ListTransactionsOutput, NotEnoughMoneyError,
+    OrderItemState, OrderStateOutput, OverdraftsOutput, QuoteOutput, ReconciliationOutput, StatisticsOutput,
+    UnauthorizedError, UserBalanceData, UserNotFoundError, UserTransaction, WalletBalance,
 };
 
 const USER_NOT_FOUND_ERROR: Error = Error {
@@ -18,24 +32,23 @@ const NOT_ENOUGH_MONEY_ERROR: Error = Error {
 const INVALID_STATE_ERROR: Error = Error {
     one_error: Some(error::OneError::InvalidState(InvalidStateError {})),
 };
+const INVALID_QUOTE_ERROR: Error = Error {
+    one_error: Some(error::OneError::InvalidQuote(InvalidQuoteError {})),
+};
+const ACCOUNT_BLOCKED_ERROR: Error = Error {
+    one_error: Some(error::OneError::AccountBlocked(AccountBlockedError {})),
+};
 
-pub fn user_balance_data_http_response(balance: UserBalance, user_id: &str, is_protobuf: bool) -> HttpResponse {
-    let data = match balance {
-        UserBalance::Ok(balance) => GenericOutput {
-            user_balance: Some(UserBalanceData {
-                user_id: user_id.to_string(),
-                currency: balance.currency,
-                value: balance.balance.to_string(),
-                reserved_value: balance.reserved.to_string(),
-                is_overdraft: balance.balance.is_negative(),
-            }),
-            ..Default::default()
-        },
-        UserBalance::NotFound => GenericOutput {
-            error: Some(USER_NOT_FOUND_ERROR),
-            ..Default::default()
-        },
-    };
+pub fn accepts_protobuf(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim() == "application/x-protobuf")
+}
+
+// encodes output message according to the Accept header
+fn encoded_http_response<T: Message + Serialize>(data: &T, is_protobuf: bool) -> HttpResponse {
     if is_protobuf {
         HttpResponse::Ok()
             .content_type("application/x-protobuf")
@@ -43,47 +56,405 @@ pub fn user_balance_data_http_response(balance: UserBalance, user_id: &str, is_p
     } else {
         HttpResponse::Ok()
             .content_type("application/json")
-            .body(serde_json::to_string(&data).unwrap())
+            .body(serde_json::to_string(data).unwrap())
+    }
+}
+
+pub fn account_status_from_proto(status: i32) -> Option<&'static str> {
+    match proto::AccountStatus::from_i32(status)? {
+        proto::AccountStatus::Active => Some(models::ACCOUNT_ACTIVE),
+        proto::AccountStatus::FrozenDebits => Some(models::ACCOUNT_FROZEN_DEBITS),
+        proto::AccountStatus::FrozenAll => Some(models::ACCOUNT_FROZEN_ALL),
+        proto::AccountStatus::Closed => Some(models::ACCOUNT_CLOSED),
+    }
+}
+
+fn account_status_into_proto(status: &str) -> proto::AccountStatus {
+    match status {
+        models::ACCOUNT_FROZEN_DEBITS => proto::AccountStatus::FrozenDebits,
+        models::ACCOUNT_FROZEN_ALL => proto::AccountStatus::FrozenAll,
+        models::ACCOUNT_CLOSED => proto::AccountStatus::Closed,
+        _ => proto::AccountStatus::Active,
+    }
+}
+
+fn user_balance_data(balance: UserBalanceValues, user_id: &str) -> UserBalanceData {
+    let wallets: Vec<WalletBalance> = balance
+        .wallets
+        .into_iter()
+        .map(|wallet| WalletBalance {
+            value: wallet.balance.to_string(),
+            reserved_value: wallet.reserved.to_string(),
+            is_overdraft: wallet.balance.is_negative(),
+            credit_limit: wallet.credit_limit.to_string(),
+            status: account_status_into_proto(&wallet.status) as i32,
+            currency: wallet.currency,
+        })
+        .collect();
+    UserBalanceData {
+        user_id: user_id.to_string(),
+        value: balance.balance.to_string(),
+        reserved_value: balance.reserved.to_string(),
+        currency: balance.currency,
+        is_overdraft: balance.balance.is_negative() || wallets.iter().any(|wallet| wallet.is_overdraft),
+        wallets,
+    }
+}
+
+pub fn user_balance_output(balance: UserBalance, user_id: &str) -> GenericOutput {
+    match balance {
+        UserBalance::Ok(balance) => GenericOutput {
+            user_balance: Some(user_balance_data(balance, user_id)),
+            ..Default::default()
+        },
+        UserBalance::NotFound => GenericOutput {
+            error: Some(USER_NOT_FOUND_ERROR),
+            ..Default::default()
+        },
+    }
+}
+
+pub fn user_balance_data_http_response(
+    balance: UserBalance,
+    user_id: &str,
+    is_protobuf: bool,
+) -> Result<HttpResponse, ServiceError> {
+    match balance {
+        UserBalance::NotFound => Err(ServiceError::UserNotFound),
+        balance => Ok(encoded_http_response(
+            &user_balance_output(balance, user_id),
+            is_protobuf,
+        )),
     }
 }
 
-pub fn bad_parameter_http_response(field: &str, is_protobuf: bool) -> HttpResponse {
-    let data = GenericOutput {
+pub fn error_output(err: &ServiceError) -> GenericOutput {
+    let one_error = match err {
+        ServiceError::Unauthorized => error::OneError::Unauthorized(UnauthorizedError {}),
+        ServiceError::BadParameter(name) => error::OneError::BadParameter(BadParameterError { name: name.clone() }),
+        ServiceError::UserNotFound => error::OneError::UserNotFound(UserNotFoundError {}),
+        ServiceError::NotEnoughMoney => error::OneError::NotEnoughMoney(NotEnoughMoneyError {}),
+        ServiceError::InvalidCurrency(currency) => error::OneError::InvalidCurrency(InvalidCurrencyError {
+            currency: currency.clone(),
+        }),
+        ServiceError::InvalidState => error::OneError::InvalidState(InvalidStateError {}),
+        ServiceError::InvalidQuote => error::OneError::InvalidQuote(InvalidQuoteError {}),
+        ServiceError::AccountBlocked => error::OneError::AccountBlocked(AccountBlockedError {}),
+        ServiceError::Internal(_) => error::OneError::Internal(InternalError {}),
+    };
+    GenericOutput {
         error: Some(Error {
-            one_error: Some(error::OneError::BadParameter(BadParameterError {
-                name: field.to_string(),
-            })),
+            one_error: Some(one_error),
         }),
         ..Default::default()
+    }
+}
+
+pub fn error_http_response(err: &ServiceError, is_protobuf: bool) -> HttpResponse {
+    let mut res = encoded_http_response(&error_output(err), is_protobuf);
+    *res.status_mut() = err.status_code();
+    res
+}
+
+pub fn bad_parameter_output(field: impl Into<String>) -> GenericOutput {
+    error_output(&ServiceError::bad_parameter(field))
+}
+
+pub fn invalid_currency_output(currency: &str) -> GenericOutput {
+    error_output(&ServiceError::invalid_currency(currency))
+}
+
+pub fn reserve_error_output(res: ReserveResult) -> GenericOutput {
+    GenericOutput {
+        error: match res {
+            ReserveResult::Ok => None,
+            ReserveResult::UserNotFound => Some(USER_NOT_FOUND_ERROR),
+            ReserveResult::InsufficientFunds => Some(NOT_ENOUGH_MONEY_ERROR),
+            ReserveResult::InvalidTransactionState => Some(INVALID_STATE_ERROR),
+            ReserveResult::InvalidQuote => Some(INVALID_QUOTE_ERROR),
+            ReserveResult::AccountBlocked => Some(ACCOUNT_BLOCKED_ERROR),
+        },
+        ..Default::default()
+    }
+}
+
+fn order_item_state(state: OrderState) -> OrderItemState {
+    let status = match state.status {
+        OrderStatus::Unknown => proto::OrderState::Unknown,
+        OrderStatus::Reserved => proto::OrderState::Reserved,
+        OrderStatus::Committed => proto::OrderState::Committed,
+        OrderStatus::Cancelled => proto::OrderState::Cancelled,
+        OrderStatus::Expired => proto::OrderState::Expired,
     };
-    if is_protobuf {
-        HttpResponse::Ok()
-            .content_type("application/x-protobuf")
-            .body(data.encode_to_vec())
-    } else {
-        HttpResponse::Ok()
-            .content_type("application/json")
-            .body(serde_json::to_string(&data).unwrap())
+    OrderItemState {
+        item_id: state.item_id,
+        state: status as i32,
+        user_id: state.user_id,
+        currency: state.reserved_value.currency().to_string(),
+        reserved_value: state.reserved_value.to_string(),
+        captured_value: state.captured_value.to_string(),
+        expires_at: state.expires_at.map(Into::into),
+        finished_at: state.finished_at.map(Into::into),
     }
 }
 
-pub fn reserve_error_http_response(res: ReserveResult, is_protobuf: bool) -> HttpResponse {
-    let data = GenericOutput {
-        error: Some(match res {
-            ReserveResult::Ok => return HttpResponse::Ok().finish(),
-            ReserveResult::UserNotFound => USER_NOT_FOUND_ERROR,
-            ReserveResult::InsufficientFunds => NOT_ENOUGH_MONEY_ERROR,
-            ReserveResult::InvalidTransactionState => INVALID_STATE_ERROR,
+pub fn order_state_output(items: Vec<OrderState>, order_id: &str) -> OrderStateOutput {
+    OrderStateOutput {
+        error: None,
+        order_id: order_id.to_string(),
+        items: items.into_iter().map(order_item_state).collect(),
+    }
+}
+
+pub fn order_state_http_response(items: Vec<OrderState>, order_id: &str, is_protobuf: bool) -> HttpResponse {
+    encoded_http_response(&order_state_output(items, order_id), is_protobuf)
+}
+
+pub fn exchange_rate_output(
+    rate: HistoricalRate,
+    from_currency: &str,
+    to_currency: &str,
+) -> Result<ExchangeRateOutput, ServiceError> {
+    match rate {
+        HistoricalRate::Ok {
+            snapshot_id,
+            created_at,
+            rate,
+        } => Ok(ExchangeRateOutput {
+            error: None,
+            from_currency: from_currency.to_string(),
+            to_currency: to_currency.to_string(),
+            rate: rate.to_string(),
+            snapshot_id: snapshot_id.to_string(),
+            snapshot_created_at: Some(created_at.into()),
         }),
-        ..Default::default()
+        HistoricalRate::NoSnapshot => Err(ServiceError::bad_parameter("at is invalid")),
+        HistoricalRate::UnknownCurrency(currency) => Err(ServiceError::InvalidCurrency(currency)),
+    }
+}
+
+pub fn exchange_rate_http_response(
+    rate: HistoricalRate,
+    from_currency: &str,
+    to_currency: &str,
+    is_protobuf: bool,
+) -> Result<HttpResponse, ServiceError> {
+    let output = exchange_rate_output(rate, from_currency, to_currency)?;
+    Ok(encoded_http_response(&output, is_protobuf))
+}
+
+pub fn quote_output(quote: models::Quote) -> QuoteOutput {
+    QuoteOutput {
+        error: None,
+        quote_id: quote.id.to_string(),
+        value: quote.value().to_string(),
+        converted_value: quote.converted_value().to_string(),
+        from_currency: quote.from_currency,
+        to_currency: quote.to_currency,
+        rate: quote.rate.to_string(),
+        expires_at: Some(quote.expires_at.into()),
+    }
+}
+
+pub fn quote_http_response(quote: models::Quote, is_protobuf: bool) -> HttpResponse {
+    encoded_http_response(&quote_output(quote), is_protobuf)
+}
+
+pub fn overdrafts_output(overdrafts: Vec<Overdraft>, now: NaiveDateTime) -> OverdraftsOutput {
+    OverdraftsOutput {
+        error: None,
+        overdrafts: overdrafts
+            .into_iter()
+            .map(|overdraft| proto::Overdraft {
+                currency: overdraft.balance.currency().to_string(),
+                value: overdraft.balance.to_string(),
+                credit_limit: overdraft.credit_limit.to_string(),
+                negative_since: Some(overdraft.negative_since.into()),
+                negative_seconds: (now - overdraft.negative_since).num_seconds().max(0),
+                user_id: overdraft.user_id,
+            })
+            .collect(),
+    }
+}
+
+pub fn overdrafts_http_response(overdrafts: Vec<Overdraft>, now: NaiveDateTime, is_protobuf: bool) -> HttpResponse {
+    encoded_http_response(&overdrafts_output(overdrafts, now), is_protobuf)
+}
+
+pub fn reconciliation_output(
+    report: models::ReconciliationReport,
+    findings: Vec<models::ReconciliationFinding>,
+) -> ReconciliationOutput {
+    ReconciliationOutput {
+        error: None,
+        report_id: report.id,
+        checked_wallets: report.checked_wallets,
+        findings: findings
+            .into_iter()
+            .map(|finding| proto::ReconciliationFinding {
+                user_id: finding.user_id,
+                currency: finding.currency,
+                kind: finding.kind,
+                transaction_id: finding.transaction_id.unwrap_or_default(),
+                expected: finding.expected.to_string(),
+                actual: finding.actual.to_string(),
+            })
+            .collect(),
+        created_at: Some(report.created_at.into()),
+    }
+}
+
+pub fn reconciliation_http_response(
+    report: models::ReconciliationReport,
+    findings: Vec<models::ReconciliationFinding>,
+    is_protobuf: bool,
+) -> HttpResponse {
+    encoded_http_response(&reconciliation_output(report, findings), is_protobuf)
+}
+
+// sums up revenue of every service in report currency, rounds totals to minor units of the currency
+pub fn revenue_totals(
+    revenue: Vec<ServiceRevenue>,
+    curr: &CurrencyConverter,
+    report_currency: &str,
+) -> Result<Vec<(String, String)>, UnknownCurrency> {
+    let mut totals: BTreeMap<String, Money> = BTreeMap::new();
+    for rec in revenue {
+        let converted = curr.convert(&rec.value(), report_currency)?;
+        *totals
+            .entry(rec.item_id)
+            .or_insert_with(|| Money::zero(report_currency)) += &converted;
+    }
+    Ok(totals
+        .into_iter()
+        .map(|(item_id, total)| (item_id, total.to_string()))
+        .collect())
+}
+
+pub fn statistics_output(data: HashMap<String, String>) -> StatisticsOutput {
+    StatisticsOutput { error: None, data }
+}
+
+pub fn statistics_http_response(data: HashMap<String, String>, is_protobuf: bool) -> HttpResponse {
+    encoded_http_response(&statistics_output(data), is_protobuf)
+}
+
+fn user_transaction(tx: models::Transaction, user_id: &str) -> UserTransaction {
+    let is_sender = tx.sender_id.as_deref() == Some(user_id);
+    let user_currency_value = if is_sender {
+        tx.sender_value().map(|value| -value)
+    } else {
+        tx.recipient_value()
     };
-    if is_protobuf {
-        HttpResponse::Ok()
-            .content_type("application/x-protobuf")
-            .body(data.encode_to_vec())
+    let order_data_field = |name: &str| {
+        tx.order_data
+            .as_ref()
+            .and_then(|data| data.get(name))
+            .and_then(|value| value.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    UserTransaction {
+        id: tx.id.to_string(),
+        currency: tx.transaction_currency.clone(),
+        value: tx.value().to_string(),
+        user_currency_value: user_currency_value.as_ref().map(Money::to_string).unwrap_or_default(),
+        user_currency: user_currency_value
+            .map(|value| value.currency().to_string())
+            .unwrap_or_default(),
+        rate_snapshot_id: tx.rate_snapshot_id.map(|id| id.to_string()).unwrap_or_default(),
+        rate: tx.rate.as_ref().map(ToString::to_string).unwrap_or_default(),
+        description: tx.description.clone().unwrap_or_default(),
+        category: tx.category.clone(),
+        source: tx.source.clone().unwrap_or_default(),
+        is_top_up_transaction: !is_sender && tx.sender_id.is_none(),
+        order_id: order_data_field("order_id"),
+        item_id: order_data_field("item_id"),
+        created_at: Some(tx.created_at.into()),
+    }
+}
+
+pub fn transactions_output(
+    balance: UserBalance,
+    page: Option<TransactionsPage>,
+    filter: &TransactionsFilter,
+) -> ListTransactionsOutput {
+    match (balance, page) {
+        (UserBalance::Ok(balance), Some(page)) => ListTransactionsOutput {
+            user_balance: Some(user_balance_data(balance, filter.user_id.as_str())),
+            next_cursor: cursor::next_page(filter, &page),
+            total: page.total,
+            transactions: page
+                .transactions
+                .into_iter()
+                .map(|tx| user_transaction(tx, filter.user_id.as_str()))
+                .collect(),
+            ..Default::default()
+        },
+        _ => ListTransactionsOutput {
+            error: Some(USER_NOT_FOUND_ERROR),
+            ..Default::default()
+        },
+    }
+}
+
+pub fn transactions_http_response(
+    balance: UserBalance,
+    page: Option<TransactionsPage>,
+    filter: &TransactionsFilter,
+    is_protobuf: bool,
+) -> Result<HttpResponse, ServiceError> {
+    match (balance, page) {
+        (UserBalance::Ok(balance), Some(page)) => Ok(encoded_http_response(
+            &transactions_output(UserBalance::Ok(balance), Some(page), filter),
+            is_protobuf,
+        )),
+        _ => Err(ServiceError::UserNotFound),
+    }
+}
+
+// renders "service;total" lines, rows are expected to be sorted
+pub fn statistics_csv_http_response(rows: Vec<(String, String)>, file_name: &str) -> HttpResponse {
+    let body = rows
+        .into_iter()
+        .map(|(service, total)| format!("{};{}\r\n", csv_field(&service), csv_field(&total)))
+        .collect::<String>();
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{file_name}\""),
+        ))
+        .body(body)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([';', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
     } else {
-        HttpResponse::Ok()
-            .content_type("application/json")
-            .body(serde_json::to_string(&data).unwrap())
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+
+    #[test]
+    fn test_statistics_csv_http_response() {
+        let res = statistics_csv_http_response(
+            vec![
+                ("service 1".to_string(), "10.50".to_string()),
+                ("a;b \"c\"".to_string(), "1.00".to_string()),
+            ],
+            "revenue-2022-11.csv",
+        );
+        assert_eq!(
+            res.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+            "attachment; filename=\"revenue-2022-11.csv\""
+        );
+        let body = res.into_body().try_into_bytes().unwrap();
+        assert_eq!(body, "service 1;10.50\r\n\"a;b \"\"c\"\"\";1.00\r\n");
     }
 }
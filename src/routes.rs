@@ -1,155 +1,176 @@
 #![allow(unused_variables)]
 
 use std::ops::DerefMut;
-use std::str::FromStr;
 
 use actix_request_identifier::RequestId;
 use actix_web::{get, http::header, post, web, HttpResponse};
-use bigdecimal::{BigDecimal, Signed, Zero};
 use diesel::r2d2::{ConnectionManager, Pool};
 use diesel::PgConnection;
-use tracing::{error, instrument};
+use serde::Deserialize;
+use tracing::instrument;
 
 use crate::database::{mutations, queries};
-use crate::{currency, proto, responses};
+use crate::errors::ServiceError;
+use crate::money::Money;
+use crate::{auth, currency, cursor, expiry, extractors, openapi, proto, reconcile, responses};
 
-#[get("/balance/{user_id}")]
-#[instrument(skip(db), fields(request_id = request_id.as_str()))]
+// registers all http handlers, operations are documented in openapi.rs
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(balance_handler)
+        .service(top_up_handler)
+        .service(reserve_handler)
+        .service(commit_handler)
+        .service(cancel_handler)
+        .service(statistics_handler)
+        .service(transactions_handler)
+        .service(transfer_handler)
+        .service(order_state_handler)
+        .service(exchange_rate_handler)
+        .service(quote_handler)
+        .service(credit_limit_handler)
+        .service(overdrafts_handler)
+        .service(reconcile_handler)
+        .service(open_account_handler)
+        .service(account_status_handler)
+        .service(account_currency_handler)
+        .service(openapi_handler)
+        .service(swagger_ui_handler)
+        .service(swagger_ui_asset_handler);
+}
+
+#[get("/balance/{user_id}", wrap = "auth::RequireClient")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
 pub async fn balance_handler(
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
     request_id: RequestId,
     accept: web::Header<header::Accept>,
     user_id: web::Path<String>,
-) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+) -> Result<HttpResponse, ServiceError> {
     let user_id = user_id.clone();
-    let is_protobuf = accept
-        .iter()
-        .any(|a| a.to_string() == "application/x-protobuf".to_string());
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
 
     let mut conn = db.get()?;
 
     let user_id1 = user_id.clone();
-    web::block(move || queries::load_balance(conn.deref_mut(), user_id1.as_str()).map_err(anyhow::Error::from))
-        .await
-        .unwrap_or_else(|e| {
-            error!("{e}");
-            Err(e.into())
-        })
-        .map(|balance| responses::user_balance_data_http_response(balance, user_id.as_str(), is_protobuf))
-        .map_err(Into::into)
+    let balance = web::block(move || queries::load_balance(conn.deref_mut(), &curr, user_id1.as_str())).await??;
+    responses::user_balance_data_http_response(balance, user_id.as_str(), is_protobuf)
 }
 
-#[post("/top-up")]
+#[post("/top-up", wrap = "auth::RequireBilling")]
 #[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
 pub async fn top_up_handler(
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
     curr: web::Data<currency::CurrencyConverter>,
     request_id: RequestId,
     accept: web::Header<header::Accept>,
-    top_up_request: web::Json<proto::TopUpInput>,
-) -> Result<HttpResponse, Box<dyn std::error::Error>> {
-    let is_protobuf = accept
-        .iter()
-        .any(|a| a.to_string() == "application/x-protobuf".to_string());
+    top_up_request: extractors::ProtoOrJson<proto::TopUpInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
 
     let mut conn = db.get()?;
 
     if top_up_request.idempotency_key.is_empty() {
-        return Ok(responses::bad_parameter_http_response("idempotency_key", is_protobuf));
+        return Err(ServiceError::bad_parameter("idempotency_key"));
     }
     if top_up_request.user_id.is_empty() {
-        return Ok(responses::bad_parameter_http_response("user_id", is_protobuf));
+        return Err(ServiceError::bad_parameter("user_id"));
     }
     if !curr.is_currency_valid(&top_up_request.currency) {
-        return Ok(responses::bad_parameter_http_response("currency", is_protobuf));
+        return Err(ServiceError::invalid_currency(&top_up_request.currency));
     }
 
-    let req_value = BigDecimal::from_str(top_up_request.value.as_str());
-    let req_value = match req_value {
-        Ok(req_value) => req_value,
-        Err(_) => return Ok(responses::bad_parameter_http_response("value", is_protobuf)),
+    let req_value = match Money::parse(top_up_request.value.as_str(), &top_up_request.currency) {
+        Some(req_value) => req_value,
+        None => return Err(ServiceError::bad_parameter("value")),
     };
-    if req_value.is_negative() || req_value.is_zero() {
-        return Ok(responses::bad_parameter_http_response("value", is_protobuf));
-    }
 
     // if merchant data is not empty, check if it is valid json
     if !top_up_request.merchant_data.is_empty() {
         let json = serde_json::from_str::<serde_json::Value>(top_up_request.merchant_data.as_str());
         if json.is_err() {
-            return Ok(responses::bad_parameter_http_response("merchant_data", is_protobuf));
+            return Err(ServiceError::bad_parameter("merchant_data"));
         }
     }
+    let details = mutations::TransactionDetails::new(
+        &top_up_request.description,
+        &top_up_request.category,
+        &top_up_request.source,
+    );
+    if let Some(field) = details.invalid_field() {
+        return Err(ServiceError::bad_parameter(format!("{field} is invalid")));
+    }
 
     let user_id1 = top_up_request.user_id.clone();
-    web::block(move || {
+    let balance = web::block(move || {
         let req_merchant_data = if top_up_request.merchant_data.is_empty() {
             None
         } else {
             Some(top_up_request.merchant_data.as_str())
         };
-        mutations::top_up(
+        let res = mutations::top_up(
             conn.deref_mut(),
             &curr,
             top_up_request.idempotency_key.as_str(),
             top_up_request.user_id.as_str(),
-            top_up_request.currency.as_str(),
             req_value,
             req_merchant_data,
-        )
-        .map_err(anyhow::Error::from)?;
-        queries::load_balance(conn.deref_mut(), top_up_request.user_id.as_str()).map_err(anyhow::Error::from)
-    })
-    .await
-    .unwrap_or_else(|e| {
-        error!("{e}");
-        Err(e.into())
+            mutations::TransactionDetails::new(
+                &top_up_request.description,
+                &top_up_request.category,
+                &top_up_request.source,
+            ),
+        )?;
+        if res == mutations::TopUpResult::AccountBlocked {
+            return Err(ServiceError::AccountBlocked);
+        }
+        Ok(queries::load_balance(
+            conn.deref_mut(),
+            &curr,
+            top_up_request.user_id.as_str(),
+        )?)
     })
-    .map(|balance| responses::user_balance_data_http_response(balance, user_id1.as_str(), is_protobuf))
-    .map_err(Into::into)
+    .await??;
+    responses::user_balance_data_http_response(balance, user_id1.as_str(), is_protobuf)
 }
 
-#[post("/reserve")]
+#[post("/reserve", wrap = "auth::RequireService")]
 #[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
 pub async fn reserve_handler(
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
     curr: web::Data<currency::CurrencyConverter>,
     request_id: RequestId,
     accept: web::Header<header::Accept>,
-    reserve_request: web::Json<proto::ReserveInput>,
-) -> Result<HttpResponse, Box<dyn std::error::Error>> {
-    let is_protobuf = accept
-        .iter()
-        .any(|a| a.to_string() == "application/x-protobuf".to_string());
+    reserve_request: extractors::ProtoOrJson<proto::ReserveInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
 
     let mut conn = db.get()?;
 
     let req_user_id = reserve_request.user_id.clone();
     if req_user_id.is_empty() {
-        return Ok(responses::bad_parameter_http_response("user_id is empty", is_protobuf));
+        return Err(ServiceError::bad_parameter("user_id is empty"));
     }
     if !curr.is_currency_valid(&reserve_request.currency) {
-        return Ok(responses::bad_parameter_http_response(
-            "currency is invalid",
-            is_protobuf,
-        ));
+        return Err(ServiceError::invalid_currency(&reserve_request.currency));
     }
-    let req_value = BigDecimal::from_str(reserve_request.value.as_str());
-    let req_value = match req_value {
-        Ok(req_value) => {
-            if req_value.is_negative() || req_value.is_zero() {
-                return Ok(responses::bad_parameter_http_response("value is invalid", is_protobuf));
-            }
-            req_value
-        }
-        Err(_) => return Ok(responses::bad_parameter_http_response("value is invalid", is_protobuf)),
+    let req_value = match Money::parse(reserve_request.value.as_str(), &reserve_request.currency) {
+        Some(req_value) => req_value,
+        None => return Err(ServiceError::bad_parameter("value is invalid")),
     };
     if reserve_request.order_id.is_empty() {
-        return Ok(responses::bad_parameter_http_response("order_id is empty", is_protobuf));
+        return Err(ServiceError::bad_parameter("order_id is empty"));
     }
+    let req_quote_id = match reserve_request.quote_id.as_str() {
+        "" => None,
+        quote_id => match quote_id.parse::<i64>() {
+            Ok(quote_id) => Some(quote_id),
+            Err(_) => return Err(ServiceError::bad_parameter("quote_id is invalid")),
+        },
+    };
 
     enum BlockResult {
-        ReserveError(mutations::ReserveResult),
+        ReserveError(ServiceError),
         BalanceResult(queries::UserBalance),
         Error(anyhow::Error),
     }
@@ -163,18 +184,29 @@ pub async fn reserve_handler(
             conn.deref_mut(),
             &curr,
             reserve_request.user_id.as_str(),
-            reserve_request.currency.as_str(),
             req_value,
             reserve_request.order_id.as_str(),
             req_item_id,
+            expiry::expires_at(reserve_request.ttl_seconds, chrono::Utc::now().naive_utc()),
+            req_quote_id,
         );
         match res {
             Ok(mutations::ReserveResult::Ok) => {}
-            Ok(res) => return BlockResult::ReserveError(res),
+            Ok(mutations::ReserveResult::UserNotFound) => return BlockResult::ReserveError(ServiceError::UserNotFound),
+            Ok(mutations::ReserveResult::InsufficientFunds) => {
+                return BlockResult::ReserveError(ServiceError::NotEnoughMoney)
+            }
+            Ok(mutations::ReserveResult::InvalidTransactionState) => {
+                return BlockResult::ReserveError(ServiceError::InvalidState)
+            }
+            Ok(mutations::ReserveResult::InvalidQuote) => return BlockResult::ReserveError(ServiceError::InvalidQuote),
+            Ok(mutations::ReserveResult::AccountBlocked) => {
+                return BlockResult::ReserveError(ServiceError::AccountBlocked)
+            }
             Err(e) => return BlockResult::Error(e.into()),
         };
 
-        let res = queries::load_balance(conn.deref_mut(), reserve_request.user_id.as_str());
+        let res = queries::load_balance(conn.deref_mut(), &curr, reserve_request.user_id.as_str());
         match res {
             Ok(res) => BlockResult::BalanceResult(res),
             Err(e) => BlockResult::Error(e.into()),
@@ -182,61 +214,63 @@ pub async fn reserve_handler(
     })
     .await
     .map(|res| match res {
-        BlockResult::ReserveError(res) => Ok(responses::reserve_error_http_response(res, is_protobuf)),
-        BlockResult::BalanceResult(balance) => Ok(responses::user_balance_data_http_response(
-            balance,
-            req_user_id.as_str(),
-            is_protobuf,
-        )),
+        BlockResult::ReserveError(e) => Err(e),
+        BlockResult::BalanceResult(balance) => {
+            responses::user_balance_data_http_response(balance, req_user_id.as_str(), is_protobuf)
+        }
         BlockResult::Error(e) => Err(e.into()),
     })
-    .unwrap_or_else(|e| {
-        error!("{e}");
-        Err(e.into())
-    })
+    .unwrap_or_else(|e| Err(e.into()))
 }
 
-#[post("/commit")]
+#[post("/commit", wrap = "auth::RequireService")]
 #[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
 pub async fn commit_handler(
     db: web::Data<Pool<ConnectionManager<PgConnection>>>,
     curr: web::Data<currency::CurrencyConverter>,
     request_id: RequestId,
     accept: web::Header<header::Accept>,
-    commit_request: web::Json<proto::CommitReservationInput>,
-) -> Result<HttpResponse, Box<dyn std::error::Error>> {
-    let is_protobuf = accept
-        .iter()
-        .any(|a| a.to_string() == "application/x-protobuf".to_string());
+    commit_request: extractors::ProtoOrJson<proto::CommitReservationInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
 
     let mut conn = db.get()?;
 
     let req_user_id = commit_request.user_id.clone();
     if req_user_id.is_empty() {
-        return Ok(responses::bad_parameter_http_response("user_id is empty", is_protobuf));
+        return Err(ServiceError::bad_parameter("user_id is empty"));
     }
     if !curr.is_currency_valid(&commit_request.currency) {
-        return Ok(responses::bad_parameter_http_response(
-            "currency is invalid",
-            is_protobuf,
-        ));
+        return Err(ServiceError::invalid_currency(&commit_request.currency));
     }
-    let req_value = BigDecimal::from_str(commit_request.value.as_str());
-    let req_value = match req_value {
-        Ok(req_value) => {
-            if req_value.is_negative() || req_value.is_zero() {
-                return Ok(responses::bad_parameter_http_response("value is invalid", is_protobuf));
-            }
-            req_value
-        }
-        Err(_) => return Ok(responses::bad_parameter_http_response("value is invalid", is_protobuf)),
+    let req_value = match Money::parse(commit_request.value.as_str(), &commit_request.currency) {
+        Some(req_value) => req_value,
+        None => return Err(ServiceError::bad_parameter("value is invalid")),
     };
     if commit_request.order_id.is_empty() {
-        return Ok(responses::bad_parameter_http_response("order_id is empty", is_protobuf));
+        return Err(ServiceError::bad_parameter("order_id is empty"));
+    }
+    if commit_request.keep_reservation && commit_request.idempotency_key.is_empty() {
+        return Err(ServiceError::bad_parameter("idempotency_key is empty"));
+    }
+    let req_quote_id = match commit_request.quote_id.as_str() {
+        "" => None,
+        quote_id => match quote_id.parse::<i64>() {
+            Ok(quote_id) => Some(quote_id),
+            Err(_) => return Err(ServiceError::bad_parameter("quote_id is invalid")),
+        },
+    };
+    let details = mutations::TransactionDetails::new(
+        &commit_request.description,
+        &commit_request.category,
+        &commit_request.source,
+    );
+    if let Some(field) = details.invalid_field() {
+        return Err(ServiceError::bad_parameter(format!("{field} is invalid")));
     }
 
     enum BlockResult {
-        CommitError(mutations::ReserveResult),
+        CommitError(ServiceError),
         BalanceResult(queries::UserBalance),
         Error(anyhow::Error),
     }
@@ -246,29 +280,49 @@ pub async fn commit_handler(
         } else {
             Some(commit_request.item_id.as_str())
         };
+        let req_idempotency_key = if commit_request.idempotency_key.is_empty() {
+            None
+        } else {
+            Some(commit_request.idempotency_key.as_str())
+        };
         let res = mutations::commit(
             conn.deref_mut(),
             &curr,
             commit_request.user_id.as_str(),
-            commit_request.currency.as_str(),
             req_value,
             commit_request.order_id.as_str(),
             req_item_id,
+            req_idempotency_key,
+            commit_request.keep_reservation,
+            req_quote_id,
+            mutations::TransactionDetails::new(
+                &commit_request.description,
+                &commit_request.category,
+                &commit_request.source,
+            ),
         );
         match res {
             Ok(res) => match res {
                 mutations::CommitResult::Ok(_) => {}
-                mutations::CommitResult::UserNotFound => {
-                    return BlockResult::CommitError(mutations::ReserveResult::UserNotFound)
-                }
+                mutations::CommitResult::UserNotFound => return BlockResult::CommitError(ServiceError::UserNotFound),
                 mutations::CommitResult::InsufficientFunds => {
-                    return BlockResult::CommitError(mutations::ReserveResult::InsufficientFunds)
+                    return BlockResult::CommitError(ServiceError::NotEnoughMoney)
+                }
+                mutations::CommitResult::InvalidTransactionState => {
+                    return BlockResult::CommitError(ServiceError::InvalidState)
+                }
+                mutations::CommitResult::ItemRequired => {
+                    return BlockResult::CommitError(ServiceError::bad_parameter("item_id is empty"))
+                }
+                mutations::CommitResult::InvalidQuote => return BlockResult::CommitError(ServiceError::InvalidQuote),
+                mutations::CommitResult::AccountBlocked => {
+                    return BlockResult::CommitError(ServiceError::AccountBlocked)
                 }
             },
             Err(e) => return BlockResult::Error(e.into()),
         };
 
-        let res = queries::load_balance(conn.deref_mut(), commit_request.user_id.as_str());
+        let res = queries::load_balance(conn.deref_mut(), &curr, commit_request.user_id.as_str());
         match res {
             Ok(res) => BlockResult::BalanceResult(res),
             Err(e) => BlockResult::Error(e.into()),
@@ -276,16 +330,643 @@ pub async fn commit_handler(
     })
     .await
     .map(|res| match res {
-        BlockResult::CommitError(res) => Ok(responses::reserve_error_http_response(res, is_protobuf)),
-        BlockResult::BalanceResult(balance) => Ok(responses::user_balance_data_http_response(
-            balance,
-            req_user_id.as_str(),
+        BlockResult::CommitError(e) => Err(e),
+        BlockResult::BalanceResult(balance) => {
+            responses::user_balance_data_http_response(balance, req_user_id.as_str(), is_protobuf)
+        }
+        BlockResult::Error(e) => Err(e.into()),
+    })
+    .unwrap_or_else(|e| Err(e.into()))
+}
+
+#[post("/cancel", wrap = "auth::RequireService")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
+pub async fn cancel_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    cancel_request: extractors::ProtoOrJson<proto::CancelReservationInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    let req_user_id = cancel_request.user_id.clone();
+    if req_user_id.is_empty() {
+        return Err(ServiceError::bad_parameter("user_id is empty"));
+    }
+    if cancel_request.order_id.is_empty() {
+        return Err(ServiceError::bad_parameter("order_id is empty"));
+    }
+
+    enum BlockResult {
+        CancelError(ServiceError),
+        BalanceResult(queries::UserBalance),
+        Error(anyhow::Error),
+    }
+    web::block(move || {
+        let req_item_id = if cancel_request.item_id.is_empty() {
+            None
+        } else {
+            Some(cancel_request.item_id.as_str())
+        };
+        let res = mutations::cancel(
+            conn.deref_mut(),
+            cancel_request.user_id.as_str(),
+            cancel_request.order_id.as_str(),
+            req_item_id,
+        );
+        match res {
+            Ok(res) => match res {
+                mutations::CancelResult::Ok => {}
+                mutations::CancelResult::UserNotFound => return BlockResult::CancelError(ServiceError::UserNotFound),
+                mutations::CancelResult::InvalidTransactionState => {
+                    return BlockResult::CancelError(ServiceError::InvalidState)
+                }
+            },
+            Err(e) => return BlockResult::Error(e.into()),
+        };
+
+        let res = queries::load_balance(conn.deref_mut(), &curr, cancel_request.user_id.as_str());
+        match res {
+            Ok(res) => BlockResult::BalanceResult(res),
+            Err(e) => BlockResult::Error(e.into()),
+        }
+    })
+    .await
+    .map(|res| match res {
+        BlockResult::CancelError(e) => Err(e),
+        BlockResult::BalanceResult(balance) => {
+            responses::user_balance_data_http_response(balance, req_user_id.as_str(), is_protobuf)
+        }
+        BlockResult::Error(e) => Err(e.into()),
+    })
+    .unwrap_or_else(|e| Err(e.into()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StatisticsQuery {
+    format: Option<String>,   // "csv" to download report as a file
+    currency: Option<String>, // report currency, defaults to base currency of the rates
+}
+
+#[get("/statistics/{year}/{month}", wrap = "auth::RequireAccounting")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
+pub async fn statistics_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    path: web::Path<(i32, i32)>,
+    query: web::Query<StatisticsQuery>,
+) -> Result<HttpResponse, ServiceError> {
+    let (year, month) = path.into_inner();
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+    let is_csv = query.format.as_deref() == Some("csv") || accept.iter().any(|a| a.to_string() == "text/csv");
+
+    let mut conn = db.get()?;
+
+    if !(2000..=9999).contains(&year) {
+        return Err(ServiceError::bad_parameter("year is invalid"));
+    }
+    if !(1..=12).contains(&month) {
+        return Err(ServiceError::bad_parameter("month is invalid"));
+    }
+    let report_currency = query.currency.clone().unwrap_or_else(|| curr.base_currency());
+    if !curr.is_currency_valid(&report_currency) {
+        return Err(ServiceError::invalid_currency(&report_currency));
+    }
+
+    let revenue = web::block(move || queries::monthly_revenue(conn.deref_mut(), year, month)).await??;
+    let totals = responses::revenue_totals(revenue, &curr, report_currency.as_str())?;
+    if is_csv {
+        Ok(responses::statistics_csv_http_response(
+            totals,
+            &format!("revenue-{year}-{month:02}.csv"),
+        ))
+    } else {
+        Ok(responses::statistics_http_response(
+            totals.into_iter().collect(),
             is_protobuf,
-        )),
+        ))
+    }
+}
+
+#[post("/transactions", wrap = "auth::RequireClient")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
+pub async fn transactions_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    list_request: extractors::ProtoOrJson<proto::ListTransactionsInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    if !(1..=100).contains(&list_request.limit) {
+        return Err(ServiceError::bad_parameter("limit is invalid"));
+    }
+    // first page takes filter from the request, next pages restore it from the cursor
+    let filter = if list_request.cursor.is_empty() {
+        if list_request.user_id.is_empty() {
+            return Err(ServiceError::bad_parameter("user_id is empty"));
+        }
+        let order = match cursor::order_from_proto(list_request.order) {
+            Some(order) => order,
+            None => return Err(ServiceError::bad_parameter("order is invalid")),
+        };
+        let min_ts = match list_request.min_ts.clone().map(cursor::naive_utc) {
+            Some(None) => return Err(ServiceError::bad_parameter("min_ts is invalid")),
+            min_ts => min_ts.flatten(),
+        };
+        let max_ts = match list_request.max_ts.clone().map(cursor::naive_utc) {
+            Some(None) => return Err(ServiceError::bad_parameter("max_ts is invalid")),
+            max_ts => max_ts.flatten(),
+        };
+        queries::TransactionsFilter {
+            user_id: list_request.user_id.clone(),
+            order,
+            min_ts,
+            max_ts,
+            category: Some(list_request.category.clone()).filter(|category| !category.is_empty()),
+            after: None,
+        }
+    } else {
+        match cursor::decode(list_request.cursor.as_str()) {
+            Some(filter) if list_request.user_id.is_empty() || list_request.user_id == filter.user_id => filter,
+            _ => return Err(ServiceError::bad_parameter("cursor is invalid")),
+        }
+    };
+
+    let limit = list_request.limit as i64;
+    let (filter, balance, page) = web::block(move || {
+        let balance = queries::load_balance(conn.deref_mut(), &curr, filter.user_id.as_str())?;
+        if balance == queries::UserBalance::NotFound {
+            return Ok((filter, balance, None));
+        }
+        let page = queries::list_transactions(conn.deref_mut(), &filter, limit)?;
+        Ok::<_, ServiceError>((filter, balance, Some(page)))
+    })
+    .await??;
+    responses::transactions_http_response(balance, page, &filter, is_protobuf)
+}
+
+#[post("/transfer", wrap = "auth::RequireBilling")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
+pub async fn transfer_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    transfer_request: extractors::ProtoOrJson<proto::TransferInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    if transfer_request.idempotency_key.is_empty() {
+        return Err(ServiceError::bad_parameter("idempotency_key"));
+    }
+    let req_user_id = transfer_request.sender_user_id.clone();
+    if req_user_id.is_empty() {
+        return Err(ServiceError::bad_parameter("sender_user_id"));
+    }
+    if transfer_request.recipient_user_id.is_empty() || transfer_request.recipient_user_id == req_user_id {
+        return Err(ServiceError::bad_parameter("recipient_user_id"));
+    }
+    if !curr.is_currency_valid(&transfer_request.currency) {
+        return Err(ServiceError::invalid_currency(&transfer_request.currency));
+    }
+    let req_value = match Money::parse(transfer_request.value.as_str(), &transfer_request.currency) {
+        Some(req_value) => req_value,
+        None => return Err(ServiceError::bad_parameter("value")),
+    };
+    let details = mutations::TransactionDetails::new(
+        &transfer_request.description,
+        &transfer_request.category,
+        &transfer_request.source,
+    );
+    if let Some(field) = details.invalid_field() {
+        return Err(ServiceError::bad_parameter(format!("{field} is invalid")));
+    }
+
+    enum BlockResult {
+        TransferError(ServiceError),
+        BalanceResult(queries::UserBalance),
+        Error(anyhow::Error),
+    }
+    web::block(move || {
+        let res = mutations::transfer(
+            conn.deref_mut(),
+            &curr,
+            transfer_request.idempotency_key.as_str(),
+            transfer_request.sender_user_id.as_str(),
+            transfer_request.recipient_user_id.as_str(),
+            req_value,
+            mutations::TransactionDetails::new(
+                &transfer_request.description,
+                &transfer_request.category,
+                &transfer_request.source,
+            ),
+        );
+        match res {
+            Ok(mutations::TransferResult::Ok(_)) => {}
+            Ok(mutations::TransferResult::UserNotFound) => {
+                return BlockResult::TransferError(ServiceError::UserNotFound)
+            }
+            Ok(mutations::TransferResult::InsufficientFunds) => {
+                return BlockResult::TransferError(ServiceError::NotEnoughMoney)
+            }
+            Ok(mutations::TransferResult::AccountBlocked) => {
+                return BlockResult::TransferError(ServiceError::AccountBlocked)
+            }
+            Err(e) => return BlockResult::Error(e.into()),
+        };
+
+        let res = queries::load_balance(conn.deref_mut(), &curr, transfer_request.sender_user_id.as_str());
+        match res {
+            Ok(res) => BlockResult::BalanceResult(res),
+            Err(e) => BlockResult::Error(e.into()),
+        }
+    })
+    .await
+    .map(|res| match res {
+        BlockResult::TransferError(e) => Err(e),
+        BlockResult::BalanceResult(balance) => {
+            responses::user_balance_data_http_response(balance, req_user_id.as_str(), is_protobuf)
+        }
         BlockResult::Error(e) => Err(e.into()),
     })
-    .unwrap_or_else(|e| {
-        error!("{e}");
-        Err(e.into())
+    .unwrap_or_else(|e| Err(e.into()))
+}
+
+#[get("/order/{order_id}", wrap = "auth::RequireService")]
+#[instrument(skip(db), fields(request_id = request_id.as_str()))]
+pub async fn order_state_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    order_id: web::Path<String>,
+) -> Result<HttpResponse, ServiceError> {
+    let order_id = order_id.into_inner();
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    let order_id1 = order_id.clone();
+    let items = web::block(move || queries::load_order_state(conn.deref_mut(), order_id1.as_str())).await??;
+    Ok(responses::order_state_http_response(
+        items,
+        order_id.as_str(),
+        is_protobuf,
+    ))
+}
+
+#[post("/quote", wrap = "auth::RequireService")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
+pub async fn quote_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    quote_request: extractors::ProtoOrJson<proto::QuoteInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    if quote_request.user_id.is_empty() {
+        return Err(ServiceError::bad_parameter("user_id is empty"));
+    }
+    if !curr.is_currency_valid(&quote_request.from_currency) {
+        return Err(ServiceError::invalid_currency(&quote_request.from_currency));
+    }
+    if !curr.is_currency_valid(&quote_request.to_currency) {
+        return Err(ServiceError::invalid_currency(&quote_request.to_currency));
+    }
+    let req_value = match Money::parse(quote_request.value.as_str(), &quote_request.from_currency) {
+        Some(req_value) => req_value,
+        None => return Err(ServiceError::bad_parameter("value is invalid")),
+    };
+
+    let quote = web::block(move || {
+        let now = chrono::Utc::now().naive_utc();
+        mutations::create_quote(
+            conn.deref_mut(),
+            &curr,
+            quote_request.user_id.as_str(),
+            req_value,
+            quote_request.to_currency.as_str(),
+            expiry::quote_expires_at(now),
+        )
+    })
+    .await??;
+    Ok(responses::quote_http_response(quote, is_protobuf))
+}
+
+#[post("/credit-limit", wrap = "auth::RequireAdmin")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
+pub async fn credit_limit_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    credit_limit_request: extractors::ProtoOrJson<proto::SetCreditLimitInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    if credit_limit_request.user_id.is_empty() {
+        return Err(ServiceError::bad_parameter("user_id"));
+    }
+    if !curr.is_currency_valid(&credit_limit_request.currency) {
+        return Err(ServiceError::invalid_currency(&credit_limit_request.currency));
+    }
+    let req_credit_limit = match Money::parse_non_negative(
+        credit_limit_request.credit_limit.as_str(),
+        &credit_limit_request.currency,
+    ) {
+        Some(req_credit_limit) => req_credit_limit,
+        None => return Err(ServiceError::bad_parameter("credit_limit")),
+    };
+
+    let user_id1 = credit_limit_request.user_id.clone();
+    let balance = web::block(move || {
+        // unknown user is reported by the balance query
+        mutations::set_credit_limit(
+            conn.deref_mut(),
+            credit_limit_request.user_id.as_str(),
+            req_credit_limit,
+        )?;
+        queries::load_balance(conn.deref_mut(), &curr, credit_limit_request.user_id.as_str())
+    })
+    .await??;
+    responses::user_balance_data_http_response(balance, user_id1.as_str(), is_protobuf)
+}
+
+#[get("/overdrafts", wrap = "auth::RequireAccounting")]
+#[instrument(skip(db), fields(request_id = request_id.as_str()))]
+pub async fn overdrafts_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    let overdrafts = web::block(move || queries::list_overdrafts(conn.deref_mut())).await??;
+    let now = chrono::Utc::now().naive_utc();
+    Ok(responses::overdrafts_http_response(overdrafts, now, is_protobuf))
+}
+
+#[post("/admin/reconcile", wrap = "auth::RequireAdmin")]
+#[instrument(skip(db), fields(request_id = request_id.as_str()))]
+pub async fn reconcile_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    let (report, findings) = web::block(move || reconcile::reconcile(conn.deref_mut())).await??;
+    Ok(responses::reconciliation_http_response(report, findings, is_protobuf))
+}
+
+#[post("/accounts", wrap = "auth::RequireBilling")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
+pub async fn open_account_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    open_request: extractors::ProtoOrJson<proto::OpenAccountInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    if open_request.user_id.is_empty() {
+        return Err(ServiceError::bad_parameter("user_id"));
+    }
+    if !curr.is_currency_valid(&open_request.currency) {
+        return Err(ServiceError::invalid_currency(&open_request.currency));
+    }
+
+    let user_id1 = open_request.user_id.clone();
+    let balance = web::block(move || {
+        let res = mutations::open_account(
+            conn.deref_mut(),
+            open_request.user_id.as_str(),
+            open_request.currency.as_str(),
+        )?;
+        if res == mutations::OpenAccountResult::AlreadyExists {
+            return Err(ServiceError::InvalidState);
+        }
+        Ok(queries::load_balance(
+            conn.deref_mut(),
+            &curr,
+            open_request.user_id.as_str(),
+        )?)
+    })
+    .await??;
+    responses::user_balance_data_http_response(balance, user_id1.as_str(), is_protobuf)
+}
+
+#[post("/accounts/status", wrap = "auth::RequireAdmin")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
+pub async fn account_status_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    status_request: extractors::ProtoOrJson<proto::SetAccountStatusInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    if status_request.user_id.is_empty() {
+        return Err(ServiceError::bad_parameter("user_id"));
+    }
+    if !curr.is_currency_valid(&status_request.currency) {
+        return Err(ServiceError::invalid_currency(&status_request.currency));
+    }
+    let req_status = match responses::account_status_from_proto(status_request.status) {
+        Some(req_status) => req_status,
+        None => return Err(ServiceError::bad_parameter("status")),
+    };
+    if status_request.reason.is_empty() {
+        return Err(ServiceError::bad_parameter("reason"));
+    }
+    let details = mutations::TransactionDetails::new(
+        &status_request.description,
+        &status_request.category,
+        &status_request.source,
+    );
+    if let Some(field) = details.invalid_field() {
+        return Err(ServiceError::bad_parameter(format!("{field} is invalid")));
+    }
+
+    let user_id1 = status_request.user_id.clone();
+    let balance = web::block(move || {
+        let req_payout_user_id = if status_request.payout_user_id.is_empty() {
+            None
+        } else {
+            Some(status_request.payout_user_id.as_str())
+        };
+        let res = mutations::set_account_status(
+            conn.deref_mut(),
+            &curr,
+            status_request.user_id.as_str(),
+            status_request.currency.as_str(),
+            req_status,
+            status_request.reason.as_str(),
+            req_payout_user_id,
+            mutations::TransactionDetails::new(
+                &status_request.description,
+                &status_request.category,
+                &status_request.source,
+            ),
+        )?;
+        match res {
+            mutations::AccountStatusResult::Ok => {}
+            mutations::AccountStatusResult::UserNotFound => return Err(ServiceError::UserNotFound),
+            mutations::AccountStatusResult::InvalidTransactionState => return Err(ServiceError::InvalidState),
+            mutations::AccountStatusResult::AccountBlocked => return Err(ServiceError::AccountBlocked),
+        }
+        Ok(queries::load_balance(
+            conn.deref_mut(),
+            &curr,
+            status_request.user_id.as_str(),
+        )?)
+    })
+    .await??;
+    responses::user_balance_data_http_response(balance, user_id1.as_str(), is_protobuf)
+}
+
+#[post("/accounts/currency", wrap = "auth::RequireAdmin")]
+#[instrument(skip(db, curr), fields(request_id = request_id.as_str()))]
+pub async fn account_currency_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    curr: web::Data<currency::CurrencyConverter>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    currency_request: extractors::ProtoOrJson<proto::ChangeAccountCurrencyInput>,
+) -> Result<HttpResponse, ServiceError> {
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    if currency_request.user_id.is_empty() {
+        return Err(ServiceError::bad_parameter("user_id"));
+    }
+    if !curr.is_currency_valid(&currency_request.currency) {
+        return Err(ServiceError::invalid_currency(&currency_request.currency));
+    }
+    if !curr.is_currency_valid(&currency_request.to_currency) {
+        return Err(ServiceError::invalid_currency(&currency_request.to_currency));
+    }
+    if currency_request.to_currency == currency_request.currency {
+        return Err(ServiceError::bad_parameter("to_currency"));
+    }
+    if currency_request.reason.is_empty() {
+        return Err(ServiceError::bad_parameter("reason"));
+    }
+    let details = mutations::TransactionDetails::new(
+        &currency_request.description,
+        &currency_request.category,
+        &currency_request.source,
+    );
+    if let Some(field) = details.invalid_field() {
+        return Err(ServiceError::bad_parameter(format!("{field} is invalid")));
+    }
+
+    let user_id1 = currency_request.user_id.clone();
+    let balance = web::block(move || {
+        let res = mutations::change_currency(
+            conn.deref_mut(),
+            &curr,
+            currency_request.user_id.as_str(),
+            currency_request.currency.as_str(),
+            currency_request.to_currency.as_str(),
+            currency_request.reason.as_str(),
+            mutations::TransactionDetails::new(
+                &currency_request.description,
+                &currency_request.category,
+                &currency_request.source,
+            ),
+        )?;
+        match res {
+            mutations::ChangeCurrencyResult::Ok(_) => {}
+            mutations::ChangeCurrencyResult::UserNotFound => return Err(ServiceError::UserNotFound),
+            mutations::ChangeCurrencyResult::InvalidTransactionState => return Err(ServiceError::InvalidState),
+            mutations::ChangeCurrencyResult::AccountBlocked => return Err(ServiceError::AccountBlocked),
+        }
+        Ok(queries::load_balance(
+            conn.deref_mut(),
+            &curr,
+            currency_request.user_id.as_str(),
+        )?)
     })
+    .await??;
+    responses::user_balance_data_http_response(balance, user_id1.as_str(), is_protobuf)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExchangeRateQuery {
+    at: Option<String>, // RFC 3339 time, defaults to now
+}
+
+#[get("/rates/{from_currency}/{to_currency}", wrap = "auth::RequireAccounting")]
+#[instrument(skip(db), fields(request_id = request_id.as_str()))]
+pub async fn exchange_rate_handler(
+    db: web::Data<Pool<ConnectionManager<PgConnection>>>,
+    request_id: RequestId,
+    accept: web::Header<header::Accept>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ExchangeRateQuery>,
+) -> Result<HttpResponse, ServiceError> {
+    let (from_currency, to_currency) = path.into_inner();
+    let is_protobuf = accept.iter().any(|a| a.to_string() == "application/x-protobuf");
+
+    let mut conn = db.get()?;
+
+    let at = match &query.at {
+        Some(at) => match chrono::DateTime::parse_from_rfc3339(at) {
+            Ok(at) => at.naive_utc(),
+            Err(_) => return Err(ServiceError::bad_parameter("at is invalid")),
+        },
+        None => chrono::Utc::now().naive_utc(),
+    };
+
+    let (from_currency1, to_currency1) = (from_currency.clone(), to_currency.clone());
+    let rate = web::block(move || queries::rate_at(conn.deref_mut(), &from_currency1, &to_currency1, at)).await??;
+    responses::exchange_rate_http_response(rate, &from_currency, &to_currency, is_protobuf)
+}
+
+#[get("/openapi.json")]
+pub async fn openapi_handler() -> HttpResponse {
+    HttpResponse::Ok().json(&*openapi::DOCUMENT)
+}
+
+#[get("/docs")]
+pub async fn swagger_ui_handler() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(openapi::SWAGGER_UI_HTML)
+}
+
+#[get("/docs/{file}")]
+pub async fn swagger_ui_asset_handler(file: web::Path<String>) -> HttpResponse {
+    match openapi::swagger_ui_asset(file.as_str()) {
+        Some((content_type, content)) => HttpResponse::Ok().content_type(content_type).body(content),
+        None => HttpResponse::NotFound().finish(),
+    }
 }
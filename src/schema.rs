@@ -1,15 +1,19 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
-    balance (user_id) {
+    balance (user_id, currency) {
         user_id -> Varchar,
         currency -> Varchar,
         current_value -> Numeric,
+        created_at -> Timestamp,
+        credit_limit -> Numeric,
+        negative_since -> Nullable<Timestamp>,
+        status -> Varchar,
     }
 }
 
 diesel::table! {
-    balance_reserve (order_id) {
+    balance_reserve (user_id, order_id, item_id) {
         order_id -> Varchar,
         user_id -> Varchar,
         item_id -> Varchar,
@@ -17,6 +21,149 @@ diesel::table! {
         value -> Numeric,
         user_currency_value -> Numeric,
         created_at -> Timestamp,
+        captured_value -> Numeric,
+        expires_at -> Nullable<Timestamp>,
+        wallet_currency -> Varchar,
+    }
+}
+
+diesel::table! {
+    balance_status_history (id) {
+        id -> Int8,
+        user_id -> Varchar,
+        currency -> Varchar,
+        old_status -> Nullable<Varchar>,
+        new_status -> Varchar,
+        reason -> Text,
+        payout_transaction_id -> Nullable<Int8>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    clients (id) {
+        id -> Varchar,
+        name -> Varchar,
+        api_key_hash -> Varchar,
+        hmac_secret -> Nullable<Varchar>,
+        scopes -> Array<Varchar>,
+        is_active -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    currency_rate (snapshot_id, currency) {
+        snapshot_id -> Int8,
+        base_currency -> Varchar,
+        currency -> Varchar,
+        rate -> Numeric,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    fx_rule (from_currency, to_currency) {
+        from_currency -> Varchar,
+        to_currency -> Varchar,
+        spread -> Numeric,
+        reserve_margin -> Numeric,
+        max_overdraft -> Numeric,
+    }
+}
+
+diesel::table! {
+    journal_entry (id) {
+        id -> Int8,
+        operation -> Varchar,
+        transaction_id -> Nullable<Int8>,
+        order_id -> Nullable<Varchar>,
+        item_id -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    ledger_account (kind, user_id, currency) {
+        kind -> Varchar,
+        user_id -> Varchar,
+        currency -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    order_history (id) {
+        id -> Int8,
+        order_id -> Varchar,
+        user_id -> Varchar,
+        item_id -> Varchar,
+        state -> Varchar,
+        currency -> Varchar,
+        released_value -> Numeric,
+        captured_value -> Numeric,
+        reserved_at -> Timestamp,
+        finished_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    posting (id) {
+        id -> Int8,
+        entry_id -> Int8,
+        account_kind -> Varchar,
+        user_id -> Varchar,
+        currency -> Varchar,
+        value -> Numeric,
+    }
+}
+
+diesel::table! {
+    quote (id) {
+        id -> Int8,
+        from_currency -> Varchar,
+        to_currency -> Varchar,
+        value -> Numeric,
+        converted_value -> Numeric,
+        rate -> Numeric,
+        rate_snapshot_id -> Int8,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        spread -> Numeric,
+        user_id -> Nullable<Varchar>,
+        order_id -> Nullable<Varchar>,
+        item_id -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    reconciliation_finding (id) {
+        id -> Int8,
+        report_id -> Int8,
+        user_id -> Varchar,
+        currency -> Varchar,
+        kind -> Varchar,
+        transaction_id -> Nullable<Int8>,
+        expected -> Numeric,
+        actual -> Numeric,
+    }
+}
+
+diesel::table! {
+    reconciliation_report (id) {
+        id -> Int8,
+        checked_wallets -> Int8,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    spread_income (id) {
+        id -> Int8,
+        transaction_id -> Int8,
+        currency -> Varchar,
+        value -> Numeric,
+        created_at -> Timestamp,
     }
 }
 
@@ -39,13 +186,28 @@ diesel::table! {
         order_data -> Nullable<Jsonb>,
         created_at -> Timestamp,
         idempotency_key -> Nullable<Varchar>,
+        rate_snapshot_id -> Nullable<Int8>,
+        rate -> Nullable<Numeric>,
+        description -> Nullable<Text>,
+        category -> Varchar,
+        source -> Nullable<Varchar>,
     }
 }
 
-diesel::joinable!(balance_reserve -> balance (user_id));
-
 diesel::allow_tables_to_appear_in_same_query!(
     balance,
     balance_reserve,
+    balance_status_history,
+    clients,
+    currency_rate,
+    fx_rule,
+    journal_entry,
+    ledger_account,
+    order_history,
+    posting,
+    quote,
+    reconciliation_finding,
+    reconciliation_report,
+    spread_income,
     transaction,
 );